@@ -23,8 +23,12 @@ VertexOutput VS_main(VertexInput input)
 
 // Texture and sampler
 Texture2D<float4> renderTextureInput : register(t0);
+// annotations drawn on the cpu, straight alpha
+Texture2D<float4> annotationTexture : register(t1);
 SamplerState samplerLinear : register(s0);
 
+#define MAX_REGIONS 16
+
 cbuffer Constants : register(b0)
 {
     struct Rect
@@ -33,7 +37,9 @@ cbuffer Constants : register(b0)
         float2 bottomRight;
     };
 
-    Rect region;
+    Rect regions[MAX_REGIONS];
+    uint regionCount;
+    float dimStrength;
 };
 
 // Pixel renderer shader
@@ -41,18 +47,27 @@ float4 PS_main(VertexOutput input) : SV_TARGET
 {
     
     float4 px = renderTextureInput.Sample(samplerLinear, input.texcoord);
+    float4 annotation = annotationTexture.Sample(samplerLinear, input.texcoord);
+
+    bool highlighted = false;
+    for (uint i = 0; i < regionCount && i < MAX_REGIONS; ++i) {
+        if (
+            input.texcoord.x > regions[i].topLeft.x &&
+            input.texcoord.y > regions[i].topLeft.y &&
+            input.texcoord.x < regions[i].bottomRight.x &&
+            input.texcoord.y < regions[i].bottomRight.y
+        ) {
+            highlighted = true;
+        }
+    }
 
-    if (
-        input.texcoord.x > region.topLeft.x &&
-        input.texcoord.y > region.topLeft.y &&
-        input.texcoord.x < region.bottomRight.x &&
-        input.texcoord.y < region.bottomRight.y
-    ) {
-        return px;
-    } else {
-       return float4(px.rgb * 0.35f, px.a);
+    if (!highlighted) {
+        px.rgb *= dimStrength;
     }
-    
+
+    // annotations sit on top of the dimming, like they do in the export
+    return float4(lerp(px.rgb, annotation.rgb, annotation.a), px.a);
+
 }
 
 RWTexture2D<float4> conversionTexture : register(u1);
@@ -88,8 +103,9 @@ void CS_convert_main(uint3 groupID : SV_GroupID, uint3 groupThreadID : SV_GroupT
                 // Ensure the pixel values are within the [0, 1] range
                 sdrColor = clamp(sdrColor, 0.0f, 1.0f);
 
-                // Convert to 8-bit color
-                uint4 sdrPixel = uint4(sdrColor * 255.0f, 255.0f);
+                // Convert to 16-bit linear color, exports keep the full precision and
+                // reduce to 8-bit sRGB themselves (was *255 before the 16-bit pipeline)
+                uint4 sdrPixel = uint4(sdrColor * 65535.0f, 65535.0f);
 
                 // Write the SDR pixel to the output texture
                 conversionOutputTexture[uint2(x, y)] = sdrPixel;
@@ -0,0 +1,348 @@
+//! One desktop duplication kept for as long as we run, turned into frames.
+//!
+//! The duplication only hands out the newest desktop image, with a count of the
+//! presents folded into it since the last one and the areas they drew to. Only
+//! those areas are copied into a shadow of the output, which is copied whole
+//! just when a frame is asked for, stamped with when it was presented and where
+//! the pointer was. A queue of frames is only kept while something reads it,
+//! since each holds a copy of the whole output.
+//!
+//! The areas each image changed are kept for a while as a stream of updates,
+//! so something that only cares about part of the screen can tell when it
+//! didn't change without reading any pixels back.
+
+use std::{collections::VecDeque, error::Error};
+
+use windows::{
+    core::ComInterface,
+    Win32::{
+        Foundation,
+        Graphics::{Direct3D11::*, Dxgi::*},
+        System::Performance,
+    },
+};
+
+use crate::pixels::{Point, Rect};
+
+#[derive(Clone)]
+pub struct Frame {
+    /// The whole output, a copy the frame owns
+    pub texture: ID3D11Texture2D1,
+    /// Performance counter ticks when it was presented, see `FrameSource::frequency`
+    pub present_time: i64,
+    /// Presents folded into this image, more than 1 means some were never seen
+    pub accumulated: u32,
+    /// Top left of the pointer shape on the output, none while it's hidden
+    pub pointer: Option<Point>,
+}
+
+/// Where one acquired image differs from the one before
+#[derive(Debug, Clone)]
+pub struct Update {
+    pub present_time: i64,
+    /// Areas copied from elsewhere on the output, these happen before the dirty areas are drawn
+    pub moves: Vec<Move>,
+    pub dirty: Vec<Rect>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Move {
+    /// Top left of where it came from, it's the same size as `to`
+    pub from: Point,
+    pub to: Rect,
+}
+
+impl Update {
+    /// Everywhere it changed
+    pub fn areas(&self) -> impl Iterator<Item = &Rect> {
+        self.moves.iter().map(|m| &m.to).chain(&self.dirty)
+    }
+}
+
+/// How far through the updates a reader is, see `FrameSource::updates_since`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cursor(u64);
+
+pub struct FrameSource {
+    output: IDXGIOutput6,
+    device: ID3D11Device5,
+    context: ID3D11DeviceContext4,
+    duplication: IDXGIOutputDuplication,
+    /// Performance counter ticks per second
+    pub frequency: i64,
+    /// The whole output as of the newest image
+    shadow: Option<ID3D11Texture2D1>,
+    /// The next image is copied whole, after a new duplication or when the areas
+    /// that changed couldn't be had
+    refresh: bool,
+    /// Copy of the shadow last handed out, until the shadow changes
+    snapshot: Option<Frame>,
+    present_time: i64,
+    accumulated: u32,
+    queue: VecDeque<Frame>,
+    /// Frames the queue holds, 0 while nothing reads it
+    capacity: usize,
+    /// Frames pushed out of a full queue before they were read
+    pub dropped: u64,
+    /// Only sent when it changes, so the last one is remembered for the frames after
+    pointer: Option<Point>,
+    /// The newest updates, the oldest is number `first_update`
+    updates: VecDeque<Update>,
+    first_update: u64,
+}
+
+/// Updates kept for readers that fall behind, past this they're told everything changed
+const MAX_UPDATES: usize = 1024;
+
+impl FrameSource {
+    pub fn new(output: &IDXGIOutput6, device: &ID3D11Device5, context: &ID3D11DeviceContext4) -> Result<Self, Box<dyn Error>> {
+        let mut frequency = 0;
+        unsafe {Performance::QueryPerformanceFrequency(&mut frequency as *mut _)};
+        Ok(Self {
+            output: output.clone(),
+            device: device.clone(),
+            context: context.clone(),
+            duplication: duplicate(output, device)?,
+            frequency,
+            shadow: None,
+            refresh: true,
+            snapshot: None,
+            present_time: 0,
+            accumulated: 0,
+            queue: VecDeque::new(),
+            capacity: 0,
+            dropped: 0,
+            pointer: None,
+            updates: VecDeque::new(),
+            first_update: 0,
+        })
+    }
+
+    /// Pick up whatever was presented since the last call, waiting up to `timeout_ms`
+    /// for something to be. True when there was a new frame.
+    pub fn poll(&mut self, timeout_ms: u32) -> Result<bool, Box<dyn Error>> {
+        let mut info = DXGI_OUTDUPL_FRAME_INFO::default();
+        let mut resource: Option<IDXGIResource> = None;
+        match unsafe {self.duplication.AcquireNextFrame(timeout_ms, &mut info as *mut _, &mut resource as *mut _)} {
+            Ok(()) => {},
+            Err(e) if e.code() == DXGI_ERROR_WAIT_TIMEOUT => return Ok(false),
+            // a mode change or the secure desktop, carry on with a new duplication
+            Err(e) if e.code() == DXGI_ERROR_ACCESS_LOST => {
+                debug!("Lost the duplication, starting another");
+                self.duplication = duplicate(&self.output, &self.device)?;
+                self.refresh = true;
+                return Ok(false);
+            },
+            Err(e) => return Err(e.into()),
+        }
+
+        if info.LastMouseUpdateTime != 0 {
+            let pointer = info.PointerPosition;
+            self.pointer = pointer.Visible.as_bool().then_some(Point { x: pointer.Position.x, y: pointer.Position.y });
+        }
+        // only the pointer moved
+        if info.LastPresentTime == 0 {
+            unsafe {self.duplication.ReleaseFrame()?};
+            return Ok(false);
+        }
+
+        let updated = self.update(resource, &info);
+        unsafe {self.duplication.ReleaseFrame()?};
+        updated?;
+        if self.capacity > 0 {
+            let frame = self.snapshot()?;
+            if self.queue.len() == self.capacity {
+                self.queue.pop_front();
+                self.dropped += 1;
+            }
+            self.queue.push_back(frame);
+        }
+        Ok(true)
+    }
+
+    /// The screen as it is now. A new duplication always starts with the whole
+    /// desktop, after that nothing new means nothing changed.
+    pub fn current(&mut self) -> Result<Frame, Box<dyn Error>> {
+        let mut timeouts = 0;
+        while !self.poll(0)? && self.shadow.is_none() {
+            timeouts += 1;
+            if timeouts == 1000 {
+                return Err("No frame from the duplication".into());
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        if timeouts > 0 {
+            debug!("captured frame after {} timeouts", timeouts);
+        }
+        self.snapshot()
+    }
+
+    /// Queue up every frame from now on, up to `capacity` of them
+    pub fn start_queue(&mut self, capacity: usize) {
+        self.queue.clear();
+        self.capacity = capacity.max(1);
+        self.dropped = 0;
+    }
+
+    pub fn stop_queue(&mut self) {
+        self.queue.clear();
+        self.capacity = 0;
+    }
+
+    /// Oldest frame in the queue
+    pub fn pop(&mut self) -> Option<Frame> {
+        self.queue.pop_front()
+    }
+
+    /// A cursor past every update so far
+    pub fn cursor(&self) -> Cursor {
+        Cursor(self.first_update + self.updates.len() as u64)
+    }
+
+    /// The updates after `cursor`, which is moved past them. A reader that fell too
+    /// far behind gets one update covering the whole output instead, as of the
+    /// oldest one still kept.
+    pub fn updates_since(&self, cursor: &mut Cursor) -> Vec<Update> {
+        let updates = match cursor.0.checked_sub(self.first_update) {
+            Some(skip) => self.updates.iter().skip(skip as usize).cloned().collect(),
+            None => {
+                let present_time = self.updates.front().map_or(self.present_time, |u| u.present_time);
+                vec![Update { present_time, moves: Vec::new(), dirty: vec![self.whole()] }]
+            },
+        };
+        *cursor = self.cursor();
+        updates
+    }
+
+    /// Copy the areas of the acquired image that changed into the shadow
+    fn update(&mut self, resource: Option<IDXGIResource>, info: &DXGI_OUTDUPL_FRAME_INFO) -> Result<(), Box<dyn Error>> {
+        let desktop = resource.ok_or("Resource was nullptr")?.cast::<ID3D11Texture2D1>()?;
+        let mut desc = D3D11_TEXTURE2D_DESC1::default();
+        unsafe {desktop.GetDesc1(&mut desc as *mut _)};
+        let shadow = match &self.shadow {
+            Some(shadow) if same_size(shadow, &desc) => shadow.clone(),
+            _ => {
+                self.refresh = true;
+                self.create_copy(&desc)?
+            }
+        };
+
+        let (moves, dirty) = match self.refresh {
+            false => self.changed_areas(info.TotalMetadataBufferSize).unwrap_or_else(|e| {
+                debug!("Couldn't get the changed areas : {:?}", e);
+                self.refresh = true;
+                (Vec::new(), Vec::new())
+            }),
+            true => (Vec::new(), Vec::new()),
+        };
+
+        let update = if self.refresh {
+            unsafe {self.context.CopyResource(&shadow, &desktop)};
+            self.refresh = false;
+            let whole = Rect { left: 0, top: 0, right: desc.Width as i32, bottom: desc.Height as i32 };
+            Update { present_time: info.LastPresentTime, moves: Vec::new(), dirty: vec![whole] }
+        } else {
+            // the acquired image already has moved areas where they went, so they're copied like the rest
+            let update = Update { present_time: info.LastPresentTime, moves, dirty };
+            for area in update.areas() {
+                let source = D3D11_BOX {
+                    left: area.left as u32,
+                    top: area.top as u32,
+                    front: 0,
+                    right: area.right as u32,
+                    bottom: area.bottom as u32,
+                    back: 1,
+                };
+                unsafe {self.context.CopySubresourceRegion(&shadow, 0, source.left, source.top, 0, &desktop, 0, Some(&source as *const _))};
+            }
+            update
+        };
+
+        self.shadow = Some(shadow);
+        self.snapshot = None;
+        self.present_time = info.LastPresentTime;
+        self.accumulated = info.AccumulatedFrames;
+        if self.updates.len() == MAX_UPDATES {
+            self.updates.pop_front();
+            self.first_update += 1;
+        }
+        self.updates.push_back(update);
+        Ok(())
+    }
+
+    /// A copy of the shadow as it is now, shared until it changes
+    fn snapshot(&mut self) -> Result<Frame, Box<dyn Error>> {
+        if let Some(frame) = &self.snapshot {
+            return Ok(frame.clone());
+        }
+        let shadow = self.shadow.as_ref().ok_or("No frame from the duplication yet")?;
+        let mut desc = D3D11_TEXTURE2D_DESC1::default();
+        unsafe {shadow.GetDesc1(&mut desc as *mut _)};
+        let texture = self.create_copy(&desc)?;
+        unsafe {self.context.CopyResource(&texture, shadow)};
+        let frame = Frame { texture, present_time: self.present_time, accumulated: self.accumulated, pointer: self.pointer };
+        self.snapshot = Some(frame.clone());
+        Ok(frame)
+    }
+
+    /// Where the image being held was moved and drawn to
+    fn changed_areas(&self, metadata_size: u32) -> Result<(Vec<Move>, Vec<Rect>), Box<dyn Error>> {
+        if metadata_size == 0 {
+            return Ok((Vec::new(), Vec::new()));
+        }
+        unsafe {
+            let mut moves = vec![DXGI_OUTDUPL_MOVE_RECT::default(); metadata_size as usize / std::mem::size_of::<DXGI_OUTDUPL_MOVE_RECT>() + 1];
+            let mut size = 0u32;
+            self.duplication.GetFrameMoveRects(std::mem::size_of_val(&moves[..]) as u32, moves.as_mut_ptr(), &mut size as *mut _)?;
+            moves.truncate(size as usize / std::mem::size_of::<DXGI_OUTDUPL_MOVE_RECT>());
+
+            let mut dirty = vec![Foundation::RECT::default(); metadata_size as usize / std::mem::size_of::<Foundation::RECT>() + 1];
+            self.duplication.GetFrameDirtyRects(std::mem::size_of_val(&dirty[..]) as u32, dirty.as_mut_ptr(), &mut size as *mut _)?;
+            dirty.truncate(size as usize / std::mem::size_of::<Foundation::RECT>());
+
+            Ok((
+                moves.iter().map(|m| Move { from: Point { x: m.SourcePoint.x, y: m.SourcePoint.y }, to: m.DestinationRect.into() }).collect(),
+                dirty.into_iter().map(Rect::from).collect(),
+            ))
+        }
+    }
+
+    /// A texture frames can be copied into and drawn from, otherwise like `desc`
+    fn create_copy(&self, desc: &D3D11_TEXTURE2D_DESC1) -> Result<ID3D11Texture2D1, Box<dyn Error>> {
+        let desc = D3D11_TEXTURE2D_DESC1 {
+            Usage: D3D11_USAGE_DEFAULT,
+            BindFlags: D3D11_BIND_SHADER_RESOURCE,
+            CPUAccessFlags: D3D11_CPU_ACCESS_FLAG(0),
+            MiscFlags: D3D11_RESOURCE_MISC_FLAG(0),
+            ..*desc
+        };
+        let mut texture: Option<ID3D11Texture2D1> = None;
+        unsafe {self.device.CreateTexture2D1(&desc as *const _, None, Some(&mut texture as *mut _))?};
+        Ok(texture.ok_or("Couldn't create a texture for the frame")?)
+    }
+
+    /// The whole output, for when what changed isn't known
+    fn whole(&self) -> Rect {
+        let mut desc = DXGI_OUTPUT_DESC1::default();
+        // left empty if it fails, no reader will find anything changed
+        let _ = unsafe {self.output.GetDesc1(&mut desc as *mut _)};
+        let coords = desc.DesktopCoordinates;
+        Rect { left: 0, top: 0, right: coords.right - coords.left, bottom: coords.bottom - coords.top }
+    }
+}
+
+fn same_size(texture: &ID3D11Texture2D1, desc: &D3D11_TEXTURE2D_DESC1) -> bool {
+    let mut existing = D3D11_TEXTURE2D_DESC1::default();
+    unsafe {texture.GetDesc1(&mut existing as *mut _)};
+    (existing.Width, existing.Height, existing.Format) == (desc.Width, desc.Height, desc.Format)
+}
+
+fn duplicate(output: &IDXGIOutput6, device: &ID3D11Device5) -> Result<IDXGIOutputDuplication, Box<dyn Error>> {
+    Ok(unsafe {
+        output.DuplicateOutput1(
+            device,
+            0,
+            &[Common::DXGI_FORMAT_R16G16B16A16_FLOAT]
+        )?
+    })
+}
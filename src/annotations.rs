@@ -0,0 +1,184 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    draw,
+    pixels::{Colour, PixelBuffer, Point, Rect},
+    text::{Align, Font},
+};
+
+/// Everything drawn on top of a capture. Kept apart from the pixels until export
+/// so a saved project can still be edited.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Annotations {
+    pub layers: Vec<AnnotationLayer>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AnnotationLayer {
+    pub name: String,
+    pub visible: bool,
+    pub annotations: Vec<Annotation>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Annotation {
+    /// Area left bright by the spotlight export, in screen pixels
+    Highlight { rect: Rect },
+    /// Numbered marker for step by step guides. The number isn't stored, steps count up
+    /// in the order they were placed so removing one renumbers the rest.
+    Step {
+        centre: Point,
+        /// Tip of a pointer arrow coming out of the marker
+        arrow: Option<Point>,
+    },
+}
+
+/// How step markers look when they're drawn
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StepStyle {
+    pub radius: f32,
+    pub fill: Colour,
+    pub number: Colour,
+    pub arrow_width: f32,
+}
+
+impl Default for StepStyle {
+    fn default() -> Self {
+        Self {
+            radius: 16.0,
+            fill: Colour::rgba(0xe5, 0x39, 0x35, 0xff),
+            number: Colour::rgba(0xff, 0xff, 0xff, 0xff),
+            arrow_width: 4.0,
+        }
+    }
+}
+
+impl Annotations {
+    /// Add to the top layer, making one if there are none yet
+    pub fn add(&mut self, annotation: Annotation) {
+        if self.layers.is_empty() {
+            self.layers.push(AnnotationLayer {
+                name: "Layer 1".to_string(),
+                visible: true,
+                annotations: Vec::new(),
+            });
+        }
+        self.layers.last_mut().unwrap().annotations.push(annotation);
+    }
+
+    /// Annotations on visible layers, bottom layer first
+    pub fn visible(&self) -> impl Iterator<Item = &Annotation> {
+        self.layers.iter()
+            .filter(|layer| layer.visible)
+            .flat_map(|layer| layer.annotations.iter())
+    }
+
+    pub fn highlights(&self) -> impl Iterator<Item = Rect> + '_ {
+        self.visible().filter_map(|annotation| match annotation {
+            Annotation::Highlight { rect } => Some(*rect),
+            _ => None,
+        })
+    }
+
+    /// Remove the top most visible step marker under `point`, returns false if there wasn't one
+    pub fn remove_step_at(&mut self, point: Point, radius: f32) -> bool {
+        for layer in self.layers.iter_mut().rev().filter(|layer| layer.visible) {
+            let hit = layer.annotations.iter().rposition(|annotation| match annotation {
+                Annotation::Step { centre, .. } => {
+                    ((centre.x - point.x) as f32).hypot((centre.y - point.y) as f32) <= radius
+                },
+                _ => false,
+            });
+            if let Some(i) = hit {
+                layer.annotations.remove(i);
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn clear(&mut self) {
+        self.layers.clear();
+    }
+
+    /// Rasterise onto `image`, whose top left corner is at `origin` in screen pixels.
+    /// Highlights belong to the spotlight effect so they aren't drawn here.
+    /// Numbers are left off the markers without a font.
+    pub fn draw(&self, image: &mut PixelBuffer, origin: Point, style: &StepStyle, font: Option<&Font>) {
+        let local = |p: Point| ((p.x - origin.x) as f32 + 0.5, (p.y - origin.y) as f32 + 0.5);
+        let fill = style.fill.to_linear();
+
+        let mut number = 0;
+        for annotation in self.visible() {
+            match annotation {
+                Annotation::Highlight { .. } => {},
+                Annotation::Step { centre, arrow } => {
+                    number += 1;
+                    let (cx, cy) = local(*centre);
+
+                    // arrow goes underneath so it comes out of the edge of the marker
+                    if let Some(tip) = arrow {
+                        draw_arrow(image, (cx, cy), local(*tip), style.arrow_width, fill);
+                    }
+                    draw::fill_circle(image, cx, cy, style.radius, fill);
+
+                    if let Some(font) = font {
+                        draw_number(image, font, number, cx, cy, style);
+                    }
+                },
+            }
+        }
+    }
+}
+
+fn draw_arrow(image: &mut PixelBuffer, from: (f32, f32), tip: (f32, f32), width: f32, colour: [f32; 4]) {
+    let (dx, dy) = (tip.0 - from.0, tip.1 - from.1);
+    let length = dx.hypot(dy);
+    if length < 1.0 {
+        return;
+    }
+    let (ux, uy) = (dx / length, dy / length);
+    let head_length = (width * 4.0).min(length);
+    let head_half = width * 2.0;
+
+    let base = (tip.0 - ux * head_length, tip.1 - uy * head_length);
+    draw::stroke_line(image, from, base, width, colour);
+    draw::fill_triangle(
+        image,
+        [
+            tip,
+            (base.0 - uy * head_half, base.1 + ux * head_half),
+            (base.0 + uy * head_half, base.1 - ux * head_half),
+        ],
+        colour,
+    );
+}
+
+fn draw_number(image: &mut PixelBuffer, font: &Font, number: usize, cx: f32, cy: f32, style: &StepStyle) {
+    let mask = font.render(&number.to_string(), style.radius * 1.2, Align::Centre);
+
+    // centre the ink rather than the line box, digits sit well above the descender
+    let mut bounds: Option<(u32, u32, u32, u32)> = None;
+    for y in 0..mask.height {
+        for x in 0..mask.width {
+            if mask.coverage[(y * mask.width + x) as usize] > 0.0 {
+                let b = bounds.get_or_insert((x, y, x, y));
+                *b = (b.0.min(x), b.1.min(y), b.2.max(x), b.3.max(y));
+            }
+        }
+    }
+    let Some((left, top, right, bottom)) = bounds else { return };
+
+    let x0 = (cx - (left + right + 1) as f32 / 2.0).round() as i32;
+    let y0 = (cy - (top + bottom + 1) as f32 / 2.0).round() as i32;
+    let [r, g, b, a] = style.number.to_linear();
+    for y in 0..mask.height {
+        for x in 0..mask.width {
+            let c = mask.coverage[(y * mask.width + x) as usize];
+            image.blend(x0 + x as i32, y0 + y as i32, [r, g, b, a * c]);
+        }
+    }
+}
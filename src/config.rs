@@ -0,0 +1,139 @@
+use std::{error::Error, path::{Path, PathBuf}};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    annotations::StepStyle,
+    effects::{
+        beautify::BeautifySettings,
+        spotlight::SpotlightSettings,
+        watermark::{Footer, Watermark},
+    },
+    encode::OutputFormat,
+    record::RecordSettings,
+    replay::ReplaySettings,
+    schedule::ScheduleSettings,
+    scroll::ScrollSettings,
+    watch::WatchSettings,
+};
+
+/// Settings read from `screenshotter.toml` in the working directory.
+/// Every field is optional, a missing file gives the default behaviour.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub export: ExportSettings,
+    /// Everywhere an export goes, each with its own format
+    pub sinks: Vec<Sink>,
+    /// Write an editable project next to every exported image
+    pub save_project: bool,
+    /// Shift+F11 selects a region to record, pressing it again stops
+    pub record: RecordSettings,
+    /// Ctrl+F11 selects a region to capture while it scrolls, pressing it again
+    /// stitches everything seen into one image and exports it
+    pub scroll: ScrollSettings,
+    /// Alt+F11 starts and stops taking captures by themselves, which go to the
+    /// sinks like any other. `{seq}` in a path numbers them.
+    pub schedule: ScheduleSettings,
+    /// Ctrl+Shift+F11 selects a region to watch, it's captured whenever enough of
+    /// it changes until Ctrl+Shift+F11 is pressed again. `{seq}` numbers the captures.
+    pub watch: WatchSettings,
+    /// Keeps the last stretch of the screen in memory. Ctrl+Alt+F11 saves it as a
+    /// clip, and in the overlay the arrow keys step back through it for a still.
+    pub replay: ReplaySettings,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            export: ExportSettings::default(),
+            sinks: vec![
+                Sink::File { path: "img.png".to_string(), format: OutputFormat::default() },
+                // every app reads the bitmap, the png keeps 16 bits for those that look for it
+                Sink::Clipboard { format: OutputFormat::Bmp },
+                Sink::Clipboard { format: OutputFormat::default() },
+            ],
+            save_project: false,
+            record: RecordSettings::default(),
+            scroll: ScrollSettings::default(),
+            schedule: ScheduleSettings::default(),
+            watch: WatchSettings::default(),
+            replay: ReplaySettings::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Sink {
+    File {
+        /// See `template` for the `{variables}` it can use
+        path: String,
+        #[serde(default)]
+        format: OutputFormat,
+    },
+    /// Replaces whatever is on the clipboard, every clipboard sink is offered at once
+    /// so pasting picks the format the other app likes best
+    Clipboard {
+        #[serde(default)]
+        format: OutputFormat,
+    },
+}
+
+impl Sink {
+    pub fn format(&self) -> &OutputFormat {
+        match self {
+            Sink::File { format, .. } | Sink::Clipboard { format } => format,
+        }
+    }
+}
+
+/// Everything that changes how a capture is turned into an image.
+/// Saved into projects so they export the same way again.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExportSettings {
+    /// Export the whole screen with everything outside the selections dimmed.
+    /// Hold ctrl when letting go of the mouse to add another selection.
+    pub spotlight: Option<SpotlightSettings>,
+    /// Look of the numbered step markers, right click places one
+    pub steps: StepStyle,
+    /// Text and image stamps drawn over the capture
+    pub watermarks: Vec<Watermark>,
+    /// Bar of text added below the capture
+    pub footer: Option<Footer>,
+    /// Put the capture on a backdrop with padding, rounded corners and a shadow.
+    pub beautify: Option<BeautifySettings>,
+    /// Font used for any text drawn on the capture
+    pub font: PathBuf,
+}
+
+impl Default for ExportSettings {
+    fn default() -> Self {
+        Self {
+            spotlight: None,
+            steps: StepStyle::default(),
+            watermarks: Vec::new(),
+            footer: None,
+            beautify: None,
+            font: PathBuf::from(r"C:\Windows\Fonts\segoeui.ttf"),
+        }
+    }
+}
+
+impl Config {
+    pub const FILE_NAME: &'static str = "screenshotter.toml";
+
+    pub fn load() -> Result<Self, Box<dyn Error>> {
+        match std::fs::read_to_string(Self::FILE_NAME) {
+            Ok(text) => Ok(toml::from_str(&text)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    /// Unlike `load` the file has to exist
+    pub fn load_from(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+}
@@ -0,0 +1,78 @@
+//! Anti aliased shapes for annotations. Coordinates are in pixels with
+//! pixel centres on the halves, colours are linear with straight alpha.
+
+use crate::pixels::PixelBuffer;
+
+pub fn fill_circle(image: &mut PixelBuffer, cx: f32, cy: f32, radius: f32, colour: [f32; 4]) {
+    let [r, g, b, a] = colour;
+    for_each_in(image, cx - radius, cy - radius, cx + radius, cy + radius, |image, x, y, px, py| {
+        let coverage = (radius - (px - cx).hypot(py - cy) + 0.5).clamp(0.0, 1.0);
+        image.blend(x, y, [r, g, b, a * coverage]);
+    });
+}
+
+/// A line `width` pixels thick with flat ends
+pub fn stroke_line(image: &mut PixelBuffer, from: (f32, f32), to: (f32, f32), width: f32, colour: [f32; 4]) {
+    let [r, g, b, a] = colour;
+    let half = width / 2.0;
+    let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+    let length_sq = (dx * dx + dy * dy).max(f32::EPSILON);
+
+    for_each_in(
+        image,
+        from.0.min(to.0) - half,
+        from.1.min(to.1) - half,
+        from.0.max(to.0) + half,
+        from.1.max(to.1) + half,
+        |image, x, y, px, py| {
+            let t = ((px - from.0) * dx + (py - from.1) * dy) / length_sq;
+            if !(0.0..=1.0).contains(&t) {
+                return;
+            }
+            let distance = (px - (from.0 + t * dx)).hypot(py - (from.1 + t * dy));
+            let coverage = (half - distance + 0.5).clamp(0.0, 1.0);
+            image.blend(x, y, [r, g, b, a * coverage]);
+        },
+    );
+}
+
+pub fn fill_triangle(image: &mut PixelBuffer, points: [(f32, f32); 3], colour: [f32; 4]) {
+    let [r, g, b, a] = colour;
+    // wind the same way every time so inside is always on the left of each edge
+    let [p0, mut p1, mut p2] = points;
+    if (p1.0 - p0.0) * (p2.1 - p0.1) - (p1.1 - p0.1) * (p2.0 - p0.0) < 0.0 {
+        std::mem::swap(&mut p1, &mut p2);
+    }
+    let edges = [(p0, p1), (p1, p2), (p2, p0)];
+
+    let (min_x, max_x) = (p0.0.min(p1.0).min(p2.0), p0.0.max(p1.0).max(p2.0));
+    let (min_y, max_y) = (p0.1.min(p1.1).min(p2.1), p0.1.max(p1.1).max(p2.1));
+    for_each_in(image, min_x, min_y, max_x, max_y, |image, x, y, px, py| {
+        // signed distance to each edge is negative inside, the nearest edge decides
+        let distance = edges.iter()
+            .map(|(a, b)| {
+                let (ex, ey) = (b.0 - a.0, b.1 - a.1);
+                ((px - a.0) * ey - (py - a.1) * ex) / ex.hypot(ey).max(f32::EPSILON)
+            })
+            .fold(f32::NEG_INFINITY, f32::max);
+        let coverage = (0.5 - distance).clamp(0.0, 1.0);
+        image.blend(x, y, [r, g, b, a * coverage]);
+    });
+}
+
+/// Call `f` for every pixel touching the box, with the pixel's centre
+fn for_each_in(
+    image: &mut PixelBuffer,
+    left: f32, top: f32, right: f32, bottom: f32,
+    mut f: impl FnMut(&mut PixelBuffer, i32, i32, f32, f32),
+) {
+    let x0 = (left.floor() as i32 - 1).max(0);
+    let y0 = (top.floor() as i32 - 1).max(0);
+    let x1 = (right.ceil() as i32 + 1).min(image.width as i32);
+    let y1 = (bottom.ceil() as i32 + 1).min(image.height as i32);
+    for y in y0..y1 {
+        for x in x0..x1 {
+            f(image, x, y, x as f32 + 0.5, y as f32 + 0.5);
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use serde::{Deserialize, Serialize};
+
+use crate::pixels::{self, Colour, PixelBuffer};
+
+use super::blur::gaussian_blur;
+
+/// Frame the capture on a backdrop, the way screenshots for docs get dressed up.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BeautifySettings {
+    /// Space between the capture and the edge of the output, in pixels
+    pub padding: u32,
+    pub corner_radius: f32,
+    pub backdrop: Backdrop,
+    pub shadow: Option<Shadow>,
+}
+
+impl Default for BeautifySettings {
+    fn default() -> Self {
+        Self {
+            padding: 64,
+            corner_radius: 12.0,
+            backdrop: Backdrop::Gradient {
+                from: Colour::rgba(0x4f, 0x46, 0xe5, 0xff),
+                to: Colour::rgba(0xdb, 0x27, 0x77, 0xff),
+                angle: 135.0,
+            },
+            shadow: Some(Shadow::default()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Backdrop {
+    /// Leave everything around the capture see-through
+    Transparent,
+    Solid { colour: Colour },
+    /// Linear gradient, `angle` is in degrees with 0 running left to right and 90 top to bottom
+    Gradient { from: Colour, to: Colour, angle: f32 },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Shadow {
+    pub offset_x: f32,
+    pub offset_y: f32,
+    /// Standard deviation of the gaussian, in pixels
+    pub blur: f32,
+    pub colour: Colour,
+}
+
+impl Default for Shadow {
+    fn default() -> Self {
+        Self {
+            offset_x: 0.0,
+            offset_y: 12.0,
+            blur: 18.0,
+            colour: Colour::rgba(0, 0, 0, 0x80),
+        }
+    }
+}
+
+pub fn beautify(image: &PixelBuffer, settings: &BeautifySettings) -> PixelBuffer {
+    let pad = settings.padding;
+    let (width, height) = (image.width + pad * 2, image.height + pad * 2);
+    let (w, h) = (width as usize, height as usize);
+    let radius = settings.corner_radius;
+
+    let mut canvas: Vec<[f32; 4]> = Vec::with_capacity(w * h);
+    for y in 0..h {
+        for x in 0..w {
+            canvas.push(settings.backdrop.sample(x as f32 + 0.5, y as f32 + 0.5, width as f32, height as f32));
+        }
+    }
+
+    if let Some(shadow) = &settings.shadow {
+        let mut mask: Vec<f32> = Vec::with_capacity(w * h);
+        for y in 0..h {
+            for x in 0..w {
+                mask.push(rounded_rect_coverage(
+                    x as f32 + 0.5 - pad as f32 - shadow.offset_x,
+                    y as f32 + 0.5 - pad as f32 - shadow.offset_y,
+                    image.width as f32,
+                    image.height as f32,
+                    radius,
+                ));
+            }
+        }
+        gaussian_blur(&mut mask, w, h, 1, shadow.blur);
+
+        let [r, g, b, a] = shadow.colour.to_linear();
+        for (dst, m) in canvas.iter_mut().zip(mask) {
+            *dst = pixels::over(*dst, [r, g, b, a * m]);
+        }
+    }
+
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let coverage = rounded_rect_coverage(
+                x as f32 + 0.5,
+                y as f32 + 0.5,
+                image.width as f32,
+                image.height as f32,
+                radius,
+            );
+            if coverage <= 0.0 {
+                continue;
+            }
+            let [r, g, b, a] = image.pixel(x, y).map(pixels::from_unorm16);
+            let dst = &mut canvas[((y + pad) * width + x + pad) as usize];
+            *dst = pixels::over(*dst, [r, g, b, a * coverage]);
+        }
+    }
+
+    PixelBuffer {
+        width,
+        height,
+        data: canvas.into_iter().map(|px| px.map(pixels::to_unorm16)).collect(),
+    }
+}
+
+impl Backdrop {
+    /// Linear light colour of the backdrop at a point on a canvas of the given size
+    fn sample(&self, x: f32, y: f32, width: f32, height: f32) -> [f32; 4] {
+        match self {
+            Backdrop::Transparent => [0.0; 4],
+            Backdrop::Solid { colour } => colour.to_linear(),
+            Backdrop::Gradient { from, to, angle } => {
+                let (dy, dx) = angle.to_radians().sin_cos();
+                // project onto the gradient direction so the corners land on exactly 0 and 1
+                let extent = (width * dx).abs() + (height * dy).abs();
+                let t = ((x - width / 2.0) * dx + (y - height / 2.0) * dy) / extent + 0.5;
+                let t = t.clamp(0.0, 1.0);
+
+                // blend in sRGB, which is what people expect a gradient to look like
+                let (from, to) = (from.to_srgb(), to.to_srgb());
+                let mut px = [0.0; 4];
+                for c in 0..4 {
+                    px[c] = from[c] + (to[c] - from[c]) * t;
+                }
+                [
+                    pixels::srgb_to_linear(px[0]),
+                    pixels::srgb_to_linear(px[1]),
+                    pixels::srgb_to_linear(px[2]),
+                    px[3],
+                ]
+            }
+        }
+    }
+}
+
+/// How much of the pixel centred on (`x`, `y`) is inside a `width` x `height` rectangle at the
+/// origin with corners rounded by `radius`. Edges get one pixel of anti aliasing.
+fn rounded_rect_coverage(x: f32, y: f32, width: f32, height: f32, radius: f32) -> f32 {
+    let radius = radius.clamp(0.0, width.min(height) / 2.0);
+    let qx = (x - width / 2.0).abs() - (width / 2.0 - radius);
+    let qy = (y - height / 2.0).abs() - (height / 2.0 - radius);
+    let outside = qx.max(0.0).hypot(qy.max(0.0));
+    let inside = qx.max(qy).min(0.0);
+    let distance = outside + inside - radius;
+    (0.5 - distance).clamp(0.0, 1.0)
+}
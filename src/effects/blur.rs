@@ -0,0 +1,63 @@
+/// Approximate a gaussian blur with standard deviation `sigma` by running
+/// three box blurs over each axis. `data` holds `channels` interleaved floats per pixel,
+/// edges are extended so the image doesn't darken towards the border.
+pub fn gaussian_blur(data: &mut [f32], width: usize, height: usize, channels: usize, sigma: f32) {
+    if sigma <= 0.0 || width == 0 || height == 0 {
+        return;
+    }
+
+    let mut line = Vec::new();
+    for radius in box_radii(sigma) {
+        // rows
+        for y in 0..height {
+            for c in 0..channels {
+                line.clear();
+                line.extend((0..width).map(|x| data[(y * width + x) * channels + c]));
+                box_blur_line(&line, radius, |x, v| data[(y * width + x) * channels + c] = v);
+            }
+        }
+        // columns
+        for x in 0..width {
+            for c in 0..channels {
+                line.clear();
+                line.extend((0..height).map(|y| data[(y * width + x) * channels + c]));
+                box_blur_line(&line, radius, |y, v| data[(y * width + x) * channels + c] = v);
+            }
+        }
+    }
+}
+
+/// Radii of the three boxes whose repeated application has a variance of sigma squared.
+fn box_radii(sigma: f32) -> [usize; 3] {
+    const PASSES: f32 = 3.0;
+    let ideal = (12.0 * sigma * sigma / PASSES + 1.0).sqrt();
+    let mut lower = ideal.floor() as i32;
+    if lower % 2 == 0 {
+        lower -= 1;
+    }
+    let lower = lower.max(1);
+    let upper = lower + 2;
+
+    let (l, s) = (lower as f32, sigma);
+    let lower_count = ((12.0 * s * s - PASSES * l * l - 4.0 * PASSES * l - 3.0 * PASSES) / (-4.0 * l - 4.0)).round();
+
+    let mut radii = [0; 3];
+    for (i, r) in radii.iter_mut().enumerate() {
+        let size = if (i as f32) < lower_count { lower } else { upper };
+        *r = (size as usize - 1) / 2;
+    }
+    radii
+}
+
+fn box_blur_line(line: &[f32], radius: usize, mut write: impl FnMut(usize, f32)) {
+    let len = line.len() as isize;
+    let r = radius as isize;
+    let at = |i: isize| line[i.clamp(0, len - 1) as usize];
+    let scale = 1.0 / (2 * r + 1) as f32;
+
+    let mut sum: f32 = (-r..=r).map(at).sum();
+    for i in 0..len {
+        write(i as usize, sum * scale);
+        sum += at(i + r + 1) - at(i - r);
+    }
+}
@@ -0,0 +1,6 @@
+//! Cpu side processing applied to a capture before it is encoded.
+
+pub mod beautify;
+pub mod blur;
+pub mod spotlight;
+pub mod watermark;
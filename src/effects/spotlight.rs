@@ -0,0 +1,64 @@
+use serde::{Deserialize, Serialize};
+
+use crate::pixels::{self, PixelBuffer, Rect};
+
+use super::blur::gaussian_blur;
+
+/// Dim everything outside the highlighted rectangles, like the selection overlay does.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SpotlightSettings {
+    /// Multiplier for the colour outside the highlights, the overlay uses 0.35
+    pub dim: f32,
+    /// Standard deviation of a gaussian blur over the dimmed area, 0 turns it off
+    pub blur: f32,
+}
+
+impl Default for SpotlightSettings {
+    fn default() -> Self {
+        Self { dim: 0.35, blur: 0.0 }
+    }
+}
+
+/// Cpu version of `PS_main`. Regions are tested in normalised texture coordinates
+/// against pixel centres the same way the shader does, so the edges line up exactly.
+pub fn spotlight(image: &PixelBuffer, regions: &[Rect], settings: &SpotlightSettings) -> PixelBuffer {
+    let (w, h) = (image.width as f32, image.height as f32);
+    let regions: Vec<[f32; 4]> = regions.iter()
+        .map(|r| [r.left as f32 / w, r.top as f32 / h, r.right as f32 / w, r.bottom as f32 / h])
+        .collect();
+
+    let blurred = (settings.blur > 0.0).then(|| {
+        let mut planes: Vec<f32> = image.data.iter()
+            .flat_map(|px| px.map(pixels::from_unorm16))
+            .collect();
+        gaussian_blur(&mut planes, image.width as usize, image.height as usize, 4, settings.blur);
+        planes
+    });
+
+    let mut data = Vec::with_capacity(image.data.len());
+    for y in 0..image.height {
+        let v = (y as f32 + 0.5) / h;
+        for x in 0..image.width {
+            let u = (x as f32 + 0.5) / w;
+            let i = (y * image.width + x) as usize;
+            let px = image.data[i];
+
+            let highlighted = regions.iter()
+                .any(|r| u > r[0] && v > r[1] && u < r[2] && v < r[3]);
+            if highlighted {
+                data.push(px);
+                continue;
+            }
+
+            let rgb: [f32; 3] = match &blurred {
+                Some(planes) => [planes[i * 4], planes[i * 4 + 1], planes[i * 4 + 2]],
+                None => [px[0], px[1], px[2]].map(pixels::from_unorm16),
+            };
+            let [r, g, b] = rgb.map(|c| pixels::to_unorm16(c * settings.dim));
+            data.push([r, g, b, px[3]]);
+        }
+    }
+
+    PixelBuffer { width: image.width, height: image.height, data }
+}
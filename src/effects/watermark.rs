@@ -0,0 +1,222 @@
+use std::{error::Error, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::ExportSettings,
+    pixels::{self, Colour, PixelBuffer},
+    template::TemplateContext,
+    text::{Align, Font, TextMask},
+};
+
+/// A stamp drawn over the capture. Text goes through the same templates as file names.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Watermark {
+    #[serde(flatten)]
+    pub kind: WatermarkKind,
+    #[serde(default)]
+    pub position: Position,
+    /// Distance from the edges of the capture, in pixels
+    #[serde(default = "default_margin")]
+    pub margin: u32,
+    #[serde(default = "default_opacity")]
+    pub opacity: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WatermarkKind {
+    Text {
+        text: String,
+        #[serde(default = "default_text_size")]
+        size: f32,
+        #[serde(default = "default_text_colour")]
+        colour: Colour,
+    },
+    Image {
+        path: PathBuf,
+        #[serde(default = "default_scale")]
+        scale: f32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Position {
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Centre,
+    Right,
+    BottomLeft,
+    Bottom,
+    #[default]
+    BottomRight,
+}
+
+/// A bar added under the capture instead of drawing over it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Footer {
+    pub text: String,
+    pub size: f32,
+    pub padding: u32,
+    pub align: Align,
+    pub colour: Colour,
+    pub background: Colour,
+}
+
+impl Default for Footer {
+    fn default() -> Self {
+        Self {
+            text: "{machine}  {now:%Y-%m-%d %H:%M:%S}".to_string(),
+            size: default_text_size(),
+            padding: 8,
+            align: Align::Left,
+            colour: default_text_colour(),
+            background: Colour::rgba(0x20, 0x20, 0x20, 0xff),
+        }
+    }
+}
+
+fn default_margin() -> u32 { 16 }
+fn default_opacity() -> f32 { 0.6 }
+fn default_text_size() -> f32 { 18.0 }
+fn default_text_colour() -> Colour { Colour::rgba(0xff, 0xff, 0xff, 0xff) }
+fn default_scale() -> f32 { 1.0 }
+
+/// Draw the configured watermarks then append the footer.
+pub fn watermark(image: &PixelBuffer, settings: &ExportSettings, context: &TemplateContext) -> Result<PixelBuffer, Box<dyn Error>> {
+    let mut image = image.clone();
+
+    let needs_font = settings.footer.is_some()
+        || settings.watermarks.iter().any(|w| matches!(w.kind, WatermarkKind::Text { .. }));
+    let font = if needs_font { Some(Font::load(&settings.font)?) } else { None };
+
+    for mark in &settings.watermarks {
+        match &mark.kind {
+            WatermarkKind::Text { text, size, colour } => {
+                let mask = font.as_ref().unwrap().render(&context.expand(text)?, *size, mark.position.align());
+                let (x, y) = mark.position.place(&image, mask.width, mask.height, mark.margin);
+                let [r, g, b, a] = colour.to_linear();
+                draw_mask(&mut image, &mask, x, y, [r, g, b, a * mark.opacity]);
+            },
+            WatermarkKind::Image { path, scale } => {
+                let stamp = scaled(&PixelBuffer::open_png(path)?, *scale);
+                let (x, y) = mark.position.place(&image, stamp.width, stamp.height, mark.margin);
+                for sy in 0..stamp.height {
+                    for sx in 0..stamp.width {
+                        let [r, g, b, a] = stamp.pixel(sx, sy).map(pixels::from_unorm16);
+                        image.blend(x + sx as i32, y + sy as i32, [r, g, b, a * mark.opacity]);
+                    }
+                }
+            },
+        }
+    }
+
+    if let Some(footer) = &settings.footer {
+        let mask = font.as_ref().unwrap().render(&context.expand(&footer.text)?, footer.size, footer.align);
+        let bar = mask.height + footer.padding * 2;
+
+        let mut framed = PixelBuffer {
+            width: image.width,
+            height: image.height + bar,
+            data: image.data,
+        };
+        let background = footer.background.to_linear().map(pixels::to_unorm16);
+        framed.data.resize((framed.width * framed.height) as usize, background);
+
+        let x = match footer.align {
+            Align::Left => footer.padding as i32,
+            Align::Centre => (framed.width as i32 - mask.width as i32) / 2,
+            Align::Right => framed.width as i32 - mask.width as i32 - footer.padding as i32,
+        };
+        let y = (image.height + footer.padding) as i32;
+        draw_mask(&mut framed, &mask, x, y, footer.colour.to_linear());
+        image = framed;
+    }
+
+    Ok(image)
+}
+
+impl Position {
+    /// Top left corner for something `width` x `height` on `image`
+    fn place(self, image: &PixelBuffer, width: u32, height: u32, margin: u32) -> (i32, i32) {
+        let free_x = image.width as i32 - width as i32;
+        let free_y = image.height as i32 - height as i32;
+        let m = margin as i32;
+        let x = match self {
+            Position::TopLeft | Position::Left | Position::BottomLeft => m,
+            Position::Top | Position::Centre | Position::Bottom => free_x / 2,
+            Position::TopRight | Position::Right | Position::BottomRight => free_x - m,
+        };
+        let y = match self {
+            Position::TopLeft | Position::Top | Position::TopRight => m,
+            Position::Left | Position::Centre | Position::Right => free_y / 2,
+            Position::BottomLeft | Position::Bottom | Position::BottomRight => free_y - m,
+        };
+        (x, y)
+    }
+
+    /// Lines of text line up with the edge they sit against
+    fn align(self) -> Align {
+        match self {
+            Position::TopLeft | Position::Left | Position::BottomLeft => Align::Left,
+            Position::Top | Position::Centre | Position::Bottom => Align::Centre,
+            Position::TopRight | Position::Right | Position::BottomRight => Align::Right,
+        }
+    }
+}
+
+fn draw_mask(image: &mut PixelBuffer, mask: &TextMask, x: i32, y: i32, colour: [f32; 4]) {
+    let [r, g, b, a] = colour;
+    for my in 0..mask.height {
+        for mx in 0..mask.width {
+            let c = mask.coverage[(my * mask.width + mx) as usize];
+            image.blend(x + mx as i32, y + my as i32, [r, g, b, a * c]);
+        }
+    }
+}
+
+/// Bilinear resize, done on premultiplied colour so transparent edges don't go dark
+fn scaled(image: &PixelBuffer, scale: f32) -> PixelBuffer {
+    if scale == 1.0 || image.width == 0 || image.height == 0 {
+        return image.clone();
+    }
+    let width = ((image.width as f32 * scale).round() as u32).max(1);
+    let height = ((image.height as f32 * scale).round() as u32).max(1);
+
+    let premultiplied: Vec<[f32; 4]> = image.data.iter()
+        .map(|px| {
+            let [r, g, b, a] = px.map(pixels::from_unorm16);
+            [r * a, g * a, b * a, a]
+        })
+        .collect();
+    let at = |x: i32, y: i32| {
+        let x = x.clamp(0, image.width as i32 - 1) as u32;
+        let y = y.clamp(0, image.height as i32 - 1) as u32;
+        premultiplied[(y * image.width + x) as usize]
+    };
+
+    let mut data = Vec::with_capacity((width * height) as usize);
+    for y in 0..height {
+        let sy = (y as f32 + 0.5) / scale - 0.5;
+        let (y0, fy) = (sy.floor() as i32, sy - sy.floor());
+        for x in 0..width {
+            let sx = (x as f32 + 0.5) / scale - 0.5;
+            let (x0, fx) = (sx.floor() as i32, sx - sx.floor());
+
+            let mut px = [0.0; 4];
+            for (c, out) in px.iter_mut().enumerate() {
+                let top = at(x0, y0)[c] * (1.0 - fx) + at(x0 + 1, y0)[c] * fx;
+                let bottom = at(x0, y0 + 1)[c] * (1.0 - fx) + at(x0 + 1, y0 + 1)[c] * fx;
+                *out = top * (1.0 - fy) + bottom * fy;
+            }
+            let a = px[3];
+            let unpremultiplied = if a > 0.0 { [px[0] / a, px[1] / a, px[2] / a, a] } else { [0.0; 4] };
+            data.push(unpremultiplied.map(pixels::to_unorm16));
+        }
+    }
+    PixelBuffer { width, height, data }
+}
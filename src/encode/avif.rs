@@ -0,0 +1,180 @@
+use std::error::Error;
+
+use avif_serialize::{constants, Aviffy};
+use rav1e::prelude::*;
+use ravif::{Img, RGBA8};
+use serde::{Deserialize, Serialize};
+
+use crate::pixels::{PixelBuffer, ScrgbBuffer, SCRGB_WHITE};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AvifSettings {
+    pub mode: AvifMode,
+    /// 1 to 100
+    pub quality: f32,
+    /// 1 is slowest and smallest, 10 is fastest
+    pub speed: u8,
+}
+
+impl Default for AvifSettings {
+    fn default() -> Self {
+        Self {
+            mode: AvifMode::Sdr,
+            quality: 80.0,
+            speed: 6,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AvifMode {
+    /// 8 bit sRGB from the processed export, alpha included
+    Sdr,
+    /// 10 bit BT.2100 PQ from the scRGB capture so highlights above SDR white
+    /// survive. Edits are carried over, but beautify only exists in SDR.
+    Hdr,
+}
+
+pub fn encode(image: &PixelBuffer, hdr: Option<&ScrgbBuffer>, settings: &AvifSettings) -> Result<Vec<u8>, Box<dyn Error>> {
+    if !(1.0..=100.0).contains(&settings.quality) {
+        return Err(format!("avif quality has to be 1 to 100, not {}", settings.quality).into());
+    }
+    if !(1..=10).contains(&settings.speed) {
+        return Err(format!("avif speed has to be 1 to 10, not {}", settings.speed).into());
+    }
+
+    match settings.mode {
+        AvifMode::Sdr => encode_sdr(image, settings),
+        AvifMode::Hdr => encode_hdr(hdr.ok_or("hdr avif needs the scRGB capture")?, settings),
+    }
+}
+
+fn encode_sdr(image: &PixelBuffer, settings: &AvifSettings) -> Result<Vec<u8>, Box<dyn Error>> {
+    let pixels: Vec<RGBA8> = image.to_srgba8()
+        .chunks_exact(4)
+        .map(|px| RGBA8::new(px[0], px[1], px[2], px[3]))
+        .collect();
+    let encoded = ravif::Encoder::new()
+        .with_quality(settings.quality)
+        .with_alpha_quality(settings.quality)
+        .with_speed(settings.speed)
+        .encode_rgba(Img::new(&pixels[..], image.width as usize, image.height as usize))?;
+    Ok(encoded.avif_file)
+}
+
+fn encode_hdr(image: &ScrgbBuffer, settings: &AvifSettings) -> Result<Vec<u8>, Box<dyn Error>> {
+    let (width, height) = (image.width as usize, image.height as usize);
+    if width == 0 || height == 0 {
+        return Err("can't encode an empty image".into());
+    }
+
+    // BT.2020 in nits for the light levels, then PQ and full range YCbCr for the planes
+    let mut max_cll = 0.0f32;
+    let mut total_fall = 0.0f64;
+    let mut planes = [Vec::with_capacity(width * height), Vec::with_capacity(width * height), Vec::with_capacity(width * height)];
+    for px in &image.data {
+        let rgb = bt709_to_bt2020([px[0], px[1], px[2]].map(|c| c.to_f32() * SCRGB_WHITE)).map(|c| c.clamp(0.0, 10000.0));
+        let brightest = rgb[0].max(rgb[1]).max(rgb[2]);
+        max_cll = max_cll.max(brightest);
+        total_fall += brightest as f64;
+
+        let [r, g, b] = rgb.map(pq_oetf);
+        let y = KR * r + (1.0 - KR - KB) * g + KB * b;
+        let cb = (b - y) / (2.0 * (1.0 - KB)) + 0.5;
+        let cr = (r - y) / (2.0 * (1.0 - KR)) + 0.5;
+        for (plane, v) in planes.iter_mut().zip([y, cb, cr]) {
+            plane.push((v.clamp(0.0, 1.0) * 1023.0).round() as u16);
+        }
+    }
+    let max_fall = (total_fall / image.data.len() as f64) as f32;
+    let light = ContentLight {
+        max_content_light_level: max_cll.round() as u16,
+        max_frame_average_light_level: max_fall.round() as u16,
+    };
+
+    let config = Config::new()
+        .with_encoder_config(EncoderConfig {
+            width,
+            height,
+            bit_depth: 10,
+            chroma_sampling: ChromaSampling::Cs444,
+            pixel_range: PixelRange::Full,
+            color_description: Some(ColorDescription {
+                color_primaries: ColorPrimaries::BT2020,
+                transfer_characteristics: TransferCharacteristics::SMPTE2084,
+                matrix_coefficients: MatrixCoefficients::BT2020NCL,
+            }),
+            content_light: Some(light),
+            still_picture: true,
+            quantizer: quality_to_quantizer(settings.quality),
+            min_quantizer: quality_to_quantizer(settings.quality) as u8,
+            speed_settings: SpeedSettings::from_preset(settings.speed),
+            ..Default::default()
+        })
+        .with_threads(0);
+    let mut context: Context<u16> = config.new_context()?;
+    let mut frame = context.new_frame();
+    for (plane, data) in frame.planes.iter_mut().zip(&planes) {
+        let bytes: Vec<u8> = data.iter().flat_map(|v| v.to_ne_bytes()).collect();
+        plane.copy_from_raw_u8(&bytes, width * 2, 2);
+    }
+    context.send_frame(frame)?;
+    context.flush();
+
+    let mut av1 = Vec::new();
+    loop {
+        match context.receive_packet() {
+            Ok(mut packet) => av1.append(&mut packet.data),
+            Err(EncoderStatus::Encoded) => continue,
+            Err(EncoderStatus::LimitReached) => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    // the av1 stream carries the colour info too but readers go by the container
+    Ok(Aviffy::new()
+        .set_color_primaries(constants::ColorPrimaries::Bt2020)
+        .set_transfer_characteristics(constants::TransferCharacteristics::Smpte2084)
+        .set_matrix_coefficients(constants::MatrixCoefficients::Bt2020Ncl)
+        .set_full_color_range(true)
+        .set_content_light_level(light.max_content_light_level, light.max_frame_average_light_level)
+        .to_vec(&av1, None, width as u32, height as u32, 10))
+}
+
+/// BT.2020 luma weights
+const KR: f32 = 0.2627;
+const KB: f32 = 0.0593;
+
+pub fn bt709_to_bt2020([r, g, b]: [f32; 3]) -> [f32; 3] {
+    [
+        0.6274 * r + 0.3293 * g + 0.0433 * b,
+        0.0691 * r + 0.9195 * g + 0.0114 * b,
+        0.0164 * r + 0.0880 * g + 0.8956 * b,
+    ]
+}
+
+/// SMPTE ST 2084 from nits to a 0-1 signal
+pub fn pq_oetf(nits: f32) -> f32 {
+    const M1: f32 = 2610.0 / 16384.0;
+    const M2: f32 = 2523.0 / 4096.0 * 128.0;
+    const C1: f32 = 3424.0 / 4096.0;
+    const C2: f32 = 2413.0 / 4096.0 * 32.0;
+    const C3: f32 = 2392.0 / 4096.0 * 32.0;
+    let l = (nits / 10000.0).powf(M1);
+    ((C1 + C2 * l) / (1.0 + C3 * l)).powf(M2)
+}
+
+/// Same curve ravif uses so quality means the same thing in both modes
+fn quality_to_quantizer(quality: f32) -> usize {
+    let q = quality / 100.0;
+    let x = if q >= 0.85 {
+        (1.0 - q) * 3.0
+    } else if q > 0.25 {
+        1.0 - 0.125 - q * 0.5
+    } else {
+        1.0 - q
+    };
+    (x * 255.0).round() as usize
+}
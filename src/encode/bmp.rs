@@ -0,0 +1,64 @@
+//! 32 bit BMP with a BITMAPV5HEADER, top down with straight alpha in sRGB.
+//!
+//! The same bytes minus the file header are a packed DIB, which is what
+//! the standard CF_DIBV5 clipboard format holds.
+
+use std::error::Error;
+
+use crate::pixels::PixelBuffer;
+
+/// The predefined clipboard format for a V5 header followed by the pixels
+pub const CF_DIBV5: u32 = 17;
+
+/// BITMAPFILEHEADER, which the clipboard leaves off
+pub const FILE_HEADER_LEN: usize = 14;
+const INFO_HEADER_LEN: usize = 124;
+
+const BI_BITFIELDS: u32 = 3;
+const LCS_SRGB: u32 = u32::from_be_bytes(*b"sRGB");
+const LCS_GM_IMAGES: u32 = 4;
+/// 72 dpi
+const PIXELS_PER_METRE: i32 = 2835;
+
+pub fn encode(image: &PixelBuffer) -> Result<Vec<u8>, Box<dyn Error>> {
+    if image.width == 0 || image.height == 0 {
+        return Err("can't encode an empty image".into());
+    }
+    let width = i32::try_from(image.width).map_err(|_| "image too wide for a bmp")?;
+    let height = i32::try_from(image.height).map_err(|_| "image too tall for a bmp")?;
+    let image_len = image.width as u64 * image.height as u64 * 4;
+    let file_len = u32::try_from((FILE_HEADER_LEN + INFO_HEADER_LEN) as u64 + image_len).map_err(|_| "image too big for a bmp")?;
+
+    let mut out = Vec::with_capacity(file_len as usize);
+    out.extend(b"BM");
+    out.extend(file_len.to_le_bytes());
+    out.extend([0; 4]); // reserved
+    out.extend((FILE_HEADER_LEN as u32 + INFO_HEADER_LEN as u32).to_le_bytes());
+
+    out.extend((INFO_HEADER_LEN as u32).to_le_bytes());
+    out.extend(width.to_le_bytes());
+    // negative height is top down
+    out.extend((-height).to_le_bytes());
+    out.extend(1u16.to_le_bytes()); // planes
+    out.extend(32u16.to_le_bytes());
+    out.extend(BI_BITFIELDS.to_le_bytes());
+    out.extend((image_len as u32).to_le_bytes());
+    out.extend(PIXELS_PER_METRE.to_le_bytes());
+    out.extend(PIXELS_PER_METRE.to_le_bytes());
+    out.extend(0u32.to_le_bytes()); // no palette
+    out.extend(0u32.to_le_bytes());
+    // masks for bytes stored as blue, green, red, alpha
+    for mask in [0x00ff_0000u32, 0x0000_ff00, 0x0000_00ff, 0xff00_0000] {
+        out.extend(mask.to_le_bytes());
+    }
+    out.extend(LCS_SRGB.to_le_bytes());
+    out.extend([0; 36]); // endpoints, unused for sRGB
+    out.extend([0; 12]); // gamma, likewise
+    out.extend(LCS_GM_IMAGES.to_le_bytes());
+    out.extend([0; 12]); // no profile, reserved
+
+    for px in image.to_srgba8().chunks_exact(4) {
+        out.extend([px[2], px[1], px[0], px[3]]);
+    }
+    Ok(out)
+}
@@ -0,0 +1,158 @@
+//! What the samples in an exported file mean, and how each container says so.
+//!
+//! Encoders describe their pixels with an `Encoding` and take every colour
+//! tag from it, so a file can't claim a curve or primaries it wasn't given.
+
+use std::borrow::Cow;
+
+use half::f16;
+use serde::{Deserialize, Serialize};
+
+use super::icc::{self, Curve, Primaries};
+use crate::pixels::{from_unorm16, to_unorm16, PixelBuffer, ScrgbBuffer};
+
+/// Primaries to store the samples in. The capture is sRGB, so converting only
+/// matters to apps that expect a wider space, or to float samples where colours
+/// outside sRGB would otherwise go negative.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Gamut {
+    Srgb,
+    DisplayP3,
+    Rec2020,
+}
+
+impl Gamut {
+    fn primaries(self) -> &'static Primaries {
+        match self {
+            Gamut::Srgb => &Primaries::SRGB,
+            Gamut::DisplayP3 => &Primaries::DISPLAY_P3,
+            Gamut::Rec2020 => &Primaries::REC2020,
+        }
+    }
+
+    /// Linear sRGB to linear values in this gamut, none when there's nothing to do
+    fn matrix(self) -> Option<[[f32; 3]; 3]> {
+        (self != Gamut::Srgb).then(|| icc::conversion(&Primaries::SRGB, self.primaries()).map(|row| row.map(|v| v as f32)))
+    }
+
+    pub fn convert(self, image: &PixelBuffer) -> Cow<'_, PixelBuffer> {
+        let Some(matrix) = self.matrix() else {
+            return Cow::Borrowed(image);
+        };
+        let data = image.data.iter()
+            .map(|px| {
+                let [r, g, b] = apply(&matrix, [px[0], px[1], px[2]].map(from_unorm16)).map(to_unorm16);
+                [r, g, b, px[3]]
+            })
+            .collect();
+        Cow::Owned(PixelBuffer { width: image.width, height: image.height, data })
+    }
+
+    /// Same for the capture, nothing is clamped
+    pub fn convert_scrgb(self, image: &ScrgbBuffer) -> Cow<'_, ScrgbBuffer> {
+        let Some(matrix) = self.matrix() else {
+            return Cow::Borrowed(image);
+        };
+        let data = image.data.iter()
+            .map(|px| {
+                let [r, g, b] = apply(&matrix, [px[0], px[1], px[2]].map(f16::to_f32)).map(f16::from_f32);
+                [r, g, b, px[3]]
+            })
+            .collect();
+        Cow::Owned(ScrgbBuffer { width: image.width, height: image.height, data })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transfer {
+    /// The sRGB curve, used for 8 bit samples whatever the gamut
+    Srgb,
+    /// Light as is, our 16 bit buffers and the capture
+    Linear,
+}
+
+impl Transfer {
+    fn curve(self) -> Curve {
+        match self {
+            Transfer::Srgb => icc::SRGB_CURVE,
+            Transfer::Linear => icc::LINEAR_CURVE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Encoding {
+    pub gamut: Gamut,
+    pub transfer: Transfer,
+}
+
+impl Encoding {
+    pub const SRGB: Encoding = Encoding { gamut: Gamut::Srgb, transfer: Transfer::Srgb };
+
+    pub fn new(gamut: Gamut, transfer: Transfer) -> Self {
+        Self { gamut, transfer }
+    }
+
+    pub fn icc(self) -> Vec<u8> {
+        let description = match (self.gamut, self.transfer) {
+            (Gamut::Srgb, Transfer::Srgb) => "sRGB",
+            (Gamut::Srgb, Transfer::Linear) => "Linear sRGB",
+            (Gamut::DisplayP3, Transfer::Srgb) => "Display P3",
+            (Gamut::DisplayP3, Transfer::Linear) => "Linear Display P3",
+            (Gamut::Rec2020, Transfer::Srgb) => "Rec.2020 with the sRGB curve",
+            (Gamut::Rec2020, Transfer::Linear) => "Linear Rec.2020",
+        };
+        icc::rgb_profile(description, self.gamut.primaries(), self.transfer.curve())
+    }
+
+    /// H.273 code points: primaries, transfer, matrix (0 is plain RGB) and full range
+    pub fn cicp(self) -> [u8; 4] {
+        let primaries = match self.gamut {
+            Gamut::Srgb => 1,
+            Gamut::DisplayP3 => 12,
+            Gamut::Rec2020 => 9,
+        };
+        let transfer = match self.transfer {
+            Transfer::Srgb => 13,
+            Transfer::Linear => 8,
+        };
+        [primaries, transfer, 0, 1]
+    }
+
+    /// The sRGB chunk when that's what it is, otherwise an ICC profile. gAMA and
+    /// cHRM go in as well for decoders that know neither. The cICP chunk isn't
+    /// supported by the png crate, see `png_cicp`.
+    pub fn tag_png(self, info: &mut png::Info) {
+        let gamma = match self.transfer {
+            Transfer::Srgb => 45455,
+            Transfer::Linear => 100000,
+        };
+        info.source_gamma = Some(png::ScaledFloat::from_scaled(gamma));
+        let p = self.gamut.primaries();
+        let xy = |(x, y): (f64, f64)| {
+            let scaled = |v: f64| png::ScaledFloat::from_scaled((v * 100000.0).round() as u32);
+            (scaled(x), scaled(y))
+        };
+        info.source_chromaticities = Some(png::SourceChromaticities {
+            white: xy(p.white),
+            red: xy(p.red),
+            green: xy(p.green),
+            blue: xy(p.blue),
+        });
+        if self == Encoding::SRGB {
+            info.srgb = Some(png::SrgbRenderingIntent::Perceptual);
+        } else {
+            info.icc_profile = Some(self.icc().into());
+        }
+    }
+
+    /// Data for a cICP chunk, only needed when the sRGB chunk can't say it
+    pub fn png_cicp(self) -> Option<[u8; 4]> {
+        (self != Encoding::SRGB).then(|| self.cicp())
+    }
+}
+
+fn apply(m: &[[f32; 3]; 3], v: [f32; 3]) -> [f32; 3] {
+    m.map(|row| row[0] * v[0] + row[1] * v[1] + row[2] * v[2])
+}
@@ -0,0 +1,173 @@
+//! zlib compression split across threads.
+//!
+//! Each thread deflates its own slice of the input into raw blocks. Every
+//! slice but the last ends with a sync flush, which pads to a byte boundary
+//! without marking the stream finished, so the pieces can be joined as is.
+//! The adler32 checksums of the slices are combined for the trailer.
+
+use std::error::Error;
+
+use flate2::{Compress, Compression, FlushCompress, Status};
+
+/// Slices smaller than this cost more in lost matches than they gain in speed
+const MIN_SLICE: usize = 256 * 1024;
+
+/// A complete zlib stream of `data`. `level` is 0 to 9, `threads` 0 uses every core.
+pub fn zlib(data: &[u8], level: u32, threads: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let threads = match threads {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    };
+    let slices = threads.min(data.len() / MIN_SLICE).max(1);
+    let slice_len = data.len().div_ceil(slices).max(1);
+
+    let pieces: Vec<Result<(Vec<u8>, u32), String>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = data.chunks(slice_len)
+            .enumerate()
+            .map(|(i, slice)| {
+                let last = (i + 1) * slice_len >= data.len();
+                scope.spawn(move || Ok((deflate(slice, level, last)?, adler32(slice))))
+            })
+            .collect();
+        handles.into_iter()
+            .map(|handle| handle.join().unwrap_or_else(|_| Err("deflate thread panicked".to_string())))
+            .collect()
+    });
+
+    let mut out = Vec::with_capacity(data.len() / 2);
+    out.extend(header(level));
+    // empty input still needs one finished block
+    if data.is_empty() {
+        out.extend(deflate(&[], level, true)?);
+    }
+    let mut checksum = 1;
+    for (piece, slice) in pieces.into_iter().zip(data.chunks(slice_len)) {
+        let (piece, adler) = piece?;
+        out.extend(piece);
+        checksum = adler32_combine(checksum, adler, slice.len());
+    }
+    out.extend(checksum.to_be_bytes());
+    Ok(out)
+}
+
+/// Raw deflate blocks for one slice, only the last one closes the stream
+fn deflate(data: &[u8], level: u32, last: bool) -> Result<Vec<u8>, String> {
+    let mut compress = Compress::new(Compression::new(level), false);
+    let flush = if last { FlushCompress::Finish } else { FlushCompress::Sync };
+    let mut out = Vec::with_capacity(data.len() / 2 + 1024);
+    loop {
+        if out.len() == out.capacity() {
+            out.reserve(out.capacity());
+        }
+        let consumed = compress.total_in() as usize;
+        let status = compress.compress_vec(&data[consumed..], &mut out, flush).map_err(|e| e.to_string())?;
+
+        let all_in = compress.total_in() as usize == data.len();
+        match status {
+            Status::StreamEnd => return Ok(out),
+            // a flush is done once it stops filling the buffer
+            _ if !last && all_in && out.len() < out.capacity() => return Ok(out),
+            _ => {},
+        }
+    }
+}
+
+fn header(level: u32) -> [u8; 2] {
+    // deflate with a 32k window
+    let cmf = 0x78u8;
+    let flevel = match level {
+        0..=1 => 0,
+        2..=5 => 1,
+        6 => 2,
+        _ => 3,
+    };
+    let flg = flevel << 6;
+    // the two bytes together have to be a multiple of 31
+    let check = 31 - ((cmf as u16 * 256 + flg as u16) % 31) as u8;
+    [cmf, flg | (check % 31)]
+}
+
+const ADLER_MOD: u32 = 65521;
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    // largest run that can't overflow before taking the modulus
+    for chunk in data.chunks(5552) {
+        for byte in chunk {
+            a += *byte as u32;
+            b += a;
+        }
+        a %= ADLER_MOD;
+        b %= ADLER_MOD;
+    }
+    (b << 16) | a
+}
+
+/// Checksum of two pieces joined, from the checksum of each and the length of the second
+fn adler32_combine(first: u32, second: u32, second_len: usize) -> u32 {
+    let m = ADLER_MOD as u64;
+    let len = second_len as u64 % m;
+    let (a1, b1) = ((first & 0xffff) as u64, (first >> 16) as u64);
+    let (a2, b2) = ((second & 0xffff) as u64, (second >> 16) as u64);
+
+    let a = (a1 + a2 + m - 1) % m;
+    let b = (b1 + b2 + len * a1 + m - len) % m;
+    ((b << 16) | a) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::ZlibDecoder;
+
+    use super::*;
+
+    /// Some runs and some noise, so there's something to match without it all matching
+    fn sample(len: usize) -> Vec<u8> {
+        let mut state = 0x2545f491u32;
+        (0..len)
+            .map(|i| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                if (i / 1000) % 3 == 0 { (i % 251) as u8 } else { state as u8 & 0x3f }
+            })
+            .collect()
+    }
+
+    fn inflate(stream: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        ZlibDecoder::new(stream).read_to_end(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn slices_join_into_one_stream() {
+        let threads = 4;
+        let data = sample(MIN_SLICE * threads + MIN_SLICE / 2 + 7);
+        for level in [0, 1, 6, 9] {
+            let stream = zlib(&data, level, threads).unwrap();
+            assert_eq!(inflate(&stream), data, "level {level}");
+            // the trailer has to be the checksum of the whole input
+            assert_eq!(stream[stream.len() - 4..], adler32(&data).to_be_bytes());
+        }
+    }
+
+    #[test]
+    fn combined_checksum_matches_whole() {
+        let data = sample(20_000);
+        for split in [0, 1, 5552, 13_337, 20_000] {
+            let (first, second) = data.split_at(split);
+            assert_eq!(adler32_combine(adler32(first), adler32(second), second.len()), adler32(&data), "split at {split}");
+        }
+    }
+
+    #[test]
+    fn small_and_empty_inputs() {
+        for len in [0, 1, 100, MIN_SLICE - 1] {
+            let data = sample(len);
+            assert_eq!(inflate(&zlib(&data, 6, 8).unwrap()), data, "{len} bytes");
+        }
+    }
+}
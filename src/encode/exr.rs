@@ -0,0 +1,131 @@
+//! OpenEXR of the capture itself, half floats as they came off the screen.
+//!
+//! A single part scanline file. Channels are stored in name order, so A, B,
+//! G, R, each a run of the whole line. ZIP compresses 16 lines at a time
+//! after splitting the bytes into low and high halves and storing each as
+//! the difference from the one before, which is what the format asks for.
+
+use std::error::Error;
+
+use serde::{Deserialize, Serialize};
+
+use super::deflate::zlib;
+use crate::pixels::ScrgbBuffer;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExrSettings {
+    pub compression: ExrCompression,
+}
+
+impl Default for ExrSettings {
+    fn default() -> Self {
+        Self { compression: ExrCompression::Zip }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExrCompression {
+    None,
+    /// Lossless, every EXR reader has it
+    Zip,
+}
+
+impl ExrCompression {
+    /// The number the header stores and how many lines go in a chunk
+    fn code_and_lines(self) -> (u8, usize) {
+        match self {
+            ExrCompression::None => (0, 1),
+            ExrCompression::Zip => (3, 16),
+        }
+    }
+}
+
+/// HALF in a channel list
+const HALF: i32 = 1;
+
+pub fn encode(image: &ScrgbBuffer, settings: &ExrSettings) -> Result<Vec<u8>, Box<dyn Error>> {
+    if image.width == 0 || image.height == 0 {
+        return Err("can't encode an empty image".into());
+    }
+    let (compression, lines) = settings.compression.code_and_lines();
+    let (width, height) = (image.width as usize, image.height as usize);
+
+    let mut out = Vec::new();
+    out.extend([0x76, 0x2f, 0x31, 0x01]);
+    // version 2, single part scanlines
+    out.extend([2, 0, 0, 0]);
+
+    let mut channels = Vec::new();
+    for name in [b'A', b'B', b'G', b'R'] {
+        channels.extend([name, 0]);
+        channels.extend(HALF.to_le_bytes());
+        // linear flag and padding, then x and y sampling
+        channels.extend([0; 4]);
+        channels.extend(1i32.to_le_bytes());
+        channels.extend(1i32.to_le_bytes());
+    }
+    channels.push(0);
+    attribute(&mut out, "channels", "chlist", &channels);
+    attribute(&mut out, "compression", "compression", &[compression]);
+    let window: Vec<u8> = [0, 0, image.width as i32 - 1, image.height as i32 - 1].iter().flat_map(|v| v.to_le_bytes()).collect();
+    attribute(&mut out, "dataWindow", "box2i", &window);
+    attribute(&mut out, "displayWindow", "box2i", &window);
+    attribute(&mut out, "lineOrder", "lineOrder", &[0]);
+    attribute(&mut out, "pixelAspectRatio", "float", &1.0f32.to_le_bytes());
+    attribute(&mut out, "screenWindowCenter", "v2f", &[0; 8]);
+    attribute(&mut out, "screenWindowWidth", "float", &1.0f32.to_le_bytes());
+    // scRGB has the sRGB primaries and D65 white, 1.0 is 80 nits
+    let chromaticities: Vec<u8> = [0.64f32, 0.33, 0.3, 0.6, 0.15, 0.06, 0.3127, 0.329].iter().flat_map(|v| v.to_le_bytes()).collect();
+    attribute(&mut out, "chromaticities", "chromaticities", &chromaticities);
+    attribute(&mut out, "whiteLuminance", "float", &80.0f32.to_le_bytes());
+    out.push(0);
+
+    // the offset table is filled in as the chunks go after it
+    let table = out.len();
+    let chunks = height.div_ceil(lines);
+    out.resize(table + chunks * 8, 0);
+
+    let mut raw = Vec::with_capacity(width * lines * 8);
+    for (i, rows) in image.data.chunks(width * lines).enumerate() {
+        raw.clear();
+        for row in rows.chunks_exact(width) {
+            for channel in [3, 2, 1, 0] {
+                raw.extend(row.iter().flat_map(|px| px[channel].to_le_bytes()));
+            }
+        }
+        let data = match settings.compression {
+            ExrCompression::None => raw.clone(),
+            ExrCompression::Zip => {
+                let packed = zlib(&predict(&raw), 6, 1)?;
+                // a chunk that didn't get smaller is stored as is, readers go by the size
+                if packed.len() < raw.len() { packed } else { raw.clone() }
+            }
+        };
+        let offset = out.len() as u64;
+        out[table + i * 8..table + i * 8 + 8].copy_from_slice(&offset.to_le_bytes());
+        out.extend(((i * lines) as i32).to_le_bytes());
+        out.extend((data.len() as i32).to_le_bytes());
+        out.extend(data);
+    }
+    Ok(out)
+}
+
+fn attribute(out: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    out.extend(name.as_bytes());
+    out.push(0);
+    out.extend(kind.as_bytes());
+    out.push(0);
+    out.extend((value.len() as i32).to_le_bytes());
+    out.extend(value);
+}
+
+/// Even bytes then odd bytes, each stored as the difference from the one before
+fn predict(raw: &[u8]) -> Vec<u8> {
+    let mut split: Vec<u8> = raw.iter().step_by(2).chain(raw.iter().skip(1).step_by(2)).copied().collect();
+    for i in (1..split.len()).rev() {
+        split[i] = split[i].wrapping_sub(split[i - 1]).wrapping_add(128);
+    }
+    split
+}
@@ -0,0 +1,229 @@
+//! Minimal ICC v4 display profiles for tagging exported images.
+//!
+//! Only what a matrix/TRC RGB profile needs: description, copyright,
+//! white point, chromatic adaptation, colorants and tone curves.
+
+/// CIE xy chromaticities of a colour space
+pub struct Primaries {
+    pub red: (f64, f64),
+    pub green: (f64, f64),
+    pub blue: (f64, f64),
+    pub white: (f64, f64),
+}
+
+impl Primaries {
+    pub const SRGB: Primaries = Primaries {
+        red: (0.640, 0.330),
+        green: (0.300, 0.600),
+        blue: (0.150, 0.060),
+        white: (0.3127, 0.3290),
+    };
+
+    pub const DISPLAY_P3: Primaries = Primaries {
+        red: (0.680, 0.320),
+        green: (0.265, 0.690),
+        blue: (0.150, 0.060),
+        white: (0.3127, 0.3290),
+    };
+
+    pub const REC2020: Primaries = Primaries {
+        red: (0.708, 0.292),
+        green: (0.170, 0.797),
+        blue: (0.131, 0.046),
+        white: (0.3127, 0.3290),
+    };
+}
+
+/// Parametric curve type 3 from the spec, `[g, a, b, c, d]`:
+/// `(a*x + b)^g` above `d`, `c*x` below it
+pub type Curve = [f64; 5];
+
+pub const SRGB_CURVE: Curve = [2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045];
+
+/// Straight through, for data that's still linear light
+pub const LINEAR_CURVE: Curve = [1.0, 1.0, 0.0, 1.0, 0.0];
+
+/// The PCS white, every profile's colorants are adapted to this
+const D50: [f64; 3] = [0.9642, 1.0, 0.8249];
+
+/// Linear rgb in `from` to linear rgb in `to`, adapting the white if they differ
+pub fn conversion(from: &Primaries, to: &Primaries) -> Matrix {
+    let adapt = bradford(xy_to_xyz(from.white), xy_to_xyz(to.white));
+    mul(&invert(&rgb_to_xyz(to)), &mul(&adapt, &rgb_to_xyz(from)))
+}
+
+pub fn rgb_profile(description: &str, primaries: &Primaries, curve: Curve) -> Vec<u8> {
+    let white = xy_to_xyz(primaries.white);
+    let adapt = bradford(white, D50);
+    let colorants = mul(&adapt, &rgb_to_xyz(primaries));
+
+    let column = |i: usize| xyz_tag([colorants[0][i], colorants[1][i], colorants[2][i]]);
+    let trc = para_tag(curve);
+    let tags: [(&[u8; 4], Vec<u8>); 10] = [
+        (b"desc", mluc_tag(description)),
+        (b"cprt", mluc_tag("No copyright, use freely")),
+        (b"wtpt", xyz_tag(D50)),
+        (b"chad", sf32_tag(&adapt)),
+        (b"rXYZ", column(0)),
+        (b"gXYZ", column(1)),
+        (b"bXYZ", column(2)),
+        (b"rTRC", trc.clone()),
+        (b"gTRC", trc.clone()),
+        (b"bTRC", trc),
+    ];
+
+    let table_size = 4 + 12 * tags.len();
+    let mut table = Vec::with_capacity(table_size);
+    table.extend((tags.len() as u32).to_be_bytes());
+    let mut body = Vec::new();
+    let mut written: Vec<(&[u8], u32)> = Vec::new();
+    for (signature, data) in &tags {
+        // identical tags can point at the same data, the three curves usually are
+        let offset = match written.iter().find(|(d, _)| *d == data.as_slice()) {
+            Some((_, offset)) => *offset,
+            None => {
+                let offset = (128 + table_size + body.len()) as u32;
+                body.extend(data);
+                // tags start on 4 byte boundaries
+                body.resize(body.len().next_multiple_of(4), 0);
+                written.push((data, offset));
+                offset
+            },
+        };
+        table.extend(*signature);
+        table.extend(offset.to_be_bytes());
+        table.extend((data.len() as u32).to_be_bytes());
+    }
+
+    let size = 128 + table.len() + body.len();
+    let mut profile = Vec::with_capacity(size);
+    profile.extend((size as u32).to_be_bytes());
+    profile.extend([0; 4]); // preferred cmm
+    profile.extend([4, 0x30, 0, 0]); // version 4.3
+    profile.extend(b"mntrRGB XYZ ");
+    profile.extend(date_time());
+    profile.extend(b"acsp");
+    profile.extend([0; 24]); // platform, flags, manufacturer, model, attributes
+    profile.extend(0u32.to_be_bytes()); // perceptual intent
+    profile.extend(D50.iter().flat_map(|v| s15_fixed16(*v)));
+    profile.extend([0; 4]); // creator
+    profile.extend([0; 16]); // profile id, all zero means not computed
+    profile.extend([0; 28]);
+    profile.extend(table);
+    profile.extend(body);
+    profile
+}
+
+/// Fixed rather than the current time so the same settings give the same bytes
+fn date_time() -> [u8; 12] {
+    let mut out = [0; 12];
+    for (i, v) in [2024u16, 1, 1, 0, 0, 0].iter().enumerate() {
+        out[i * 2..i * 2 + 2].copy_from_slice(&v.to_be_bytes());
+    }
+    out
+}
+
+fn s15_fixed16(v: f64) -> [u8; 4] {
+    ((v * 65536.0).round() as i32).to_be_bytes()
+}
+
+fn mluc_tag(text: &str) -> Vec<u8> {
+    let utf16: Vec<u8> = text.encode_utf16().flat_map(|c| c.to_be_bytes()).collect();
+    let mut tag = Vec::new();
+    tag.extend(b"mluc");
+    tag.extend([0; 4]);
+    tag.extend(1u32.to_be_bytes()); // one record
+    tag.extend(12u32.to_be_bytes()); // record size
+    tag.extend(b"enUS");
+    tag.extend((utf16.len() as u32).to_be_bytes());
+    tag.extend(28u32.to_be_bytes()); // string offset from the tag start
+    tag.extend(utf16);
+    tag
+}
+
+fn xyz_tag(xyz: [f64; 3]) -> Vec<u8> {
+    let mut tag = Vec::new();
+    tag.extend(b"XYZ ");
+    tag.extend([0; 4]);
+    tag.extend(xyz.iter().flat_map(|v| s15_fixed16(*v)));
+    tag
+}
+
+fn sf32_tag(matrix: &Matrix) -> Vec<u8> {
+    let mut tag = Vec::new();
+    tag.extend(b"sf32");
+    tag.extend([0; 4]);
+    tag.extend(matrix.iter().flatten().flat_map(|v| s15_fixed16(*v)));
+    tag
+}
+
+fn para_tag(curve: Curve) -> Vec<u8> {
+    let mut tag = Vec::new();
+    tag.extend(b"para");
+    tag.extend([0; 4]);
+    tag.extend(3u16.to_be_bytes());
+    tag.extend([0; 2]);
+    tag.extend(curve.iter().flat_map(|v| s15_fixed16(*v)));
+    tag
+}
+
+pub type Matrix = [[f64; 3]; 3];
+
+fn xy_to_xyz((x, y): (f64, f64)) -> [f64; 3] {
+    [x / y, 1.0, (1.0 - x - y) / y]
+}
+
+/// Columns are the XYZ of each primary, scaled so rgb 1,1,1 lands on the white point
+fn rgb_to_xyz(primaries: &Primaries) -> Matrix {
+    let [r, g, b] = [primaries.red, primaries.green, primaries.blue].map(xy_to_xyz);
+    let unscaled = [[r[0], g[0], b[0]], [r[1], g[1], b[1]], [r[2], g[2], b[2]]];
+    let scale = apply(&invert(&unscaled), xy_to_xyz(primaries.white));
+    unscaled.map(|row| [row[0] * scale[0], row[1] * scale[1], row[2] * scale[2]])
+}
+
+/// Chromatic adaptation from one white to another in the Bradford cone space
+fn bradford(from: [f64; 3], to: [f64; 3]) -> Matrix {
+    const CONE: Matrix = [
+        [0.8951, 0.2664, -0.1614],
+        [-0.7502, 1.7135, 0.0367],
+        [0.0389, -0.0685, 1.0296],
+    ];
+    let (src, dst) = (apply(&CONE, from), apply(&CONE, to));
+    let scale = [
+        [dst[0] / src[0], 0.0, 0.0],
+        [0.0, dst[1] / src[1], 0.0],
+        [0.0, 0.0, dst[2] / src[2]],
+    ];
+    mul(&invert(&CONE), &mul(&scale, &CONE))
+}
+
+fn apply(m: &Matrix, v: [f64; 3]) -> [f64; 3] {
+    m.map(|row| row[0] * v[0] + row[1] * v[1] + row[2] * v[2])
+}
+
+fn mul(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut out = [[0.0; 3]; 3];
+    for (i, row) in out.iter_mut().enumerate() {
+        for (j, v) in row.iter_mut().enumerate() {
+            *v = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    out
+}
+
+fn invert(m: &Matrix) -> Matrix {
+    let cofactor = |r: usize, c: usize| {
+        let (r0, r1) = ((r + 1) % 3, (r + 2) % 3);
+        let (c0, c1) = ((c + 1) % 3, (c + 2) % 3);
+        m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+    };
+    let det: f64 = (0..3).map(|c| m[0][c] * cofactor(0, c)).sum();
+    let mut out = [[0.0; 3]; 3];
+    for (r, row) in out.iter_mut().enumerate() {
+        for (c, v) in row.iter_mut().enumerate() {
+            // transpose of the cofactors
+            *v = cofactor(c, r) / det;
+        }
+    }
+    out
+}
@@ -0,0 +1,80 @@
+use std::error::Error;
+
+use jpeg_encoder::{ColorType, Encoder, SamplingFactor};
+use serde::{Deserialize, Serialize};
+
+use super::colour::Encoding;
+use crate::pixels::PixelBuffer;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JpegSettings {
+    /// 1 to 100
+    pub quality: u8,
+    pub subsampling: Subsampling,
+    /// Loads blurry first then sharpens, usually a little smaller too
+    pub progressive: bool,
+}
+
+impl Default for JpegSettings {
+    fn default() -> Self {
+        Self {
+            quality: 90,
+            subsampling: Subsampling::Yuv444,
+            progressive: false,
+        }
+    }
+}
+
+/// How much colour resolution is thrown away. 4:2:0 is a lot smaller for photos
+/// but smears coloured text, which is most of what ends up in a screenshot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Subsampling {
+    #[serde(rename = "4:4:4")]
+    Yuv444,
+    #[serde(rename = "4:2:0")]
+    Yuv420,
+}
+
+pub fn encode(image: &PixelBuffer, settings: &JpegSettings) -> Result<Vec<u8>, Box<dyn Error>> {
+    // no alpha channel, anything see through goes onto white like a browser would show it
+    let rgb = image.to_srgb8([1.0; 3]);
+    write(&rgb, ColorType::Rgb, image.width, image.height, settings, &[], Some(&Encoding::SRGB.icc()))
+}
+
+/// Encode packed 8 bit samples. `segments` are (n, data) for extra APPn markers,
+/// they go in order straight after the JFIF header.
+pub fn write(
+    samples: &[u8],
+    color: ColorType,
+    width: u32,
+    height: u32,
+    settings: &JpegSettings,
+    segments: &[(u8, &[u8])],
+    icc: Option<&[u8]>,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    // the format tops out a little below u16::MAX
+    if width > 65500 || height > 65500 {
+        return Err(format!("{width}x{height} is too big for a jpeg").into());
+    }
+    if !(1..=100).contains(&settings.quality) {
+        return Err(format!("jpeg quality has to be 1 to 100, not {}", settings.quality).into());
+    }
+
+    let mut data = Vec::new();
+    let mut encoder = Encoder::new(&mut data, settings.quality);
+    encoder.set_sampling_factor(match settings.subsampling {
+        Subsampling::Yuv444 => SamplingFactor::R_4_4_4,
+        Subsampling::Yuv420 => SamplingFactor::R_4_2_0,
+    });
+    encoder.set_progressive(settings.progressive);
+    for (n, segment) in segments {
+        encoder.add_app_segment(*n, segment)?;
+    }
+    if let Some(icc) = icc {
+        encoder.add_icc_profile(icc)?;
+    }
+
+    encoder.encode(samples, width as u16, height as u16, color)?;
+    Ok(data)
+}
@@ -0,0 +1,115 @@
+//! Turning the processed capture into file bytes.
+
+pub mod avif;
+pub mod bmp;
+pub mod colour;
+pub mod deflate;
+pub mod exr;
+pub mod icc;
+pub mod jpeg;
+pub mod png;
+pub mod radiance;
+pub mod tiff;
+pub mod ultrahdr;
+pub mod webp;
+
+use std::error::Error;
+
+use serde::{Deserialize, Serialize};
+
+use crate::pixels::{PixelBuffer, ScrgbBuffer};
+
+use self::{
+    avif::{AvifMode, AvifSettings},
+    exr::ExrSettings,
+    jpeg::JpegSettings,
+    png::PngSettings,
+    radiance::RadianceSettings,
+    tiff::TiffSettings,
+    ultrahdr::UltraHdrSettings,
+    webp::WebpSettings,
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OutputFormat {
+    /// 16 bit RGBA, or 8 bit when that's enough
+    Png(PngSettings),
+    /// 8 bit sRGB, much smaller for photos and video frames
+    Jpeg(JpegSettings),
+    /// 8 bit sRGB with alpha, lossless or lossy
+    Webp(WebpSettings),
+    /// AV1 in SDR or 10 bit HDR
+    Avif(AvifSettings),
+    /// SDR jpeg with a gain map that brings the HDR back on screens that can show it
+    UltraHdr(UltraHdrSettings),
+    /// 8 or 16 bit integer, or the capture itself as floats
+    Tiff(TiffSettings),
+    /// Radiance RGBE of the capture itself, for lighting tools
+    Radiance(RadianceSettings),
+    /// OpenEXR of the capture itself as half floats, for compositing and grading tools
+    Exr(ExrSettings),
+    /// 8 bit sRGB with alpha, uncompressed. On the clipboard every app can read it.
+    Bmp,
+}
+
+impl Default for OutputFormat {
+    fn default() -> Self {
+        OutputFormat::Png(PngSettings::default())
+    }
+}
+
+impl OutputFormat {
+    /// `hdr` is the untouched capture, only needed when `needs_hdr` says so
+    pub fn encode(&self, image: &PixelBuffer, hdr: Option<&ScrgbBuffer>) -> Result<Vec<u8>, Box<dyn Error>> {
+        match self {
+            OutputFormat::Png(settings) => png::encode(image, settings),
+            OutputFormat::Jpeg(settings) => jpeg::encode(image, settings),
+            OutputFormat::Webp(settings) => webp::encode(image, settings),
+            OutputFormat::Avif(settings) => avif::encode(image, hdr, settings),
+            OutputFormat::UltraHdr(settings) => ultrahdr::encode(image, hdr, settings),
+            OutputFormat::Tiff(settings) => tiff::encode(image, hdr, settings),
+            OutputFormat::Bmp => bmp::encode(image),
+            OutputFormat::Radiance(settings) => radiance::encode(hdr.ok_or("radiance needs the scRGB capture")?, settings),
+            OutputFormat::Exr(settings) => exr::encode(hdr.ok_or("exr needs the scRGB capture")?, settings),
+        }
+    }
+
+    pub fn needs_hdr(&self) -> bool {
+        match self {
+            OutputFormat::Avif(settings) => settings.mode == AvifMode::Hdr,
+            OutputFormat::UltraHdr(_) | OutputFormat::Radiance(_) | OutputFormat::Exr(_) => true,
+            OutputFormat::Tiff(settings) => settings.sample.is_float(),
+            _ => false,
+        }
+    }
+
+    pub fn clipboard_format(&self) -> ClipboardFormat {
+        match self {
+            OutputFormat::Png(_) => ClipboardFormat::Named("png"),
+            OutputFormat::Jpeg(_) | OutputFormat::UltraHdr(_) => ClipboardFormat::Named("JFIF"),
+            OutputFormat::Webp(_) => ClipboardFormat::Named("image/webp"),
+            OutputFormat::Avif(_) => ClipboardFormat::Named("image/avif"),
+            OutputFormat::Tiff(_) => ClipboardFormat::Named("image/tiff"),
+            OutputFormat::Radiance(_) => ClipboardFormat::Named("image/vnd.radiance"),
+            OutputFormat::Exr(_) => ClipboardFormat::Named("image/x-exr"),
+            OutputFormat::Bmp => ClipboardFormat::Standard(bmp::CF_DIBV5),
+        }
+    }
+
+    /// The part of the encoded file that goes on the clipboard
+    pub fn clipboard_data<'a>(&self, encoded: &'a [u8]) -> &'a [u8] {
+        match self {
+            OutputFormat::Bmp => &encoded[bmp::FILE_HEADER_LEN..],
+            _ => encoded,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClipboardFormat {
+    /// Registered by name, only apps that know the name will find it
+    Named(&'static str),
+    /// One of the formats predefined by windows
+    Standard(u32),
+}
@@ -0,0 +1,340 @@
+use std::{collections::{HashMap, HashSet}, error::Error};
+
+use color_quant::NeuQuant;
+use serde::{Deserialize, Serialize};
+
+use super::{colour::{Encoding, Gamut, Transfer}, deflate};
+use crate::pixels::PixelBuffer;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PngSettings {
+    /// Store 8 bit sRGB instead of 16 bit linear when it holds exactly the same
+    /// colours, without alpha when everything is opaque and with a palette when
+    /// there are few enough colours
+    pub reduce: bool,
+    /// Reduce to 8 bit even when that rounds colours, gradients and tone mapped
+    /// HDR lose their in between shades
+    pub lossy: bool,
+    /// Pick the best 256 colours when there are more, this loses detail and
+    /// reduces whatever `lossy` says
+    pub quantise: bool,
+    /// Spread the error from quantising so gradients don't band, costs some size
+    pub dither: bool,
+    /// Deflate level, 0 only stores and 9 is smallest but slowest
+    pub level: u32,
+    pub filter: Filter,
+    /// Threads to compress with, 0 uses every core
+    pub threads: usize,
+    /// Try every filter at a couple of levels and keep the smallest, this is slow
+    pub optimise: bool,
+    /// Primaries to store the colours in, tagged so colour managed apps show them right
+    pub gamut: Gamut,
+}
+
+/// How each row is predicted from the ones before it before compressing.
+/// In the order of the png filter type numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Filter {
+    None,
+    Sub,
+    Up,
+    Average,
+    Paeth,
+    /// Pick per row, whichever leaves the smallest values
+    Adaptive,
+}
+
+impl Default for PngSettings {
+    fn default() -> Self {
+        Self {
+            reduce: true,
+            lossy: false,
+            quantise: false,
+            dither: true,
+            level: 6,
+            filter: Filter::Adaptive,
+            threads: 0,
+            optimise: false,
+            gamut: Gamut::Srgb,
+        }
+    }
+}
+
+pub fn encode(image: &PixelBuffer, settings: &PngSettings) -> Result<Vec<u8>, Box<dyn Error>> {
+    let image = settings.gamut.convert(image);
+    let image = image.as_ref();
+    // decided up front so only one of them is compressed
+    let reduce = settings.lossy || settings.quantise || (settings.reduce && image.fits_srgb8());
+    if !reduce {
+        return encode_16(image, settings);
+    }
+
+    let (reduced, kind) = encode_8(image, settings)?;
+    // measured against the raw 16 bit samples, encoding both to compare would double the work
+    let full = image.width as usize * image.height as usize * 8;
+    let saved = full.saturating_sub(reduced.len());
+    debug!(
+        "Reduced png to {kind}, {} bytes, saved {saved} bytes ({:.1}%) against {full} bytes of 16 bit samples",
+        reduced.len(),
+        saved as f64 * 100.0 / full.max(1) as f64
+    );
+    Ok(reduced)
+}
+
+fn encode_16(image: &PixelBuffer, settings: &PngSettings) -> Result<Vec<u8>, Box<dyn Error>> {
+    let header = |info: &mut png::Info| {
+        info.color_type = png::ColorType::Rgba;
+        info.bit_depth = png::BitDepth::Sixteen;
+    };
+    let colour = Encoding::new(settings.gamut, Transfer::Linear);
+    write(image.width, image.height, &image.to_be_bytes(), 8, header, colour, settings)
+}
+
+/// The smallest 8 bit form allowed, and a description of it for the log
+fn encode_8(image: &PixelBuffer, settings: &PngSettings) -> Result<(Vec<u8>, String), Box<dyn Error>> {
+    let rgba = image.to_srgba8();
+
+    if let Some(palette) = exact_palette(&rgba) {
+        let lookup: HashMap<[u8; 4], u8> = palette.iter().enumerate().map(|(i, c)| (*c, i as u8)).collect();
+        let indices: Vec<u8> = rgba.chunks_exact(4).map(|px| lookup[&[px[0], px[1], px[2], px[3]]]).collect();
+        let kind = format!("exact {} colour palette", palette.len());
+        return Ok((write_indexed(image, &palette, &indices, settings)?, kind));
+    }
+
+    if settings.quantise {
+        let quantiser = NeuQuant::new(10, 256, &rgba);
+        let palette: Vec<[u8; 4]> = quantiser.color_map_rgba()
+            .chunks_exact(4)
+            .map(|c| [c[0], c[1], c[2], c[3]])
+            .collect();
+        let indices = if settings.dither {
+            dither(&rgba, image.width as usize, &quantiser, |_| false)
+        } else {
+            rgba.chunks_exact(4).map(|px| quantiser.index_of(px) as u8).collect()
+        };
+        let kind = format!("quantised palette{}", if settings.dither { ", dithered" } else { "" });
+        return Ok((write_indexed(image, &palette, &indices, settings)?, kind));
+    }
+
+    let opaque = rgba.chunks_exact(4).all(|px| px[3] == u8::MAX);
+    let (samples, colour, bytes_per_pixel, kind) = if opaque {
+        let rgb: Vec<u8> = rgba.chunks_exact(4).flat_map(|px| [px[0], px[1], px[2]]).collect();
+        (rgb, png::ColorType::Rgb, 3, "8 bit RGB")
+    } else {
+        (rgba, png::ColorType::Rgba, 4, "8 bit RGBA")
+    };
+
+    let header = |info: &mut png::Info| {
+        info.color_type = colour;
+        info.bit_depth = png::BitDepth::Eight;
+    };
+    let colour = Encoding::new(settings.gamut, Transfer::Srgb);
+    Ok((write(image.width, image.height, &samples, bytes_per_pixel, header, colour, settings)?, kind.to_string()))
+}
+
+/// Every colour in the image if there are no more than 256. See through
+/// colours go first so the transparency chunk can stop early.
+pub fn exact_palette(rgba: &[u8]) -> Option<Vec<[u8; 4]>> {
+    let mut seen = HashSet::new();
+    for px in rgba.chunks_exact(4) {
+        let colour = [px[0], px[1], px[2], px[3]];
+        if !seen.contains(&colour) {
+            if seen.len() == 256 {
+                return None;
+            }
+            seen.insert(colour);
+        }
+    }
+    let mut palette: Vec<[u8; 4]> = seen.into_iter().collect();
+    palette.sort_by_key(|c| (c[3] == u8::MAX, *c));
+    Some(palette)
+}
+
+fn write_indexed(image: &PixelBuffer, palette: &[[u8; 4]], indices: &[u8], settings: &PngSettings) -> Result<Vec<u8>, Box<dyn Error>> {
+    // fewest bits that still fit every index, rows are padded to whole bytes
+    let depth = match palette.len() {
+        0..=2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        _ => 8,
+    };
+    let per_byte = 8 / depth;
+    let mut packed = Vec::with_capacity(indices.len() / per_byte + image.height as usize);
+    for row in indices.chunks(image.width as usize) {
+        for chunk in row.chunks(per_byte) {
+            let byte = chunk.iter()
+                .enumerate()
+                .fold(0u8, |byte, (i, index)| byte | index << (8 - depth * (i + 1)));
+            packed.push(byte);
+        }
+    }
+
+    let plte: Vec<u8> = palette.iter().flat_map(|c| [c[0], c[1], c[2]]).collect();
+    let trns: Vec<u8> = palette.iter().map(|c| c[3]).take_while(|a| *a != u8::MAX).collect();
+
+    let header = |info: &mut png::Info| {
+        info.color_type = png::ColorType::Indexed;
+        info.bit_depth = match depth {
+            1 => png::BitDepth::One,
+            2 => png::BitDepth::Two,
+            4 => png::BitDepth::Four,
+            _ => png::BitDepth::Eight,
+        };
+        info.palette = Some(plte.clone().into());
+        if !trns.is_empty() {
+            info.trns = Some(trns.clone().into());
+        }
+    };
+    // filtering rarely helps indices, optimise can still try it
+    let settings = PngSettings { filter: Filter::None, ..settings.clone() };
+    let colour = Encoding::new(settings.gamut, Transfer::Srgb);
+    write(image.width, image.height, &packed, 1, header, colour, &settings)
+}
+
+/// Filter, compress and wrap up `samples`. `header` sets the colour type, depth
+/// and palette, `colour` says what the samples mean.
+fn write(
+    width: u32,
+    height: u32,
+    samples: &[u8],
+    bytes_per_pixel: usize,
+    header: impl Fn(&mut png::Info),
+    colour: Encoding,
+    settings: &PngSettings,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    if settings.level > 9 {
+        return Err(format!("png compression level has to be 0 to 9, not {}", settings.level).into());
+    }
+    let row_len = if height == 0 { 0 } else { samples.len() / height as usize };
+
+    let attempts: Vec<(Filter, u32)> = if settings.optimise {
+        [Filter::None, Filter::Sub, Filter::Up, Filter::Average, Filter::Paeth, Filter::Adaptive].into_iter()
+            .flat_map(|f| [(f, 6), (f, 9)])
+            .collect()
+    } else {
+        vec![(settings.filter, settings.level)]
+    };
+
+    let mut best: Option<(Vec<u8>, Filter, u32)> = None;
+    for (filter, level) in attempts {
+        let idat = deflate::zlib(&filter_rows(samples, row_len, bytes_per_pixel, filter), level, settings.threads)?;
+        if best.as_ref().is_none_or(|(b, ..)| idat.len() < b.len()) {
+            best = Some((idat, filter, level));
+        }
+    }
+    let (idat, filter, level) = best.unwrap();
+    if settings.optimise {
+        debug!("Optimised png with {filter:?} filtering at level {level}");
+    }
+
+    let mut data = Vec::with_capacity(idat.len() + 1024);
+    {
+        let mut info = png::Info::with_size(width, height);
+        header(&mut info);
+        colour.tag_png(&mut info);
+        // the header ends with PLTE, which cICP has to come before, so palettes make do with the profile
+        let cicp = colour.png_cicp().filter(|_| info.palette.is_none());
+        let mut writer = png::Encoder::with_info(&mut data, info)?.write_header()?;
+        if let Some(cicp) = cicp {
+            writer.write_chunk(png::chunk::cICP, &cicp)?;
+        }
+        // already compressed, so it goes in as a raw chunk. IEND is added when the writer drops.
+        writer.write_chunk(png::chunk::IDAT, &idat)?;
+    }
+    Ok(data)
+}
+
+/// Prefix every row with its filter type and filter it. `bytes_per_pixel` is
+/// the distance to the matching byte in the pixel on the left, at least 1.
+pub fn filter_rows(samples: &[u8], row_len: usize, bytes_per_pixel: usize, filter: Filter) -> Vec<u8> {
+    let mut out = Vec::with_capacity(samples.len() + samples.len() / row_len.max(1));
+    let empty = vec![0; row_len];
+    let mut candidate = vec![0; row_len];
+    let mut previous: &[u8] = &empty;
+    for row in samples.chunks(row_len.max(1)) {
+        let kind = match filter {
+            Filter::Adaptive => {
+                // smallest sum of the bytes taken as signed, the usual heuristic
+                [Filter::None, Filter::Sub, Filter::Up, Filter::Average, Filter::Paeth].into_iter()
+                    .min_by_key(|f| {
+                        filter_row(*f, row, previous, bytes_per_pixel, &mut candidate);
+                        candidate.iter().map(|b| (*b as i8).unsigned_abs() as u64).sum::<u64>()
+                    })
+                    .unwrap()
+            },
+            f => f,
+        };
+        filter_row(kind, row, previous, bytes_per_pixel, &mut candidate);
+        out.push(kind as u8);
+        out.extend_from_slice(&candidate[..row.len()]);
+        previous = row;
+    }
+    out
+}
+
+fn filter_row(filter: Filter, row: &[u8], previous: &[u8], bpp: usize, out: &mut [u8]) {
+    for i in 0..row.len() {
+        let left = if i >= bpp { row[i - bpp] } else { 0 };
+        let up = previous[i];
+        let up_left = if i >= bpp { previous[i - bpp] } else { 0 };
+        let prediction = match filter {
+            Filter::None | Filter::Adaptive => 0,
+            Filter::Sub => left,
+            Filter::Up => up,
+            Filter::Average => ((left as u16 + up as u16) / 2) as u8,
+            Filter::Paeth => paeth(left, up, up_left),
+        };
+        out[i] = row[i].wrapping_sub(prediction);
+    }
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = ((p - a as i16).abs(), (p - b as i16).abs(), (p - c as i16).abs());
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+/// Floyd-Steinberg, done on the 8 bit sRGB values the palette was built from.
+/// Pixels `skip` picks out get index 0 and neither take nor pass on any error.
+pub fn dither(rgba: &[u8], width: usize, quantiser: &NeuQuant, skip: impl Fn(usize) -> bool) -> Vec<u8> {
+    let mut error = vec![[0.0f32; 4]; rgba.len() / 4];
+    let mut indices = Vec::with_capacity(rgba.len() / 4);
+    for (i, px) in rgba.chunks_exact(4).enumerate() {
+        if skip(i) {
+            indices.push(0);
+            continue;
+        }
+        let wanted: [f32; 4] = std::array::from_fn(|c| (px[c] as f32 + error[i][c]).clamp(0.0, 255.0));
+        let target = wanted.map(|c| c.round() as u8);
+        let index = quantiser.index_of(&target);
+        indices.push(index as u8);
+
+        let got = quantiser.lookup(index).unwrap();
+        let (x, last_column) = (i % width, i % width == width - 1);
+        for c in 0..4 {
+            let e = wanted[c] - got[c] as f32;
+            let mut spread = |j: usize, weight: f32| {
+                if let Some(px) = error.get_mut(j) {
+                    px[c] += e * weight;
+                }
+            };
+            if !last_column {
+                spread(i + 1, 7.0 / 16.0);
+                spread(i + width + 1, 1.0 / 16.0);
+            }
+            if x > 0 {
+                spread(i + width - 1, 3.0 / 16.0);
+            }
+            spread(i + width, 5.0 / 16.0);
+        }
+    }
+    indices
+}
@@ -0,0 +1,108 @@
+//! Radiance RGBE (.hdr), straight from the scRGB capture.
+//!
+//! Radiance measures light in watts per steradian per square metre, where
+//! luminance in nits is 179 times the weighted channels. The capture is
+//! written as is and the header's `EXPOSURE` says how to get back to that.
+
+use std::error::Error;
+
+use serde::{Deserialize, Serialize};
+
+use crate::pixels::ScrgbBuffer;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RadianceSettings {
+    /// Stops to brighten or darken by, recorded in the header so tools can undo it
+    pub exposure: f32,
+}
+
+impl Default for RadianceSettings {
+    fn default() -> Self {
+        Self { exposure: 0.0 }
+    }
+}
+
+/// scRGB 1.0 is 80 nits, Radiance 1.0 is 179
+const SCRGB_EXPOSURE: f32 = 179.0 / 80.0;
+
+pub fn encode(image: &ScrgbBuffer, settings: &RadianceSettings) -> Result<Vec<u8>, Box<dyn Error>> {
+    if image.width == 0 || image.height == 0 {
+        return Err("can't encode an empty image".into());
+    }
+    let scale = settings.exposure.exp2();
+
+    let mut out = Vec::new();
+    out.extend(b"#?RADIANCE\n");
+    out.extend(b"# scRGB capture, 1.0 is 80 nits before exposure\n");
+    out.extend(b"FORMAT=32-bit_rle_rgbe\n");
+    out.extend(format!("EXPOSURE={}\n", SCRGB_EXPOSURE * scale).as_bytes());
+    // scRGB uses the sRGB primaries and D65 white
+    out.extend(b"PRIMARIES=0.640 0.330 0.300 0.600 0.150 0.060 0.3127 0.3290\n");
+    out.extend(format!("\n-Y {} +X {}\n", image.height, image.width).as_bytes());
+
+    let width = image.width as usize;
+    let mut channels = [Vec::with_capacity(width), Vec::with_capacity(width), Vec::with_capacity(width), Vec::with_capacity(width)];
+    for row in image.data.chunks_exact(width) {
+        let rgbe = row.iter().map(|px| to_rgbe([px[0], px[1], px[2]].map(|c| c.to_f32() * scale)));
+
+        // the run length scheme only works for these widths, anything else is written flat
+        if !(8..=0x7fff).contains(&width) {
+            out.extend(rgbe.flatten());
+            continue;
+        }
+        for channel in channels.iter_mut() {
+            channel.clear();
+        }
+        for px in rgbe {
+            for (channel, v) in channels.iter_mut().zip(px) {
+                channel.push(v);
+            }
+        }
+        out.extend([2, 2, (width >> 8) as u8, width as u8]);
+        for channel in &channels {
+            run_length(channel, &mut out);
+        }
+    }
+    Ok(out)
+}
+
+/// Shared exponent from the brightest channel. Negatives can't be stored so they go to 0.
+fn to_rgbe(rgb: [f32; 3]) -> [u8; 4] {
+    let rgb = rgb.map(|c| if c.is_finite() { c.max(0.0) } else { 0.0 });
+    let brightest = rgb[0].max(rgb[1]).max(rgb[2]);
+    if brightest < 1e-32 {
+        return [0; 4];
+    }
+    // brightest = mantissa * 2^exponent with the mantissa in [0.5, 1)
+    let exponent = brightest.log2().floor() as i32 + 1;
+    let scale = 256.0 / (exponent as f32).exp2();
+    let [r, g, b] = rgb.map(|c| (c * scale).min(255.0) as u8);
+    [r, g, b, (exponent + 128).clamp(0, 255) as u8]
+}
+
+/// One channel of a scanline. A count over 128 is a run of one byte,
+/// 128 or under is that many literal bytes.
+fn run_length(data: &[u8], out: &mut Vec<u8>) {
+    // shorter runs cost as much as writing them out
+    const MIN_RUN: usize = 4;
+    let literals = |data: &[u8], out: &mut Vec<u8>| {
+        for chunk in data.chunks(128) {
+            out.push(chunk.len() as u8);
+            out.extend(chunk);
+        }
+    };
+
+    let mut literal_start = 0;
+    let mut i = 0;
+    while i < data.len() {
+        let run = data[i..].iter().take(127).take_while(|v| **v == data[i]).count();
+        if run >= MIN_RUN {
+            literals(&data[literal_start..i], out);
+            out.extend([128 + run as u8, data[i]]);
+            literal_start = i + run;
+        }
+        i += run;
+    }
+    literals(&data[literal_start..], out);
+}
@@ -0,0 +1,248 @@
+//! Baseline little endian TIFF with one RGBA image in strips.
+//!
+//! Integer samples come from the processed export, 8 bit with the sRGB curve
+//! and 16 bit linear. Float samples are the capture as it came off the gpu,
+//! linear with 1.0 at 80 nits. Every file carries an ICC profile for whichever
+//! it is, in whatever gamut was asked for.
+
+use std::error::Error;
+
+use serde::{Deserialize, Serialize};
+use weezl::{encode::Encoder as LzwEncoder, BitOrder};
+
+use super::{colour::{Encoding, Gamut, Transfer}, deflate};
+use crate::pixels::{PixelBuffer, ScrgbBuffer};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TiffSettings {
+    pub sample: Sample,
+    pub compression: TiffCompression,
+    /// Primaries to store the colours in, the float samples keep more of the capture in wider ones
+    pub gamut: Gamut,
+}
+
+impl Default for TiffSettings {
+    fn default() -> Self {
+        Self {
+            sample: Sample::U16,
+            compression: TiffCompression::Lzw,
+            gamut: Gamut::Srgb,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Sample {
+    U8,
+    U16,
+    /// Half floats, exactly what the capture holds
+    F16,
+    F32,
+}
+
+impl Sample {
+    pub fn is_float(self) -> bool {
+        matches!(self, Sample::F16 | Sample::F32)
+    }
+
+    fn bytes(self) -> usize {
+        match self {
+            Sample::U8 => 1,
+            Sample::U16 | Sample::F16 => 2,
+            Sample::F32 => 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TiffCompression {
+    None,
+    Lzw,
+    Deflate,
+}
+
+/// Uncompressed size each strip aims for
+const STRIP_SIZE: usize = 256 * 1024;
+
+pub fn encode(image: &PixelBuffer, hdr: Option<&ScrgbBuffer>, settings: &TiffSettings) -> Result<Vec<u8>, Box<dyn Error>> {
+    let gamut = settings.gamut;
+    let (width, height, samples, transfer): (u32, u32, Vec<u8>, Transfer) = match settings.sample {
+        Sample::U8 => (image.width, image.height, gamut.convert(image).to_srgba8(), Transfer::Srgb),
+        Sample::U16 => (
+            image.width,
+            image.height,
+            gamut.convert(image).data.iter().flat_map(|px| px.iter().flat_map(|c| c.to_le_bytes())).collect(),
+            Transfer::Linear,
+        ),
+        Sample::F16 | Sample::F32 => {
+            let hdr = gamut.convert_scrgb(hdr.ok_or("float tiffs need the scRGB capture")?);
+            let samples = if settings.sample == Sample::F16 {
+                hdr.to_le_bytes()
+            } else {
+                hdr.data.iter().flat_map(|px| px.iter().flat_map(|c| c.to_f32().to_le_bytes())).collect()
+            };
+            (hdr.width, hdr.height, samples, Transfer::Linear)
+        },
+    };
+    let profile = Encoding::new(gamut, transfer).icc();
+    if width == 0 || height == 0 {
+        return Err("can't encode an empty image".into());
+    }
+
+    let sample_bytes = settings.sample.bytes();
+    let row_len = width as usize * 4 * sample_bytes;
+    let rows_per_strip = (STRIP_SIZE / row_len).clamp(1, height as usize);
+    // differencing neighbours helps integers compress, floats would need the byte shuffling predictor
+    let predictor = settings.compression != TiffCompression::None && !settings.sample.is_float();
+
+    let mut strips = Vec::new();
+    for strip in samples.chunks(row_len * rows_per_strip) {
+        let mut strip = strip.to_vec();
+        if predictor {
+            difference(&mut strip, row_len, settings.sample);
+        }
+        strips.push(match settings.compression {
+            TiffCompression::None => strip,
+            TiffCompression::Lzw => lzw(&strip)?,
+            TiffCompression::Deflate => deflate::zlib(&strip, 6, 1)?,
+        });
+    }
+
+    let mut out = Vec::new();
+    out.extend(b"II");
+    out.extend(42u16.to_le_bytes());
+    out.extend(0u32.to_le_bytes()); // ifd offset, filled in below
+
+    // strips first, then the values too big to sit in the directory, then the directory
+    let mut strip_offsets = Vec::with_capacity(strips.len());
+    for strip in &strips {
+        strip_offsets.push(out.len() as u32);
+        out.extend(strip);
+        pad(&mut out);
+    }
+    let strip_counts: Vec<u32> = strips.iter().map(|s| s.len() as u32).collect();
+
+    let bits = (sample_bytes * 8) as u16;
+    let format: u16 = if settings.sample.is_float() { 3 } else { 1 };
+    let compression: u16 = match settings.compression {
+        TiffCompression::None => 1,
+        TiffCompression::Lzw => 5,
+        TiffCompression::Deflate => 8,
+    };
+
+    let mut entries = vec![
+        Entry::long(256, &[width]),
+        Entry::long(257, &[height]),
+        Entry::short(258, &[bits; 4]),
+        Entry::short(259, &[compression]),
+        Entry::short(262, &[2]), // rgb
+        Entry::long(273, &strip_offsets),
+        Entry::short(277, &[4]),
+        Entry::long(278, &[rows_per_strip as u32]),
+        Entry::long(279, &strip_counts),
+        Entry::rational(282, 72, 1),
+        Entry::rational(283, 72, 1),
+        Entry::short(284, &[1]), // chunky
+        Entry::short(296, &[2]), // inches
+        Entry::short(338, &[2]), // unassociated alpha
+        Entry::short(339, &[format; 4]),
+        Entry { tag: 34675, kind: 7, count: profile.len() as u32, data: profile },
+    ];
+    if predictor {
+        entries.push(Entry::short(317, &[2]));
+    }
+    entries.sort_by_key(|e| e.tag);
+
+    let mut values = Vec::new();
+    let values_start = out.len() as u32;
+    let ifd_offset = values_start + entries.iter().map(|e| e.spill_len()).sum::<u32>();
+    let mut ifd = Vec::new();
+    ifd.extend((entries.len() as u16).to_le_bytes());
+    for entry in &entries {
+        ifd.extend(entry.tag.to_le_bytes());
+        ifd.extend(entry.kind.to_le_bytes());
+        ifd.extend(entry.count.to_le_bytes());
+        if entry.data.len() <= 4 {
+            let mut inline = entry.data.clone();
+            inline.resize(4, 0);
+            ifd.extend(inline);
+        } else {
+            ifd.extend((values_start + values.len() as u32).to_le_bytes());
+            values.extend(&entry.data);
+            pad(&mut values);
+        }
+    }
+    ifd.extend(0u32.to_le_bytes()); // no more images
+
+    out.extend(values);
+    out.extend(ifd);
+    out[4..8].copy_from_slice(&ifd_offset.to_le_bytes());
+    Ok(out)
+}
+
+struct Entry {
+    tag: u16,
+    kind: u16,
+    count: u32,
+    data: Vec<u8>,
+}
+
+impl Entry {
+    fn short(tag: u16, values: &[u16]) -> Self {
+        Self { tag, kind: 3, count: values.len() as u32, data: values.iter().flat_map(|v| v.to_le_bytes()).collect() }
+    }
+
+    fn long(tag: u16, values: &[u32]) -> Self {
+        Self { tag, kind: 4, count: values.len() as u32, data: values.iter().flat_map(|v| v.to_le_bytes()).collect() }
+    }
+
+    fn rational(tag: u16, numerator: u32, denominator: u32) -> Self {
+        Self { tag, kind: 5, count: 1, data: [numerator, denominator].iter().flat_map(|v| v.to_le_bytes()).collect() }
+    }
+
+    /// Bytes taken outside the directory, values of 4 bytes or less sit inside it
+    fn spill_len(&self) -> u32 {
+        if self.data.len() <= 4 { 0 } else { self.data.len().next_multiple_of(2) as u32 }
+    }
+}
+
+/// Offsets have to be even
+fn pad(out: &mut Vec<u8>) {
+    if out.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+/// Horizontal differencing, predictor 2. Each sample becomes the difference
+/// from the same channel of the pixel to its left.
+fn difference(strip: &mut [u8], row_len: usize, sample: Sample) {
+    for row in strip.chunks_mut(row_len) {
+        match sample {
+            Sample::U8 => {
+                for i in (4..row.len()).rev() {
+                    row[i] = row[i].wrapping_sub(row[i - 4]);
+                }
+            },
+            _ => {
+                for i in (4..row.len() / 2).rev() {
+                    let current = u16::from_le_bytes([row[i * 2], row[i * 2 + 1]]);
+                    let left = u16::from_le_bytes([row[i * 2 - 8], row[i * 2 - 7]]);
+                    row[i * 2..i * 2 + 2].copy_from_slice(&current.wrapping_sub(left).to_le_bytes());
+                }
+            },
+        }
+    }
+}
+
+fn lzw(data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut out = Vec::with_capacity(data.len() / 2);
+    LzwEncoder::with_tiff_size_switch(BitOrder::Msb, 8)
+        .into_vec(&mut out)
+        .encode_all(data)
+        .status
+        .map_err(|e| format!("lzw failed : {e}"))?;
+    Ok(out)
+}
@@ -0,0 +1,334 @@
+//! Ultra HDR jpegs: an ordinary SDR jpeg with a second, greyscale jpeg appended
+//! that says how much brighter each pixel should be on an HDR screen.
+//!
+//! The two images are tied together with an MPF index and XMP, following
+//! the Ultra HDR v1 layout so both Android and Chrome pick it up.
+
+use std::error::Error;
+
+use jpeg_encoder::ColorType;
+use serde::{Deserialize, Serialize};
+
+use super::{colour::Encoding, jpeg::{self, JpegSettings}};
+use crate::pixels::{self, PixelBuffer, ScrgbBuffer, SCRGB_WHITE};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UltraHdrSettings {
+    /// The SDR image everything can show
+    pub base: JpegSettings,
+    /// 1 to 100, the gain map is smooth so it can go lower than the base
+    pub gain_map_quality: u8,
+    /// Brightness of SDR white on the HDR screen in nits, the gain map is relative to it
+    pub sdr_white: f32,
+}
+
+impl Default for UltraHdrSettings {
+    fn default() -> Self {
+        Self {
+            base: JpegSettings::default(),
+            gain_map_quality: 85,
+            sdr_white: 203.0,
+        }
+    }
+}
+
+/// Added to both sides of the ratio so black pixels don't divide by zero
+const OFFSET: f32 = 1.0 / 64.0;
+
+const XMP_NAMESPACE: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+
+pub fn encode(image: &PixelBuffer, hdr: Option<&ScrgbBuffer>, settings: &UltraHdrSettings) -> Result<Vec<u8>, Box<dyn Error>> {
+    let hdr = hdr.ok_or("ultra hdr needs the scRGB capture")?;
+    if (hdr.width, hdr.height) != (image.width, image.height) {
+        return Err("ultra hdr can't be used with effects that change the size of the export".into());
+    }
+    if settings.sdr_white <= 0.0 {
+        return Err(format!("sdr white has to be above 0, not {}", settings.sdr_white).into());
+    }
+
+    // compare against the 8 bit base as it will be decoded, not the 16 bit original
+    let sdr = image.to_srgb8([1.0; 3]);
+    let gains: Vec<f32> = sdr.chunks_exact(3)
+        .zip(&hdr.data)
+        .map(|(sdr, hdr)| {
+            let sdr = luminance(sdr.iter().map(|c| pixels::srgb_to_linear(*c as f32 / 255.0)));
+            let hdr = luminance(hdr[..3].iter().map(|c| c.to_f32().max(0.0) * SCRGB_WHITE / settings.sdr_white));
+            ((hdr + OFFSET) / (sdr + OFFSET)).log2()
+        })
+        .collect();
+    let metadata = GainMapMetadata::covering(&gains);
+
+    let map: Vec<u8> = gains.iter().map(|g| metadata.quantise(*g)).collect();
+    let map_settings = JpegSettings { quality: settings.gain_map_quality, ..settings.base.clone() };
+    let gain_map = jpeg::write(
+        &map,
+        ColorType::Luma,
+        image.width,
+        image.height,
+        &map_settings,
+        &[(1, &[XMP_NAMESPACE, metadata.xmp().as_bytes()].concat())],
+        None,
+    )?;
+
+    let container = [XMP_NAMESPACE, container_xmp(gain_map.len()).as_bytes()].concat();
+    let mut primary = jpeg::write(
+        &sdr,
+        ColorType::Rgb,
+        image.width,
+        image.height,
+        &settings.base,
+        &[(1, &container), (2, &mpf(0, 0, 0))],
+        Some(&Encoding::SRGB.icc()),
+    )?;
+
+    // the index needs the primary's own size so it's patched in once that's known
+    let header = primary.windows(4).position(|w| w == b"MPF\0").ok_or("mpf segment went missing")? + 4;
+    let index = mpf(primary.len() as u32, gain_map.len() as u32, (primary.len() - header) as u32);
+    primary[header - 4..header - 4 + index.len()].copy_from_slice(&index);
+
+    primary.extend(gain_map);
+    Ok(primary)
+}
+
+fn luminance(rgb: impl Iterator<Item = f32>) -> f32 {
+    rgb.zip([0.2126, 0.7152, 0.0722]).map(|(c, w)| c * w).sum()
+}
+
+/// Range of the gain map in log2 stops, values in the map are spread evenly across it
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct GainMapMetadata {
+    min: f32,
+    max: f32,
+}
+
+impl GainMapMetadata {
+    fn covering(gains: &[f32]) -> Self {
+        let min = gains.iter().copied().fold(f32::INFINITY, f32::min).min(0.0);
+        let max = gains.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        // a flat map still needs a range to divide by
+        Self { min, max: max.max(min + 1.0 / 256.0) }
+    }
+
+    fn quantise(self, gain: f32) -> u8 {
+        ((gain - self.min) / (self.max - self.min) * 255.0).round().clamp(0.0, 255.0) as u8
+    }
+
+    fn xmp(self) -> String {
+        format!(
+            concat!(
+                r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">"#,
+                r#"<rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">"#,
+                r#"<rdf:Description rdf:about="" xmlns:hdrgm="http://ns.adobe.com/hdr-gain-map/1.0/""#,
+                r#" hdrgm:Version="1.0" hdrgm:GainMapMin="{min}" hdrgm:GainMapMax="{max}" hdrgm:Gamma="1""#,
+                r#" hdrgm:OffsetSDR="{offset}" hdrgm:OffsetHDR="{offset}""#,
+                r#" hdrgm:HDRCapacityMin="0" hdrgm:HDRCapacityMax="{capacity}" hdrgm:BaseRenditionIsHDR="False"/>"#,
+                r#"</rdf:RDF></x:xmpmeta>"#,
+            ),
+            min = self.min,
+            max = self.max,
+            offset = OFFSET,
+            capacity = self.max.max(1.0 / 256.0),
+        )
+    }
+}
+
+/// Points readers at the gain map, which starts right after the primary ends
+fn container_xmp(gain_map_length: usize) -> String {
+    format!(
+        concat!(
+            r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">"#,
+            r#"<rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">"#,
+            r#"<rdf:Description rdf:about="" xmlns:hdrgm="http://ns.adobe.com/hdr-gain-map/1.0/""#,
+            r#" xmlns:Container="http://ns.google.com/photos/1.0/container/""#,
+            r#" xmlns:Item="http://ns.google.com/photos/1.0/container/item/" hdrgm:Version="1.0">"#,
+            r#"<Container:Directory><rdf:Seq>"#,
+            r#"<rdf:li rdf:parseType="Resource"><Container:Item Item:Semantic="Primary" Item:Mime="image/jpeg"/></rdf:li>"#,
+            r#"<rdf:li rdf:parseType="Resource"><Container:Item Item:Semantic="GainMap" Item:Mime="image/jpeg" Item:Length="{}"/></rdf:li>"#,
+            r#"</rdf:Seq></Container:Directory>"#,
+            r#"</rdf:Description></rdf:RDF></x:xmpmeta>"#,
+        ),
+        gain_map_length,
+    )
+}
+
+/// CIPA DC-007 multi picture index for two images. `gain_map_offset` is from the
+/// start of the TIFF header inside this segment, which is where MPF offsets count from.
+fn mpf(primary_size: u32, gain_map_size: u32, gain_map_offset: u32) -> Vec<u8> {
+    const ENTRIES: u16 = 3;
+    // header, entry count, entries, next ifd
+    let entries_offset = 8 + 2 + 12 * ENTRIES as u32 + 4;
+
+    let mut out = Vec::new();
+    out.extend(b"MPF\0");
+    out.extend(b"MM\0\x2a");
+    out.extend(8u32.to_be_bytes());
+    out.extend(ENTRIES.to_be_bytes());
+    // version, undefined x4
+    out.extend([0xb0, 0x00, 0, 7]);
+    out.extend(4u32.to_be_bytes());
+    out.extend(b"0100");
+    // number of images, long
+    out.extend([0xb0, 0x01, 0, 4]);
+    out.extend(1u32.to_be_bytes());
+    out.extend(2u32.to_be_bytes());
+    // mp entries, undefined x32
+    out.extend([0xb0, 0x02, 0, 7]);
+    out.extend(32u32.to_be_bytes());
+    out.extend(entries_offset.to_be_bytes());
+    out.extend(0u32.to_be_bytes());
+
+    // primary: baseline jpeg, representative image
+    out.extend(0x2003_0000u32.to_be_bytes());
+    out.extend(primary_size.to_be_bytes());
+    out.extend(0u32.to_be_bytes());
+    out.extend([0; 4]);
+    // gain map: no flags
+    out.extend(0u32.to_be_bytes());
+    out.extend(gain_map_size.to_be_bytes());
+    out.extend(gain_map_offset.to_be_bytes());
+    out.extend([0; 4]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use half::f16;
+
+    use super::*;
+    use crate::{
+        config::ExportSettings,
+        effects::watermark::{watermark, Position, Watermark, WatermarkKind},
+        template::TemplateContext,
+    };
+
+    /// Just enough of a reader to get the HDR rendition back out
+    fn decode(file: &[u8]) -> (Vec<[f32; 3]>, u32, u32) {
+        let mut primary = jpeg_decoder::Decoder::new(file);
+        let sdr = primary.decode().unwrap();
+        let info = primary.info().unwrap();
+
+        // second entry in the mpf index
+        let header = file.windows(4).position(|w| w == b"MPF\0").unwrap() + 4;
+        let entry = header + 8 + 2 + 12 * 3 + 4 + 16;
+        let offset = u32::from_be_bytes(file[entry + 8..entry + 12].try_into().unwrap()) as usize;
+        let gain_map_file = &file[header + offset..];
+        assert_eq!(&gain_map_file[..2], [0xff, 0xd8]);
+
+        let xmp = String::from_utf8_lossy(gain_map_file);
+        let attribute = |name: &str| -> f32 {
+            let start = xmp.find(&format!("hdrgm:{name}=\"")).unwrap() + name.len() + 8;
+            xmp[start..start + xmp[start..].find('"').unwrap()].parse().unwrap()
+        };
+        let (min, max, offset) = (attribute("GainMapMin"), attribute("GainMapMax"), attribute("OffsetSDR"));
+        assert_eq!(attribute("OffsetHDR"), offset);
+
+        let map = jpeg_decoder::Decoder::new(gain_map_file).decode().unwrap();
+        let hdr = sdr.chunks_exact(3)
+            .zip(map)
+            .map(|(px, g)| {
+                let gain = (min + (max - min) * g as f32 / 255.0).exp2();
+                [0, 1, 2].map(|c| (pixels::srgb_to_linear(px[c] as f32 / 255.0) + offset) * gain - offset)
+            })
+            .collect();
+        (hdr, info.width as u32, info.height as u32)
+    }
+
+    fn settings() -> UltraHdrSettings {
+        UltraHdrSettings {
+            base: JpegSettings { quality: 95, ..Default::default() },
+            gain_map_quality: 95,
+            sdr_white: 200.0,
+        }
+    }
+
+    /// A grey ramp from black up to 8x SDR white, brighter towards the bottom, and
+    /// its SDR conversion with what the conversion divided by
+    fn ramp(width: u32, height: u32, settings: &UltraHdrSettings) -> (ScrgbBuffer, PixelBuffer, f32) {
+        let mut hdr = ScrgbBuffer { width, height, data: Vec::new() };
+        for y in 0..height {
+            for x in 0..width {
+                let v = (x as f32 / width as f32) * (1.0 + y as f32 / 8.0) * settings.sdr_white / SCRGB_WHITE;
+                hdr.data.push([f16::from_f32(v), f16::from_f32(v), f16::from_f32(v), f16::ONE]);
+            }
+        }
+
+        // the same thing the conversion shader does
+        let brightest = hdr.data.iter().map(|px| px[0].to_f32()).fold(0.0, f32::max);
+        let sdr = PixelBuffer {
+            width,
+            height,
+            data: hdr.data.iter()
+                .map(|px| {
+                    let v = pixels::to_unorm16(px[0].to_f32() / brightest);
+                    [v, v, v, u16::MAX]
+                })
+                .collect(),
+        };
+        (hdr, sdr, brightest)
+    }
+
+    #[test]
+    fn rebuilds_hdr_within_tolerance() {
+        let (width, height) = (96, 64);
+        let settings = settings();
+        let (hdr, sdr, _) = ramp(width, height, &settings);
+
+        let file = encode(&sdr, Some(&hdr), &settings).unwrap();
+        let (decoded, w, h) = decode(&file);
+        assert_eq!((w, h), (width, height));
+
+        for (expected, got) in hdr.data.iter().zip(decoded) {
+            let expected = expected[0].to_f32() * SCRGB_WHITE / settings.sdr_white;
+            for c in got {
+                // within 5% or a hair above black
+                let tolerance = expected * 0.05 + 0.01;
+                assert!((c - expected).abs() <= tolerance, "expected {expected} got {c}");
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_watermarks_in_hdr() {
+        let (width, height) = (96, 64);
+        let settings = settings();
+        let (hdr, sdr, brightest) = ramp(width, height, &settings);
+
+        // a solid red square stamped in the bright bottom right
+        let stamp_path = std::env::temp_dir().join(format!("ultrahdr-stamp-{}.png", std::process::id()));
+        let stamp = PixelBuffer { width: 16, height: 16, data: vec![[u16::MAX, 0, 0, u16::MAX]; 256] };
+        std::fs::write(&stamp_path, super::super::png::encode(&stamp, &Default::default()).unwrap()).unwrap();
+        let export = ExportSettings {
+            watermarks: vec![Watermark {
+                kind: WatermarkKind::Image { path: stamp_path.clone(), scale: 1.0 },
+                position: Position::BottomRight,
+                margin: 8,
+                opacity: 1.0,
+            }],
+            ..Default::default()
+        };
+        let stamped = watermark(&sdr, &export, &TemplateContext::new(width, height)).unwrap();
+        std::fs::remove_file(&stamp_path).unwrap();
+
+        let hdr = hdr.with_edits(&sdr, &stamped, brightest).unwrap();
+        let (decoded, ..) = decode(&encode(&stamped, Some(&hdr), &settings).unwrap());
+
+        // red as bright as the conversion's white, on an HDR screen too
+        let red = brightest * SCRGB_WHITE / settings.sdr_white;
+        for y in height - 24 + 2..height - 8 - 2 {
+            for x in width - 24 + 2..width - 8 - 2 {
+                let [r, g, b] = decoded[(y * width + x) as usize];
+                assert!((r - red).abs() <= red * 0.1, "red at {x},{y} is {r}, expected {red}");
+                assert!(g < 0.1 * red && b < 0.1 * red, "{x},{y} isn't red: {g} {b}");
+            }
+        }
+    }
+
+    #[test]
+    fn needs_matching_sizes() {
+        let hdr = ScrgbBuffer { width: 4, height: 4, data: vec![[f16::ONE; 4]; 16] };
+        let sdr = PixelBuffer::new(8, 4);
+        assert!(encode(&sdr, Some(&hdr), &UltraHdrSettings::default()).is_err());
+        assert!(encode(&sdr, None, &UltraHdrSettings::default()).is_err());
+    }
+}
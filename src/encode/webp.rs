@@ -0,0 +1,39 @@
+use std::error::Error;
+
+use serde::{Deserialize, Serialize};
+
+use crate::pixels::PixelBuffer;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebpSettings {
+    /// Keeps every pixel exact, which is usually the smallest option for UI anyway
+    pub lossless: bool,
+    /// 0 to 100. Picture quality when lossy, how hard to try when lossless.
+    pub quality: f32,
+}
+
+impl Default for WebpSettings {
+    fn default() -> Self {
+        Self {
+            lossless: true,
+            quality: 75.0,
+        }
+    }
+}
+
+pub fn encode(image: &PixelBuffer, settings: &WebpSettings) -> Result<Vec<u8>, Box<dyn Error>> {
+    // the format stores sizes in 14 bits
+    if image.width > 16383 || image.height > 16383 {
+        return Err(format!("{}x{} is too big for a webp", image.width, image.height).into());
+    }
+    if !(0.0..=100.0).contains(&settings.quality) {
+        return Err(format!("webp quality has to be 0 to 100, not {}", settings.quality).into());
+    }
+
+    let rgba = image.to_srgba8();
+    let data = webp::Encoder::from_rgba(&rgba, image.width, image.height)
+        .encode_simple(settings.lossless, settings.quality)
+        .map_err(|e| format!("webp encoding failed : {e:?}"))?;
+    Ok(data.to_vec())
+}
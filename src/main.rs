@@ -1,15 +1,14 @@
 #![windows_subsystem = "windows"]
 
 
-use std::{error::Error, time::Instant};
-use png::{Encoder, self};
+use std::{error::Error, path::PathBuf, time::{Duration, Instant}};
 use windows::{
     Win32::{
         UI::{
             Input::KeyboardAndMouse::{
                 VK_SNAPSHOT,
                 VIRTUAL_KEY,
-                self, VK_ESCAPE, VK_F11
+                self, VK_ESCAPE, VK_F11, VK_CONTROL, VK_RETURN, VK_LEFT, VK_RIGHT
             },
             WindowsAndMessaging::*
         },
@@ -26,14 +25,14 @@ use windows::{
         },
         System::{
             LibraryLoader::LoadLibraryA,
-            Diagnostics::Debug::OutputDebugStringW,
             Memory,
             DataExchange,
+            Performance,
         },
     },
     core::{
         ComInterface,
-        PCWSTR
+        PCSTR,
     },
     s
 };
@@ -46,18 +45,88 @@ const COMPUTE_PREPROCESS_SHADER_BYTECODE: &[u8] = include_bytes!("../compiled_sh
 
 macro_rules! debug {
     ($($t:tt)*) => {{
+        // tests keep it with the test that wrote it
+        #[cfg(test)]
+        eprintln!($($t)*);
+        #[cfg(not(test))]
         #[allow(unused_unsafe)]
         unsafe {
-            OutputDebugStringW(
-                PCWSTR::from_raw(
+            ::windows::Win32::System::Diagnostics::Debug::OutputDebugStringW(
+                ::windows::core::PCWSTR::from_raw(
                     (&(format!($($t)*) + "\n\0").encode_utf16().collect::<Vec<u16>>()[0] as *const u16))
             );
         }
     }};
 }
 
+mod acquire;
+mod annotations;
+mod config;
+mod draw;
+mod effects;
+mod encode;
+mod pixels;
+mod project;
+mod record;
+mod replay;
+mod schedule;
+mod scroll;
+mod template;
+mod text;
+mod watch;
+
+use acquire::{Cursor, FrameSource, Update};
+use annotations::{Annotation, Annotations};
+use config::{Config, Sink};
+use encode::{ClipboardFormat, OutputFormat};
+use pixels::{PixelBuffer, Point, Rect, ScrgbBuffer};
+use project::Project;
+use record::{archive::Archive, Recorder};
+use replay::{Replay, Timeline};
+use schedule::{ScheduleArea, When};
+use scroll::Stitcher;
+use template::TemplateContext;
+use text::Font;
+use watch::Watcher;
+
 pub const D3D11_CPU_ACCESS_NONE: D3D11_CPU_ACCESS_FLAG = D3D11_CPU_ACCESS_FLAG(0i32);
 
+// hotkey ids, sent back as the WM_HOTKEY wparam
+const CAPTURE_HOTKEY: usize = 0;
+const RECORD_HOTKEY: usize = 1;
+const SCROLL_HOTKEY: usize = 2;
+const SCHEDULE_HOTKEY: usize = 3;
+const WATCH_HOTKEY: usize = 4;
+const REPLAY_HOTKEY: usize = 5;
+
+// WM_TIMER ids
+const RECORD_TIMER: usize = 1;
+const SCROLL_TIMER: usize = 2;
+const SCHEDULE_TIMER: usize = 3;
+const WATCH_TIMER: usize = 4;
+const REPLAY_TIMER: usize = 5;
+
+// frames a recording can fall behind by before the oldest is skipped, each is a copy of the screen
+const RECORD_QUEUE: usize = 4;
+
+/// Write the frame showing `seconds` into the recording at `path` to `out`
+fn export_recording_frame(path: Option<&str>, seconds: &str, out: &str, sdr_white: f32) -> Result<(), Box<dyn Error>> {
+    let path = path.ok_or("--frame needs a recording to take it from")?;
+    let seconds: f64 = seconds.parse().map_err(|e| format!("Bad time {} : {}", seconds, e))?;
+    let mut archive = Archive::open(path)?;
+    let i = archive.index_at(Duration::from_secs_f64(seconds.max(0.0)));
+    archive.export(i, out, sdr_white)?;
+    debug!("Wrote frame {} of {} to {}", i + 1, path, out);
+    Ok(())
+}
+
+/// For running from scripts, which only see stderr and the exit status
+fn exit_with_error(message: &str) -> ! {
+    debug!("{}", message);
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
 fn main() {
     // output panic message to debug stream
     std::panic::set_hook(Box::new(|p| {
@@ -73,13 +142,84 @@ fn main() {
         };
     };
 
-    register_hotey(VK_F11);
+    // screenshotter [--config settings.toml] [capture.sshot | recording.sshotrec [--frame seconds out.png]]
+    let mut config_path = None;
+    let mut project_path = None;
+    let mut frame_export = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => config_path = args.next(),
+            "--frame" => match args.next().zip(args.next()) {
+                Some(frame) => frame_export = Some(frame),
+                None => exit_with_error("--frame needs a time in seconds and a file to write to"),
+            },
+            _ => project_path = Some(arg),
+        }
+    }
+
+    let config = match &config_path {
+        Some(path) => Config::load_from(path),
+        None => Config::load(),
+    }.unwrap_or_else(|e| {
+        debug!("Couldn't load config, using defaults : {:?}", e);
+        Config::default()
+    });
+
+    // write out one frame of a recording without opening anything
+    if let Some((seconds, out)) = frame_export {
+        if let Err(e) = export_recording_frame(project_path.as_deref(), &seconds, &out, config.record.sdr_white) {
+            exit_with_error(&format!("Couldn't export the frame : {}", e));
+        }
+        return;
+    }
+
+    register_hotey(CAPTURE_HOTKEY, KeyboardAndMouse::MOD_NOREPEAT, VK_F11);
+    register_hotey(RECORD_HOTKEY, KeyboardAndMouse::MOD_NOREPEAT | KeyboardAndMouse::MOD_SHIFT, VK_F11);
+    register_hotey(SCROLL_HOTKEY, KeyboardAndMouse::MOD_NOREPEAT | KeyboardAndMouse::MOD_CONTROL, VK_F11);
+    register_hotey(SCHEDULE_HOTKEY, KeyboardAndMouse::MOD_NOREPEAT | KeyboardAndMouse::MOD_ALT, VK_F11);
+    register_hotey(WATCH_HOTKEY, KeyboardAndMouse::MOD_NOREPEAT | KeyboardAndMouse::MOD_CONTROL | KeyboardAndMouse::MOD_SHIFT, VK_F11);
+    register_hotey(REPLAY_HOTKEY, KeyboardAndMouse::MOD_NOREPEAT | KeyboardAndMouse::MOD_CONTROL | KeyboardAndMouse::MOD_ALT, VK_F11);
 
-    let mut state = DXGIState::new().unwrap();
+    let mut state = DXGIState::new(config).unwrap();
 
     debug!("{:?}", state.get_output_desc());
     debug!("Output dimensions are {:?}", state.get_output_desc().DesktopCoordinates.dimensions());
 
+    let archive_path = project_path.take_if(|path| path.ends_with(Archive::EXTENSION));
+    if let Some(path) = archive_path {
+        match state.open_archive(&path) {
+            Ok(()) => {
+                state.show_window();
+                state.paint_frame();
+            },
+            Err(e) => debug!("Couldn't open recording {} : {:?}", path, e),
+        }
+    }
+
+    if let Some(path) = project_path {
+        // settings passed on the command line win over the ones saved with the project
+        match Project::open(&path).and_then(|project| state.open_project(project, config_path.is_none())) {
+            Ok(()) => {
+                state.show_window();
+                state.paint_frame();
+            },
+            Err(e) => debug!("Couldn't open project {} : {:?}", path, e),
+        }
+    }
+
+
+    if state.config.replay.enabled {
+        if let Err(e) = state.start_replay() {
+            debug!("Couldn't start the replay buffer : {:?}", e);
+        }
+    }
+
+    if state.config.schedule.autostart {
+        if let Err(e) = state.start_schedule() {
+            debug!("Couldn't start the schedule : {:?}", e);
+        }
+    }
 
     #[cfg(debug_assertions)]
     debug!("Debug mode");
@@ -91,6 +231,38 @@ fn main() {
             // There is a message available
             match msg.message {
                 WM_HOTKEY => {
+                    if msg.wParam.0 == REPLAY_HOTKEY {
+                        if let Err(e) = state.save_replay() {
+                            debug!("Couldn't save the replay : {:?}", e);
+                        }
+                        continue;
+                    }
+                    if msg.wParam.0 == SCHEDULE_HOTKEY {
+                        if state.schedule.is_some() {
+                            state.stop_schedule();
+                        } else if let Err(e) = state.start_schedule() {
+                            debug!("Couldn't start the schedule : {:?}", e);
+                        }
+                        continue;
+                    }
+                    if msg.wParam.0 == RECORD_HOTKEY && state.recording.is_some() {
+                        state.stop_recording();
+                        continue;
+                    }
+                    if msg.wParam.0 == SCROLL_HOTKEY && state.scrolling.is_some() {
+                        state.stop_scrolling();
+                        continue;
+                    }
+                    if msg.wParam.0 == WATCH_HOTKEY && state.watching.is_some() {
+                        state.stop_watching();
+                        continue;
+                    }
+                    state.select_for = match msg.wParam.0 {
+                        RECORD_HOTKEY => SelectFor::Record,
+                        SCROLL_HOTKEY => SelectFor::Scroll,
+                        WATCH_HOTKEY => SelectFor::Watch,
+                        _ => SelectFor::Export,
+                    };
                     state.capture_screen().unwrap();
                     state.show_window();
                     state.paint_frame();
@@ -106,9 +278,48 @@ fn main() {
                     state.hide_window();
                 }
 
-                WM_TIMER => {}
+                WM_TIMER => {
+                    if msg.wParam.0 == RECORD_TIMER {
+                        if let Err(e) = state.record_frame() {
+                            debug!("Recording failed : {:?}", e);
+                            state.stop_recording();
+                        }
+                    }
+                    if msg.wParam.0 == SCHEDULE_TIMER {
+                        match state.schedule_tick() {
+                            Ok(true) => {},
+                            Ok(false) => state.stop_schedule(),
+                            Err(e) => {
+                                debug!("Scheduled capture failed : {:?}", e);
+                                state.stop_schedule();
+                            }
+                        }
+                    }
+                    if msg.wParam.0 == REPLAY_TIMER {
+                        if let Err(e) = state.replay_sample() {
+                            debug!("Replay buffer failed, stopping it : {:?}", e);
+                            state.stop_replay();
+                        }
+                    }
+                    if msg.wParam.0 == WATCH_TIMER {
+                        if let Err(e) = state.watch_step() {
+                            debug!("Watching failed : {:?}", e);
+                            state.stop_watching();
+                        }
+                    }
+                    if msg.wParam.0 == SCROLL_TIMER {
+                        match state.scroll_step() {
+                            Ok(true) => {},
+                            Ok(false) => state.stop_scrolling(),
+                            Err(e) => {
+                                debug!("Scrolling capture failed : {:?}", e);
+                                state.stop_scrolling();
+                            }
+                        }
+                    }
+                }
 
-                WM_KEYDOWN | WM_KEYUP | WM_LBUTTONDOWN | WM_LBUTTONUP | WM_MOUSEMOVE => {
+                WM_KEYDOWN | WM_KEYUP | WM_LBUTTONDOWN | WM_LBUTTONUP | WM_RBUTTONDOWN | WM_RBUTTONUP | WM_MOUSEMOVE => {
                     state.process_input(msg);
                 }
 
@@ -123,12 +334,12 @@ fn main() {
     
 }
 
-fn register_hotey(key: VIRTUAL_KEY) {
+fn register_hotey(id: usize, modifiers: KeyboardAndMouse::HOT_KEY_MODIFIERS, key: VIRTUAL_KEY) {
     unsafe {
         KeyboardAndMouse::RegisterHotKey(
             None,
-            0,
-            KeyboardAndMouse::MOD_NOREPEAT,
+            id as i32,
+            modifiers,
             key.0 as u32
         ).ok().unwrap()
     };
@@ -198,12 +409,118 @@ struct DXGIState {
     screenshot: Option<ID3D11Texture2D1>,
     has_frame: bool,
     input_state: Option<InputState>,
+    annotations: Annotations,
+    // cpu drawn annotations shown over the capture
+    annotation_overlay: ID3D11Texture2D1,
+    annotation_view: ID3D11ShaderResourceView,
+    // where the right button went down, a step marker goes here when it comes back up
+    step_press: Option<Point>,
+    font: Option<Font>,
+    // area exported last, what enter exports again
+    selection: Option<Foundation::RECT>,
     state_resource: ID3D11Buffer,
     use_dirty_rects: bool,
+    // set by the hotkey that opened the overlay
+    select_for: SelectFor,
+    recording: Option<Recording>,
+    scrolling: Option<Scrolling>,
+    schedule: Option<Schedule>,
+    watching: Option<Watching>,
+    replay: Option<Replaying>,
+    // samples from the replay being stepped through in the overlay, and where
+    replay_view: Option<ReplayView>,
+    // the duplication, kept from startup so captures don't wait for a new one
+    frames: FrameSource,
+    config: Config,
+}
+
+/// What letting go of the mouse does with the selection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SelectFor {
+    Export,
+    Record,
+    Scroll,
+    Watch,
+}
+
+/// A region being recorded, new frames are picked up from the duplication on a timer
+struct Recording {
+    rect: Foundation::RECT,
+    // the region is copied here to be read back
+    staging: ID3D11Texture2D1,
+    recorder: Box<dyn Recorder>,
+    path: PathBuf,
+    // performance counter when it started and its ticks per second, present times are in these
+    start: i64,
+    frequency: i64,
+    frames: u32,
+    // frames whose presents didn't draw in the region aren't read back
+    cursor: Cursor,
+    last_present: i64,
+}
+
+/// A region captured while it scrolls, each new frame adds what came into view
+struct Scrolling {
+    rect: Foundation::RECT,
+    staging: ID3D11Texture2D1,
+    stitcher: Stitcher,
+    // steps in a row that added nothing, scrolling by itself stops after a few
+    idle: u32,
+}
+
+/// Captures being taken by themselves, see `schedule`
+struct Schedule {
+    when: When,
+    rect: Foundation::RECT,
+    // when the next capture is due
+    next: chrono::DateTime<chrono::Local>,
+    // captures taken so far
+    sequence: u64,
+    timelapse: Option<Timelapse>,
+}
+
+/// A region captured whenever enough of it changes
+struct Watching {
+    rect: Foundation::RECT,
+    staging: ID3D11Texture2D1,
+    watcher: Watcher,
+    // how far through the duplication's updates it's looked
+    cursor: Cursor,
+    // captures taken so far
+    sequence: u64,
+}
+
+/// The last stretch of the screen, sampled on a timer
+struct Replaying {
+    replay: Replay,
+    cursor: Cursor,
+    // the whole output, only the areas that changed are copied in and read
+    staging: ID3D11Texture2D1,
+}
+
+/// A replay or recording being stepped through in the overlay with the arrow keys
+struct ReplayView {
+    timeline: Box<dyn Timeline>,
+    // the frame showing, the timeline's length for the capture the overlay opened with
+    index: usize,
+    // what a replay was opened over, a recording has nothing past its last frame
+    live: Option<ID3D11Texture2D1>,
+}
+
+struct Timelapse {
+    recorder: Box<dyn Recorder>,
+    path: PathBuf,
+    fps: f32,
+}
+
+impl Recording {
+    fn since_start(&self, ticks: i64) -> Duration {
+        Duration::from_secs_f64((ticks - self.start).max(0) as f64 / self.frequency as f64)
+    }
 }
 
 impl DXGIState {
-    fn new() -> Result<Self, Box<dyn Error>> {
+    fn new(config: Config) -> Result<Self, Box<dyn Error>> {
 
         unsafe { windows::Win32::UI::HiDpi::SetProcessDpiAwarenessContext(
             windows::Win32::UI::HiDpi::DPI_AWARENESS_CONTEXT_PER_MONITOR_AWARE 
@@ -508,7 +825,7 @@ impl DXGIState {
             let mut buffer: Option<ID3D11Buffer> = None;
             device.CreateBuffer(
                 &D3D11_BUFFER_DESC {
-                    ByteWidth: std::mem::size_of::<NormalisedRect>() as u32,
+                    ByteWidth: std::mem::size_of::<OverlayConstants>() as u32,
                     Usage: D3D11_USAGE_DYNAMIC,
                     BindFlags: D3D11_BIND_CONSTANT_BUFFER,
                     CPUAccessFlags: D3D11_CPU_ACCESS_WRITE,
@@ -530,6 +847,28 @@ impl DXGIState {
             )
         };
 
+        let annotation_overlay = Self::create_texture(
+            &device,
+            &dimensions,
+            D3D11_USAGE_DEFAULT,
+            D3D11_CPU_ACCESS_NONE,
+            D3D11_BIND_SHADER_RESOURCE,
+            DXGI_FORMAT_R16G16B16A16_UNORM,
+            1
+        )?;
+
+        let annotation_view = unsafe {
+            let mut view: Option<ID3D11ShaderResourceView> = None;
+            device.CreateShaderResourceView(&annotation_overlay, None, Some(&mut view as *mut _))?;
+            view.unwrap()
+        };
+
+        let frames = FrameSource::new(&output, &device, &device_context)?;
+
+        let font = Font::load(&config.export.font)
+            .map_err(|e| debug!("Couldn't load font, step markers won't have numbers : {:?}", e))
+            .ok();
+
         Ok(Self {
             factory,
             device,
@@ -546,9 +885,23 @@ impl DXGIState {
             screenshot: None,
             has_frame: false,
             input_state: None,
+            annotations: Annotations::default(),
+            annotation_overlay,
+            annotation_view,
+            step_press: None,
+            font,
+            selection: None,
             state_resource,
             use_dirty_rects: false,
-
+            select_for: SelectFor::Export,
+            recording: None,
+            scrolling: None,
+            schedule: None,
+            watching: None,
+            replay: None,
+            replay_view: None,
+            frames,
+            config,
         })
     }
 
@@ -592,14 +945,52 @@ impl DXGIState {
                 let mut final_rect = state.dimensions().to_rect();
                 self.input_state = None;
                 self.use_dirty_rects = false;
-                self.hide_window();
 
                 final_rect.bottom+=1;
                 final_rect.right+=1;
 
-                if let Err(e) = self.process_final_rect(final_rect) {
-                    debug!("processing final rect (screenshot) error : {:?}", e);
-                };
+                match std::mem::replace(&mut self.select_for, SelectFor::Export) {
+                    SelectFor::Export => {},
+                    SelectFor::Record => {
+                        self.annotations.clear();
+                        self.hide_window();
+                        if let Err(e) = self.start_recording(final_rect) {
+                            debug!("Couldn't start recording : {:?}", e);
+                        }
+                        return;
+                    },
+                    SelectFor::Scroll => {
+                        self.annotations.clear();
+                        self.hide_window();
+                        if let Err(e) = self.start_scrolling(final_rect) {
+                            debug!("Couldn't start scrolling capture : {:?}", e);
+                        }
+                        return;
+                    },
+                    SelectFor::Watch => {
+                        self.annotations.clear();
+                        self.hide_window();
+                        if let Err(e) = self.start_watching(final_rect) {
+                            debug!("Couldn't start watching : {:?}", e);
+                        }
+                        return;
+                    },
+                }
+
+                if self.config.export.spotlight.is_some() {
+                    self.annotations.add(Annotation::Highlight { rect: final_rect.into() });
+
+                    // holding ctrl keeps the overlay up to pick another region
+                    if unsafe {KeyboardAndMouse::GetKeyState(VK_CONTROL.0 as i32)} < 0 {
+                        self.has_frame = true;
+                        return;
+                    }
+                    let screen = self.get_output_desc().DesktopCoordinates.dimensions();
+                    final_rect = Dimensions {x: 0, y: 0, ..screen}.to_rect();
+                }
+
+                self.hide_window();
+                self.export(final_rect, None);
             }
 
             (WM_KEYUP, Some(_)) => {
@@ -612,84 +1003,75 @@ impl DXGIState {
             }
 
             (WM_KEYUP, None) => {
+                // step back through the replay for a still of something that's gone
+                if msg.wParam.0 == VK_LEFT.0 as usize || msg.wParam.0 == VK_RIGHT.0 as usize {
+                    let step = if msg.wParam.0 == VK_LEFT.0 as usize { -1 } else { 1 };
+                    if let Err(e) = self.step_replay(step) {
+                        debug!("Couldn't show the replay : {:?}", e);
+                    }
+                    self.has_frame = true;
+                }
+
                 if msg.wParam.0 == VK_ESCAPE.0 as usize{
                     self.input_state = None;
+                    self.annotations.clear();
                     self.use_dirty_rects = false;
+                    self.select_for = SelectFor::Export;
                     self.hide_window();
                 } 
+
+                // export the previous selection again, for reopened projects
+                if msg.wParam.0 == VK_RETURN.0 as usize {
+                    if let Some(rect) = self.selection {
+                        self.use_dirty_rects = false;
+                        self.hide_window();
+                        self.export(rect, None);
+                    }
+                }
             }
 
             (WM_MOUSEMOVE, Some(state)) => {
                 state.corner2 = Some((msg.pt.x, msg.pt.y));
                 self.has_frame = true;
             }
-            _ => {}
-        }
-    }
-
-    fn capture_screen(&mut self) -> Result<(), Box<dyn Error>> {
-        let ctx: IDXGIOutputDuplication = unsafe {
-            self.output.DuplicateOutput1(
-            &self.device,
-                0,
-                &[
-                    DXGI_FORMAT_R16G16B16A16_FLOAT,
-                ]
-            )?
-        };
-
-        let mut resource: Option<IDXGIResource> = None;
-
 
-        let mut timeouts: u32 = 0;
-        let frame_info: DXGI_OUTDUPL_FRAME_INFO = unsafe {
-            let mut frame_info: DXGI_OUTDUPL_FRAME_INFO = DXGI_OUTDUPL_FRAME_INFO::default();
-            while frame_info.LastPresentTime == 0 {
-                ctx.ReleaseFrame().ok();
-                match ctx.AcquireNextFrame(
-                    1,
-                    &mut frame_info as *mut _,
-                    &mut resource as *mut _,
-                ) {
-                    Ok(()) => {},
-                    Err(e) => {
-
-                        if e.code() == DXGI_ERROR_WAIT_TIMEOUT {
-                            timeouts += 1;
-
-                        if e.code().0 as i32 == DXGI_ERROR_ACCESS_LOST.0 {
-                            return self.capture_screen();
-                        };
-                        } else {
-                            debug!("Failed to Acquire next frame: {:?}", e);
-                        }
-
-                    },
-                };
+            // right click places a numbered step, or removes the one under the cursor.
+            // dragging before letting go points an arrow at where the button came up
+            (WM_RBUTTONDOWN, None) => {
+                let point = Point {x: msg.pt.x, y: msg.pt.y};
+                if self.annotations.remove_step_at(point, self.config.export.steps.radius) {
+                    self.refresh_annotation_overlay();
+                } else {
+                    self.step_press = Some(point);
+                }
             }
-            frame_info
-        };
-
-        debug!("caputed frame : {:?}", frame_info);
-
-        let resource = resource.ok_or("Resource was nullptr")?.cast::<ID3D11Texture2D1>()?;
 
-        if timeouts > 0 {
-            debug!("captured frame after {} timeouts", timeouts);
+            (WM_RBUTTONUP, None) => {
+                if let Some(centre) = self.step_press.take() {
+                    let tip = Point {x: msg.pt.x, y: msg.pt.y};
+                    let dragged = ((tip.x - centre.x) as f32).hypot((tip.y - centre.y) as f32);
+                    self.annotations.add(Annotation::Step {
+                        centre,
+                        arrow: (dragged > self.config.export.steps.radius).then_some(tip),
+                    });
+                    self.refresh_annotation_overlay();
+                }
+            }
+            _ => {}
         }
+    }
 
-        let screencap: ID3D11Texture2D1 = Self::create_texture(
-            &self.device,
-            &self.get_output_desc().DesktopCoordinates.dimensions(),
-            D3D11_USAGE_DEFAULT,
-            D3D11_CPU_ACCESS_NONE,
-            D3D11_BIND_SHADER_RESOURCE,
-            DXGI_FORMAT_R16G16B16A16_FLOAT,
-            1
-        )?;
+    fn capture_screen(&mut self) -> Result<(), Box<dyn Error>> {
+        let frame = self.frames.current()?;
+        debug!("captured frame presented at {} after {} presents, pointer at {:?}", frame.present_time, frame.accumulated, frame.pointer);
 
-        unsafe {self.device_context.CopyResource(&screencap, &resource)}
+        self.annotations.clear();
+        self.selection = None;
+        self.replay_view = None;
+        self.set_screenshot(frame.texture)
+    }
 
+    fn set_screenshot(&mut self, screencap: ID3D11Texture2D1) -> Result<(), Box<dyn Error>> {
         // set the pipeline view
 
         let render_source_view = unsafe {
@@ -703,15 +1085,35 @@ impl DXGIState {
                 0,
                 Some(&[
                     Some(render_source_view),
-                    None
+                    Some(self.annotation_view.clone())
                 ])
             );
         };
 
         self.screenshot = Some(screencap);
+        self.refresh_annotation_overlay();
         Ok(())
     }
 
+    /// Redraw the annotations into the texture the overlay blends on top
+    fn refresh_annotation_overlay(&mut self) {
+        let screen = self.get_output_desc().DesktopCoordinates.dimensions();
+        let mut layer = PixelBuffer::new(screen.width, screen.height);
+        self.annotations.draw(&mut layer, Point {x: 0, y: 0}, &self.config.export.steps, self.font.as_ref());
+
+        unsafe {
+            self.device_context.UpdateSubresource(
+                &self.annotation_overlay,
+                0,
+                None,
+                layer.data.as_ptr() as *const _,
+                screen.width * 8,
+                0
+            );
+        }
+        self.has_frame = true;
+    }
+
     fn paint_frame(&mut self) {
         if self.screenshot.is_none() {
             return
@@ -730,17 +1132,24 @@ impl DXGIState {
              ) {
                 Ok(()) => {
                     let screen = self.get_output_desc().DesktopCoordinates.dimensions();
-                    let r = match &self.input_state {
-                        Some(state) => {
-                            NormalisedRect::new(state.dimensions().to_rect(), screen.width, screen.height)
-                        },
-                        None => {
-                            NormalisedRect::default()
-                        },
+                    let mut constants = OverlayConstants {
+                        dim_strength: self.config.export.spotlight.as_ref().map_or(0.35, |s| s.dim),
+                        ..Default::default()
                     };
+                    let current = self.input_state.as_ref().map(|state| state.dimensions().to_rect());
+                    let mut regions: Vec<Foundation::RECT> = self.annotations.highlights()
+                        .map(Foundation::RECT::from)
+                        .chain(current)
+                        .collect();
+                    // newest first so those are the ones kept if there are too many
+                    regions.reverse();
+                    for (slot, rect) in constants.regions.iter_mut().zip(regions) {
+                        *slot = NormalisedRect::new(rect, screen.width, screen.height);
+                        constants.region_count += 1;
+                    }
                     std::ptr::write(
-                        map.pData as *mut NormalisedRect,
-                        r
+                        map.pData as *mut OverlayConstants,
+                        constants
                     );
                     self.device_context.Unmap(&self.state_resource, 0);
                 },
@@ -786,7 +1195,665 @@ impl DXGIState {
         
     }
 
-    fn process_final_rect(&self, rect: Foundation::RECT) -> Result<(), Box<dyn Error>> {
+    /// `sequence` numbers the captures taken on a schedule
+    fn export(&mut self, rect: Foundation::RECT, sequence: Option<u64>) {
+        self.selection = Some(rect);
+        let written = match self.process_final_rect(rect, sequence) {
+            Ok(written) => written,
+            Err(e) => {
+                debug!("processing final rect (screenshot) error : {:?}", e);
+                self.annotations.clear();
+                return;
+            }
+        };
+
+        if self.config.save_project {
+            // the project goes next to the first file so the two are easy to find together
+            match written.first() {
+                Some(output) => {
+                    let before_save = Instant::now();
+                    let path = output.with_extension(Project::EXTENSION);
+                    match self.read_screenshot().and_then(|frame| Project {
+                        frame,
+                        selection: Some(rect.into()),
+                        annotations: self.annotations.clone(),
+                        export: self.config.export.clone(),
+                    }.save(&path)) {
+                        Ok(()) => debug!("Saved project to {} in {:?}", path.display(), Instant::now() - before_save),
+                        Err(e) => debug!("Couldn't save project : {:?}", e),
+                    }
+                },
+                None => debug!("No file written to save the project next to"),
+            }
+        }
+        self.annotations.clear();
+    }
+
+    /// Record `rect` until the record hotkey is pressed again
+    fn start_recording(&mut self, rect: Foundation::RECT) -> Result<(), Box<dyn Error>> {
+        let dimensions = rect.dimensions();
+        if !dimensions.has_area() {
+            return Err("Nothing selected to record".into());
+        }
+        if self.scrolling.is_some() {
+            return Err("Can't record during a scrolling capture".into());
+        }
+        let settings = &self.config.record;
+        let path = PathBuf::from(TemplateContext::new(dimensions.width, dimensions.height).expand(&settings.path)?);
+        let mut recorder = settings.format.recorder(path.clone(), dimensions.width, dimensions.height, settings.sdr_white)?;
+        // what was on screen when the region was picked, so there's a frame even if nothing changes
+        recorder.push(&self.read_screenshot()?.crop(rect.into())?, Duration::ZERO)?;
+        let staging = self.create_region_staging(&dimensions)?;
+
+        let mut start = 0;
+        unsafe {Performance::QueryPerformanceCounter(&mut start as *mut _)};
+        // the timer is how often the duplication is checked, which caps the frame rate
+        let interval = (1000.0 / settings.max_fps.max(1.0)) as u32;
+        debug!("Recording {:?} to {} every {}ms", rect, path.display(), interval);
+
+        self.recording = Some(Recording {
+            rect,
+            staging,
+            recorder,
+            path,
+            start,
+            frequency: self.frames.frequency,
+            frames: 1,
+            cursor: self.frames.cursor(),
+            last_present: start,
+        });
+        self.frames.start_queue(RECORD_QUEUE);
+        unsafe {SetTimer(self.window, RECORD_TIMER, interval, None)};
+        Ok(())
+    }
+
+    /// Pass every frame presented since the last call to the recorder
+    fn record_frame(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(recording) = &mut self.recording else {
+            return Ok(());
+        };
+
+        self.frames.poll(0)?;
+        let updates = self.frames.updates_since(&mut recording.cursor);
+        let rect = Rect::from(recording.rect);
+        while let Some(frame) = self.frames.pop() {
+            let changed = updates.iter()
+                .filter(|u| u.present_time > recording.last_present && u.present_time <= frame.present_time)
+                .flat_map(Update::areas)
+                .any(|area| area.intersects(&rect));
+            recording.last_present = frame.present_time;
+            if !changed {
+                continue;
+            }
+            let region = Self::read_region(&self.device_context, &frame.texture, recording.rect, &recording.staging)?;
+            let time = recording.since_start(frame.present_time);
+            recording.recorder.push(&region, time)?;
+            recording.frames += 1;
+        }
+        Ok(())
+    }
+
+    /// Write out the recording, if there is one
+    fn stop_recording(&mut self) {
+        let Some(recording) = self.recording.take() else {
+            return;
+        };
+        unsafe {KillTimer(self.window, RECORD_TIMER)};
+        self.frames.stop_queue();
+        if self.frames.dropped > 0 {
+            debug!("{} frames came in faster than they were recorded and were skipped", self.frames.dropped);
+        }
+
+        let mut now = 0;
+        unsafe {Performance::QueryPerformanceCounter(&mut now as *mut _)};
+        let length = recording.since_start(now);
+        let Recording { recorder, path, frames, .. } = recording;
+
+        let before_finish = Instant::now();
+        match recorder.finish(length) {
+            Ok(()) => debug!("Recorded {} frames over {:?} to {} in {:?}", frames, length, path.display(), Instant::now() - before_finish),
+            Err(e) => debug!("Couldn't write recording to {} : {:?}", path.display(), e),
+        }
+    }
+
+    /// Take a capture on the configured schedule until the schedule hotkey is pressed again
+    fn start_schedule(&mut self) -> Result<(), Box<dyn Error>> {
+        let settings = &self.config.schedule;
+        let when = When::from_settings(settings)?;
+        let screen = self.get_output_desc().DesktopCoordinates.dimensions();
+        let screen = Dimensions {x: 0, y: 0, ..screen}.to_rect();
+        let rect = match settings.area {
+            ScheduleArea::Screen => screen,
+            ScheduleArea::Selection => self.selection.unwrap_or(screen),
+            ScheduleArea::Region { left, top, width, height } => Dimensions {width, height, x: left, y: top}.to_rect(),
+        };
+        // a selection can come from a scroll capture or a project made on a bigger screen
+        if rect.left < 0 || rect.top < 0 || rect.right > screen.right || rect.bottom > screen.bottom || !rect.dimensions().has_area() {
+            return Err(format!("Scheduled area {:?} isn't on the screen", rect).into());
+        }
+
+        let dimensions = rect.dimensions();
+        let timelapse = match &settings.timelapse {
+            Some(timelapse) => {
+                if timelapse.fps.is_nan() || timelapse.fps <= 0.0 {
+                    return Err(format!("timelapse fps has to be above 0, not {}", timelapse.fps).into());
+                }
+                let path = PathBuf::from(TemplateContext::new(dimensions.width, dimensions.height).expand(&timelapse.path)?);
+                let recorder = timelapse.format.recorder(path.clone(), dimensions.width, dimensions.height, self.config.record.sdr_white)?;
+                Some(Timelapse { recorder, path, fps: timelapse.fps })
+            },
+            None => None,
+        };
+        debug!("Capturing {:?} on a schedule", rect);
+
+        let next = when.first(chrono::Local::now())?;
+        debug!("First scheduled capture at {}", next);
+        self.schedule = Some(Schedule { when, rect, next, sequence: 0, timelapse });
+        self.arm_schedule();
+        Ok(())
+    }
+
+    /// Set the timer for the next capture. Timers can't wait much over 24 days,
+    /// so a tick can come early and just sets it again.
+    fn arm_schedule(&self) {
+        let Some(schedule) = &self.schedule else {
+            return;
+        };
+        let wait = (schedule.next - chrono::Local::now()).to_std().unwrap_or_default();
+        let ms = wait.as_millis().clamp(USER_TIMER_MINIMUM as u128, USER_TIMER_MAXIMUM as u128) as u32;
+        unsafe {SetTimer(self.window, SCHEDULE_TIMER, ms, None)};
+    }
+
+    /// Take the capture if it's due, false once there are no more to take
+    fn schedule_tick(&mut self) -> Result<bool, Box<dyn Error>> {
+        let Some(schedule) = &mut self.schedule else {
+            return Ok(false);
+        };
+        let now = chrono::Local::now();
+        if now + chrono::Duration::milliseconds(USER_TIMER_MINIMUM as i64) < schedule.next {
+            self.arm_schedule();
+            return Ok(true);
+        }
+        let due = schedule.next;
+        schedule.next = schedule.when.next(due, now)?;
+
+        // the capture would pull the screen out from under someone making a selection
+        if unsafe {IsWindowVisible(self.window)}.as_bool() {
+            debug!("Skipped the capture due at {} while the overlay is up", due);
+            self.arm_schedule();
+            return Ok(true);
+        }
+
+        self.capture_screen()?;
+        let Some(schedule) = &mut self.schedule else {
+            return Ok(false);
+        };
+        schedule.sequence += 1;
+        let (rect, sequence) = (schedule.rect, schedule.sequence);
+        if schedule.timelapse.is_some() {
+            let frame = self.read_screenshot()?.crop(rect.into())?;
+            if let Some(Schedule { timelapse: Some(timelapse), .. }) = &mut self.schedule {
+                let time = Duration::from_secs_f64((sequence - 1) as f64 / timelapse.fps as f64);
+                timelapse.recorder.push(&frame, time)?;
+            }
+        }
+        self.export(rect, Some(sequence));
+
+        let count = self.config.schedule.count;
+        if count > 0 && sequence >= count {
+            return Ok(false);
+        }
+        self.arm_schedule();
+        Ok(true)
+    }
+
+    /// Stop taking captures and write the timelapse, if there is one
+    fn stop_schedule(&mut self) {
+        let Some(schedule) = self.schedule.take() else {
+            return;
+        };
+        unsafe {KillTimer(self.window, SCHEDULE_TIMER)};
+        debug!("Stopped the schedule after {} captures", schedule.sequence);
+
+        let Some(Timelapse { recorder, path, fps }) = schedule.timelapse else {
+            return;
+        };
+        let before_finish = Instant::now();
+        match recorder.finish(Duration::from_secs_f64(schedule.sequence as f64 / fps as f64)) {
+            Ok(()) => debug!("Wrote timelapse to {} in {:?}", path.display(), Instant::now() - before_finish),
+            Err(e) => debug!("Couldn't write timelapse to {} : {:?}", path.display(), e),
+        }
+    }
+
+    /// Stitch `rect` together as it scrolls, until the scroll hotkey is pressed again
+    fn start_scrolling(&mut self, rect: Foundation::RECT) -> Result<(), Box<dyn Error>> {
+        let dimensions = rect.dimensions();
+        if !dimensions.has_area() {
+            return Err("Nothing selected to capture".into());
+        }
+        if self.recording.is_some() {
+            return Err("Can't capture scrolling while recording".into());
+        }
+        let settings = &self.config.scroll;
+        let stitcher = Stitcher::new(&self.read_screenshot()?.crop(rect.into())?, settings.max_height);
+        debug!("Capturing {:?} while it scrolls, every {}ms", rect, settings.interval);
+
+        self.scrolling = Some(Scrolling {
+            rect,
+            staging: self.create_region_staging(&dimensions)?,
+            stitcher,
+            idle: 0,
+        });
+        self.frames.start_queue(RECORD_QUEUE);
+        unsafe {SetTimer(self.window, SCROLL_TIMER, settings.interval.max(1), None)};
+        if settings.auto_scroll > 0 {
+            self.scroll_wheel(rect)?;
+        }
+        Ok(())
+    }
+
+    /// Add the frames presented since the last step, false once there's nothing left to scroll
+    fn scroll_step(&mut self) -> Result<bool, Box<dyn Error>> {
+        let Some(scrolling) = &mut self.scrolling else {
+            return Ok(false);
+        };
+
+        self.frames.poll(0)?;
+        let mut added = 0;
+        while let Some(frame) = self.frames.pop() {
+            let region = Self::read_region(&self.device_context, &frame.texture, scrolling.rect, &scrolling.staging)?;
+            added += scrolling.stitcher.push(&region)?;
+        }
+        if scrolling.stitcher.is_full() {
+            return Ok(false);
+        }
+        if self.config.scroll.auto_scroll == 0 {
+            return Ok(true);
+        }
+
+        // a page can take a moment to draw after scrolling, the end is when a few steps in a row add nothing
+        scrolling.idle = if added > 0 { 0 } else { scrolling.idle + 1 };
+        if scrolling.idle >= 3 {
+            return Ok(false);
+        }
+        let rect = scrolling.rect;
+        self.scroll_wheel(rect)?;
+        Ok(true)
+    }
+
+    /// Turn the wheel down over the middle of `rect`
+    fn scroll_wheel(&self, rect: Foundation::RECT) -> Result<(), Box<dyn Error>> {
+        let notches = self.config.scroll.auto_scroll as i32;
+        let input = KeyboardAndMouse::INPUT {
+            r#type: KeyboardAndMouse::INPUT_MOUSE,
+            Anonymous: KeyboardAndMouse::INPUT_0 {
+                mi: KeyboardAndMouse::MOUSEINPUT {
+                    mouseData: -(WHEEL_DELTA as i32) * notches,
+                    dwFlags: KeyboardAndMouse::MOUSEEVENTF_WHEEL,
+                    ..Default::default()
+                },
+            },
+        };
+        unsafe {
+            SetCursorPos((rect.left + rect.right) / 2, (rect.top + rect.bottom) / 2);
+            if KeyboardAndMouse::SendInput(&[input], std::mem::size_of::<KeyboardAndMouse::INPUT>() as i32) != 1 {
+                return Err(format!("Couldn't scroll : {:?}", GetLastError()).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Export what was stitched, if a scrolling capture is going
+    fn stop_scrolling(&mut self) {
+        let Some(scrolling) = self.scrolling.take() else {
+            return;
+        };
+        unsafe {KillTimer(self.window, SCROLL_TIMER)};
+        self.frames.stop_queue();
+
+        let image = scrolling.stitcher.finish();
+        debug!("Stitched a {}x{} scrolling capture", image.width, image.height);
+        let rect = Dimensions {width: image.width, height: image.height, x: 0, y: 0}.to_rect();
+        match self.load_frame(&image) {
+            Ok(()) => self.export(rect, None),
+            Err(e) => debug!("Couldn't load the stitched image : {:?}", e),
+        }
+    }
+
+    /// Capture `rect` whenever enough of it changes, until the watch hotkey is pressed again
+    fn start_watching(&mut self, rect: Foundation::RECT) -> Result<(), Box<dyn Error>> {
+        let dimensions = rect.dimensions();
+        if !dimensions.has_area() {
+            return Err("Nothing selected to watch".into());
+        }
+        let settings = &self.config.watch;
+        let watcher = Watcher::new(self.read_screenshot()?.crop(rect.into())?, settings);
+        debug!("Watching {:?} every {}ms", rect, settings.interval);
+
+        // changes from before the overlay was closed are already in the baseline
+        self.watching = Some(Watching {
+            rect,
+            staging: self.create_region_staging(&dimensions)?,
+            watcher,
+            cursor: self.frames.cursor(),
+            sequence: 0,
+        });
+        unsafe {SetTimer(self.window, WATCH_TIMER, settings.interval.max(1), None)};
+        Ok(())
+    }
+
+    /// Look for changes in the watched region and capture it if there are enough
+    fn watch_step(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(watching) = &mut self.watching else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        // changes pile up until it's over, or until the overlay is closed
+        if watching.watcher.cooling(now) || unsafe {IsWindowVisible(self.window)}.as_bool() {
+            return Ok(());
+        }
+
+        self.frames.poll(0)?;
+        let rect = Rect::from(watching.rect);
+        let updates = self.frames.updates_since(&mut watching.cursor);
+        if !updates.iter().flat_map(Update::areas).any(|area| area.intersects(&rect)) {
+            return Ok(());
+        }
+        let frame = self.frames.current()?;
+        let region = Self::read_region(&self.device_context, &frame.texture, watching.rect, &watching.staging)?;
+        if !watching.watcher.check(region, now) {
+            return Ok(());
+        }
+
+        watching.sequence += 1;
+        let (rect, sequence) = (watching.rect, watching.sequence);
+        self.set_screenshot(frame.texture)?;
+        self.annotations.clear();
+        self.export(rect, Some(sequence));
+        Ok(())
+    }
+
+    fn stop_watching(&mut self) {
+        let Some(watching) = self.watching.take() else {
+            return;
+        };
+        unsafe {KillTimer(self.window, WATCH_TIMER)};
+        debug!("Stopped watching after {} captures", watching.sequence);
+    }
+
+    /// Keep sampling the screen into the replay buffer
+    fn start_replay(&mut self) -> Result<(), Box<dyn Error>> {
+        let settings = &self.config.replay;
+        let screen = self.get_output_desc().DesktopCoordinates.dimensions();
+        let replay = Replay::new(settings, screen.width, screen.height, self.frames.frequency)?;
+        let interval = (1000.0 / settings.fps) as u32;
+        debug!("Keeping {}s of replay every {}ms", settings.seconds, interval);
+
+        self.replay = Some(Replaying {
+            replay,
+            cursor: self.frames.cursor(),
+            staging: self.create_region_staging(&Dimensions {x: 0, y: 0, ..screen})?,
+        });
+        unsafe {SetTimer(self.window, REPLAY_TIMER, interval.max(1), None)};
+        Ok(())
+    }
+
+    /// Read back what changed since the last sample and hand it to the replay
+    fn replay_sample(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(replaying) = &mut self.replay else {
+            return Ok(());
+        };
+        self.frames.poll(0)?;
+        let updates = self.frames.updates_since(&mut replaying.cursor);
+        let newest = updates.last().map(|u| u.present_time);
+        let (width, height) = (replaying.replay.width(), replaying.replay.height());
+        let key = newest.is_some_and(|time| replaying.replay.needs_keyframe(time));
+        let areas = match key {
+            true => vec![Rect { left: 0, top: 0, right: width as i32, bottom: height as i32 }],
+            false => replay::sample_areas(updates.iter().flat_map(Update::areas), width, height),
+        };
+        // nothing new, the sample before just lasts longer
+        let Some(present_time) = newest.filter(|_| !areas.is_empty()) else {
+            return Ok(());
+        };
+
+        let frame = self.frames.current()?;
+        let mut desc = D3D11_TEXTURE2D_DESC1::default();
+        unsafe {frame.texture.GetDesc1(&mut desc as *mut _)};
+        if (desc.Width, desc.Height) != (width, height) {
+            return Err(format!("output changed size to {}x{}", desc.Width, desc.Height).into());
+        }
+        let pixels = Self::read_areas(&self.device_context, &frame.texture, &areas, &replaying.staging)?;
+        replaying.replay.push(present_time, key, areas.into_iter().zip(pixels).collect());
+        Ok(())
+    }
+
+    fn stop_replay(&mut self) {
+        if self.replay.take().is_some() {
+            unsafe {KillTimer(self.window, REPLAY_TIMER)};
+        }
+    }
+
+    /// Write what the replay holds to a clip
+    fn save_replay(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(replaying) = &self.replay else {
+            return Err("The replay buffer isn't on, see replay.enabled".into());
+        };
+        let clip = replaying.replay.clip();
+        if clip.is_empty() {
+            return Err("Nothing in the replay buffer yet".into());
+        }
+        let settings = &self.config.replay;
+        let path = PathBuf::from(TemplateContext::new(clip.width, clip.height).expand(&settings.path)?);
+        let before_save = Instant::now();
+        let mut recorder = settings.format.recorder(path.clone(), clip.width, clip.height, self.config.record.sdr_white)?;
+        clip.for_each(|frame, time| recorder.push(frame, time))?;
+        // the last sample is shown for as long as one was taken for
+        let end = clip.time(clip.len() - 1) + Duration::from_secs_f32(1.0 / settings.fps);
+        recorder.finish(end)?;
+        debug!("Wrote {:?} of replay to {} in {:?}", end, path.display(), Instant::now() - before_save);
+        Ok(())
+    }
+
+    /// Show the replay sample `step` away from the one showing
+    fn step_replay(&mut self, step: isize) -> Result<(), Box<dyn Error>> {
+        if self.replay_view.is_none() {
+            let Some(replaying) = &self.replay else {
+                return Ok(());
+            };
+            let live = self.screenshot.clone().ok_or("No screenshot to go back from")?;
+            let clip = replaying.replay.clip();
+            self.replay_view = Some(ReplayView { index: clip.len(), timeline: Box::new(clip), live: Some(live) });
+        }
+        let Some(view) = &mut self.replay_view else {
+            return Ok(());
+        };
+        if view.timeline.is_empty() {
+            return Ok(());
+        }
+        let len = view.timeline.len();
+        let last = if view.live.is_some() { len } else { len.saturating_sub(1) };
+        let index = view.index.saturating_add_signed(step).min(last);
+        if index == view.index {
+            return Ok(());
+        }
+        view.index = index;
+        if index == len {
+            if let Some(live) = view.live.clone() {
+                return self.set_screenshot(live);
+            }
+        }
+        let ago = view.timeline.time(len - 1).saturating_sub(view.timeline.time(index));
+        debug!("Showing frame {} of {} at {:?}, {:?} before the last", index + 1, len, view.timeline.time(index), ago);
+        let frame = view.timeline.frame(index)?;
+        self.load_frame(&frame)
+    }
+
+    /// Copy each area of a frame back to the cpu through `staging`, which is the
+    /// whole frame's size. The rows inside each come back as they're laid out.
+    fn read_areas(
+        context: &ID3D11DeviceContext4,
+        texture: &ID3D11Texture2D1,
+        areas: &[Rect],
+        staging: &ID3D11Texture2D1
+    ) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+        unsafe {
+            for area in areas {
+                let area = Foundation::RECT::from(*area);
+                context.CopySubresourceRegion(staging, 0, area.left as u32, area.top as u32, 0, texture, 0, Some(&area.as_flat_box() as *const _));
+            }
+
+            let mut map = D3D11_MAPPED_SUBRESOURCE::default();
+            context.Map(staging, 0, D3D11_MAP_READ, 0, Some(&mut map as *mut _))?;
+            let pitch = map.RowPitch as usize;
+            let bottom = areas.iter().map(|a| a.bottom as usize).max().unwrap_or(0);
+            let px_data = std::slice::from_raw_parts(map.pData as *const u8, pitch * bottom);
+            let pixels = areas.iter()
+                .map(|area| {
+                    let (left, right) = (area.left as usize * 8, area.right as usize * 8);
+                    px_data.chunks(pitch)
+                        .skip(area.top as usize)
+                        .take((area.bottom - area.top) as usize)
+                        .flat_map(|row| &row[left..right])
+                        .copied()
+                        .collect()
+                })
+                .collect();
+            context.Unmap(staging, 0);
+            Ok(pixels)
+        }
+    }
+
+    /// Staging texture the size of a region, for reading frames back
+    fn create_region_staging(&self, dimensions: &Dimensions) -> Result<ID3D11Texture2D1, Box<dyn Error>> {
+        Self::create_texture(
+            &self.device,
+            dimensions,
+            D3D11_USAGE_STAGING,
+            D3D11_CPU_ACCESS_READ,
+            D3D11_BIND_FLAG(0),
+            DXGI_FORMAT_R16G16B16A16_FLOAT,
+            1
+        )
+    }
+
+    /// Copy `rect` of a frame back to the cpu through `staging`, which is its size
+    fn read_region(
+        context: &ID3D11DeviceContext4,
+        texture: &ID3D11Texture2D1,
+        rect: Foundation::RECT,
+        staging: &ID3D11Texture2D1
+    ) -> Result<ScrgbBuffer, Box<dyn Error>> {
+        let dimensions = rect.dimensions();
+        unsafe {
+            context.CopySubresourceRegion(staging, 0, 0, 0, 0, texture, 0, Some(&rect.as_flat_box() as *const _));
+
+            let mut map = D3D11_MAPPED_SUBRESOURCE::default();
+            context.Map(staging, 0, D3D11_MAP_READ, 0, Some(&mut map as *mut _))?;
+            let px_data = std::slice::from_raw_parts(map.pData as *const u8, (map.RowPitch * dimensions.height) as usize);
+            let region = ScrgbBuffer::from_mapped(px_data, map.RowPitch as usize, dimensions.width, dimensions.height);
+            context.Unmap(staging, 0);
+            Ok(region)
+        }
+    }
+
+    /// Copy the whole capture back to the cpu without converting it
+    fn read_screenshot(&self) -> Result<ScrgbBuffer, Box<dyn Error>> {
+        let screenshot = self.screenshot.as_ref().ok_or("No screenshot to read")?;
+        let dimensions = unsafe {
+            let mut desc = D3D11_TEXTURE2D_DESC1::default();
+            screenshot.GetDesc1(&mut desc as *mut _);
+            Dimensions {width: desc.Width, height: desc.Height, x: 0, y: 0}
+        };
+
+        let staging = Self::create_texture(
+            &self.device,
+            &dimensions,
+            D3D11_USAGE_STAGING,
+            D3D11_CPU_ACCESS_READ,
+            D3D11_BIND_FLAG(0),
+            DXGI_FORMAT_R16G16B16A16_FLOAT,
+            1
+        )?;
+
+        unsafe {
+            self.device_context.CopyResource(&staging, screenshot);
+
+            let mut map = D3D11_MAPPED_SUBRESOURCE::default();
+            self.device_context.Map(&staging, 0, D3D11_MAP_READ, 0, Some(&mut map as *mut _))?;
+            let px_data = std::slice::from_raw_parts(map.pData as *const u8, (map.RowPitch * dimensions.height) as usize);
+            let frame = ScrgbBuffer::from_mapped(px_data, map.RowPitch as usize, dimensions.width, dimensions.height);
+            self.device_context.Unmap(&staging, 0);
+            Ok(frame)
+        }
+    }
+
+    /// Show the first frame of a recording archive, the arrow keys step through the rest
+    fn open_archive(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
+        let mut archive = Archive::open(path)?;
+        if archive.is_empty() {
+            return Err("The recording has no frames".into());
+        }
+        debug!("Opened {} with {} frames over {:?}", path, archive.len(), archive.duration());
+        let frame = archive.frame(0)?;
+        self.load_frame(&frame)?;
+        self.annotations.clear();
+        self.selection = None;
+        self.replay_view = Some(ReplayView { timeline: Box::new(archive), index: 0, live: None });
+        self.refresh_annotation_overlay();
+        Ok(())
+    }
+
+    /// Put a saved project back on screen so it can be changed and exported again
+    fn open_project(&mut self, project: Project, use_project_settings: bool) -> Result<(), Box<dyn Error>> {
+        let frame = &project.frame;
+        let screen = self.get_output_desc().DesktopCoordinates.dimensions();
+        if (frame.width, frame.height) != (screen.width, screen.height) {
+            debug!("Project is {}x{} but the screen is {}x{}", frame.width, frame.height, screen.width, screen.height);
+        }
+
+        self.load_frame(frame)?;
+
+        self.selection = project.selection.map(Foundation::RECT::from);
+        self.annotations = project.annotations;
+        if use_project_settings {
+            self.config.export = project.export;
+            self.font = Font::load(&self.config.export.font)
+                .map_err(|e| debug!("Couldn't load project font : {:?}", e))
+                .ok();
+        }
+        self.refresh_annotation_overlay();
+        Ok(())
+    }
+
+    /// Upload `frame` and make it the capture
+    fn load_frame(&mut self, frame: &ScrgbBuffer) -> Result<(), Box<dyn Error>> {
+        let screencap = Self::create_texture(
+            &self.device,
+            &Dimensions {width: frame.width, height: frame.height, x: 0, y: 0},
+            D3D11_USAGE_DEFAULT,
+            D3D11_CPU_ACCESS_NONE,
+            D3D11_BIND_SHADER_RESOURCE,
+            DXGI_FORMAT_R16G16B16A16_FLOAT,
+            1
+        )?;
+
+        let px_data = frame.to_le_bytes();
+        unsafe {
+            self.device_context.UpdateSubresource(
+                &screencap,
+                0,
+                None,
+                px_data.as_ptr() as *const _,
+                frame.width * 8,
+                0
+            );
+        }
+        self.set_screenshot(screencap)
+    }
+
+    /// Convert, process and encode the area, returns the file it was written to
+    fn process_final_rect(&self, rect: Foundation::RECT, sequence: Option<u64>) -> Result<Vec<PathBuf>, Box<dyn Error>> {
 
         let dimensions = rect.dimensions();
         debug!("FINAL RECT IS {:?} - ({}x{})", rect, dimensions.width, dimensions.height);
@@ -965,6 +2032,8 @@ impl DXGIState {
             self.device_context.GenerateMips(&srv.unwrap());
         }
 
+        // what the conversion divides by, undone for edits that go into hdr exports
+        let max_luminosity: f32;
         unsafe {
 
 
@@ -981,6 +2050,7 @@ impl DXGIState {
 
                 let preprocessor_buff = std::slice::from_raw_parts(map.pData as *const f32, map.RowPitch as usize / 4);
                 debug!("preprocessor results : {:?}", preprocessor_buff);
+                max_luminosity = preprocessor_buff[0];
                 self.device_context.Unmap(&buf, 0);
 
             }
@@ -1034,63 +2104,103 @@ impl DXGIState {
             }
         };
 
-        // remove padding because rows are alligned to 16 bytes
+        // rows are alligned to 16 bytes, the buffer drops the padding
+        let mut image = PixelBuffer::from_mapped(px_data, map.RowPitch as usize, dimensions.width, dimensions.height);
+        unsafe {self.device_context.Unmap(&final_staging_texture, 0);};
 
-        
-        let mut heap_data = Vec::from(u64_data);
+        // hdr formats go back to the capture itself, the edits are drawn onto it after
+        let plain = if self.config.sinks.iter().any(|sink| sink.format().needs_hdr()) {
+            Some(image.clone())
+        } else {
+            None
+        };
 
-        heap_data.retain(|px| {
-            *px != 0
-        });
+        if let Some(settings) = &self.config.export.spotlight {
+            let before_spotlight = Instant::now();
+            let regions: Vec<Rect> = self.annotations.highlights()
+                .map(|r| r.offset(-rect.left, -rect.top))
+                .collect();
+            image = effects::spotlight::spotlight(&image, &regions, settings);
+            debug!("Applied spotlight in {:?}", Instant::now() - before_spotlight);
+        }
 
-        let px_data: &[u8] = unsafe {
-            std::slice::from_raw_parts(
-                heap_data.as_ptr() as *const u8,
-                heap_data.len() * 8
-            )
-        };
+        self.annotations.draw(&mut image, Point {x: rect.left, y: rect.top}, &self.config.export.steps, self.font.as_ref());
 
-        let before_encoding = Instant::now();
-
-        let mut data: Vec<u8> = Vec::with_capacity(px_data.len());
-        // write the pixels to the data buffer
-        {
-            let mut encoder = Encoder::new(&mut data, dimensions.width, dimensions.height);
-
-            encoder.set_color(png::ColorType::Rgba);
-            encoder.set_depth(png::BitDepth::Sixteen);
-            encoder.set_source_gamma(png::ScaledFloat::from_scaled(45454));
-            let source_chromaticities = png::SourceChromaticities::new(
-                (0.31270, 0.32900),
-                (0.64000, 0.33000),
-                (0.30000, 0.60000),
-                (0.15000, 0.06000)
-            );
-            encoder.set_source_chromaticities(source_chromaticities);
-            let mut writer = match encoder.write_header() {
-                Ok(w) => w,
-                Err(e) => {
-                    unsafe { self.device_context.Unmap(&final_staging_texture, 0) };
-                    return Err(Box::new(e));
-                }
-            };
+        let mut context = TemplateContext::new(dimensions.width, dimensions.height);
+        context.sequence = sequence;
 
+        if !self.config.export.watermarks.is_empty() || self.config.export.footer.is_some() {
+            let before_watermark = Instant::now();
+            image = effects::watermark::watermark(&image, &self.config.export, &context)?;
+            debug!("Stamped watermarks in {:?}", Instant::now() - before_watermark);
+        }
+
+        // the spotlight, annotations and stamps have to be on every capture, hdr or not
+        let hdr = match &plain {
+            Some(plain) => Some(self.read_screenshot()?.crop(rect.into())?.with_edits(plain, &image, max_luminosity)?),
+            None => None,
+        };
 
-            // our data has rows aligned to 16 bytes
-            let res = writer.write_image_data(&px_data);
-            unsafe {self.device_context.Unmap(&final_staging_texture, 0);};
-            res?;
+        if let Some(settings) = &self.config.export.beautify {
+            let before_beautify = Instant::now();
+            image = effects::beautify::beautify(&image, settings);
+            debug!("Beautified image in {:?}", Instant::now() - before_beautify);
         }
-        debug!("Encoded image in {:?}", Instant::now() - before_encoding);
 
-        std::fs::write("img.png", &data).ok();
+        // sinks sharing a format share the encoded bytes
+        let mut encoded: Vec<(&OutputFormat, Vec<u8>)> = Vec::new();
+        for sink in &self.config.sinks {
+            if !encoded.iter().any(|(format, _)| *format == sink.format()) {
+                let before_encoding = Instant::now();
+                encoded.push((sink.format(), sink.format().encode(&image, hdr.as_ref())?));
+                debug!("Encoded image as {:?} in {:?}", sink.format(), Instant::now() - before_encoding);
+            }
+        }
+        let data_for = |format: &OutputFormat| encoded.iter().find(|(f, _)| *f == format).map(|(_, data)| data.as_slice()).unwrap();
+
+        let mut written = Vec::new();
+        let mut clipboard = Vec::new();
+        for sink in &self.config.sinks {
+            match sink {
+                Sink::File { path, format } => {
+                    let path = match context.sequence {
+                        Some(_) => template::numbered(path),
+                        None => path.into(),
+                    };
+                    let path = PathBuf::from(context.expand(&path)?);
+                    match std::fs::write(&path, data_for(format)) {
+                        Ok(()) => written.push(path),
+                        Err(e) => debug!("Couldn't write {} : {:?}", path.display(), e),
+                    }
+                },
+                Sink::Clipboard { format } => clipboard.push((format.clipboard_format(), format.clipboard_data(data_for(format)))),
+            }
+        }
+        if !clipboard.is_empty() {
+            self.set_clipboard(&clipboard)?;
+            debug!("copied to clipboard");
+        }
+
+        Ok(written)
+    }
 
-        // create global memory
+    /// Replace the clipboard contents with `data` under each format
+    fn set_clipboard(&self, items: &[(ClipboardFormat, &[u8])]) -> Result<(), Box<dyn Error>> {
         unsafe {
-            let handle: Foundation::HGLOBAL = Memory::GlobalAlloc(Memory::GMEM_MOVEABLE, data.len())?;
+            if !DataExchange::OpenClipboard(self.window).as_bool() {
+                return Err("Unable to open the clipboard".into());
+            }
+            DataExchange::EmptyClipboard();
 
-            if DataExchange::OpenClipboard(self.window).as_bool() {
-                DataExchange::EmptyClipboard();
+            for (format, data) in items {
+                // create global memory
+                let handle: Foundation::HGLOBAL = match Memory::GlobalAlloc(Memory::GMEM_MOVEABLE, data.len()) {
+                    Ok(handle) => handle,
+                    Err(e) => {
+                        DataExchange::CloseClipboard();
+                        return Err(e.into());
+                    }
+                };
                 let ptr = Memory::GlobalLock(handle);
 
                 if ptr.is_null() {
@@ -1101,22 +2211,25 @@ impl DXGIState {
                 std::ptr::copy(data.as_ptr(), ptr as *mut u8, data.len());
                 Memory::GlobalUnlock(handle);
 
-
-                let format = {
-                    DataExchange::RegisterClipboardFormatA(s!("png"))
-
+                let format = match format {
+                    ClipboardFormat::Named(name) => {
+                        let name = format!("{name}\0");
+                        DataExchange::RegisterClipboardFormatA(PCSTR::from_raw(name.as_ptr()))
+                    },
+                    ClipboardFormat::Standard(format) => *format,
                 };
 
                 debug!("Clipboard format is {}", format);
 
                 let res = DataExchange::SetClipboardData(format, Foundation::HANDLE(handle.0));
-                DataExchange::CloseClipboard();
                 debug!("set clipboard res: {:?}", res);
-                res?;
+                // the clipboard only owns the memory once it's been accepted
+                if res.is_err() {
+                    Memory::GlobalFree(handle)?;
+                }
             }
+            DataExchange::CloseClipboard();
         }
-        debug!("copied to clipboard");
-
         Ok(())
     }
 
@@ -1211,6 +2324,18 @@ struct Vertex (
     [f32; 2],
 );
 
+impl From<Foundation::RECT> for Rect {
+    fn from(rect: Foundation::RECT) -> Self {
+        Rect { left: rect.left, top: rect.top, right: rect.right, bottom: rect.bottom }
+    }
+}
+
+impl From<Rect> for Foundation::RECT {
+    fn from(rect: Rect) -> Self {
+        Foundation::RECT { left: rect.left, top: rect.top, right: rect.right, bottom: rect.bottom }
+    }
+}
+
 #[repr(C)]
 #[derive(Debug)]
 struct NormalisedRect {
@@ -1239,6 +2364,19 @@ impl Default for NormalisedRect {
     }
 }
 
+// has to match MAX_REGIONS in the pixel shader
+const MAX_OVERLAY_REGIONS: usize = 16;
+
+/// Constant buffer layout for `PS_main`, padded out to a multiple of 16 bytes
+#[repr(C)]
+#[derive(Debug, Default)]
+struct OverlayConstants {
+    regions: [NormalisedRect; MAX_OVERLAY_REGIONS],
+    region_count: u32,
+    dim_strength: f32,
+    _padding: [u32; 2],
+}
+
 struct ComputeResource {
     preprocessor: ID3D11ComputeShader,
     convert_resource: ID3D11ComputeShader, 
@@ -0,0 +1,409 @@
+use std::sync::OnceLock;
+
+use half::f16;
+use serde::{Deserialize, Serialize};
+
+/// An RGBA image on the cpu with 16 bits per channel.
+/// Values are linear light with straight (not premultiplied) alpha,
+/// this is what the conversion shader writes out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PixelBuffer {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<[u16; 4]>,
+}
+
+impl PixelBuffer {
+    /// A fully transparent image
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            data: vec![[0; 4]; (width * height) as usize],
+        }
+    }
+
+    /// Copy out of a mapped R16G16B16A16 texture, dropping the row padding.
+    pub fn from_mapped(px_data: &[u8], row_pitch: usize, width: u32, height: u32) -> Self {
+        let mut data = Vec::with_capacity((width * height) as usize);
+        for row in px_data.chunks(row_pitch).take(height as usize) {
+            for px in row[..width as usize * 8].chunks_exact(8) {
+                data.push([
+                    u16::from_le_bytes([px[0], px[1]]),
+                    u16::from_le_bytes([px[2], px[3]]),
+                    u16::from_le_bytes([px[4], px[5]]),
+                    u16::from_le_bytes([px[6], px[7]]),
+                ]);
+            }
+        }
+        Self { width, height, data }
+    }
+
+    /// Decode a png file, assuming it is sRGB like nearly every png out there
+    pub fn open_png(path: impl AsRef<std::path::Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.as_ref();
+        let file = std::fs::File::open(path)
+            .map_err(|e| format!("couldn't open {} : {e}", path.display()))?;
+        let mut decoder = png::Decoder::new(std::io::BufReader::new(file));
+        decoder.set_transformations(png::Transformations::EXPAND);
+        let mut reader = decoder.read_info()?;
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buf)?;
+
+        let channels = info.color_type.samples();
+        let samples: Vec<f32> = match info.bit_depth {
+            png::BitDepth::Sixteen => buf[..info.buffer_size()].chunks_exact(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]) as f32 / 65535.0)
+                .collect(),
+            _ => buf[..info.buffer_size()].iter().map(|c| *c as f32 / 255.0).collect(),
+        };
+
+        let data = samples.chunks_exact(channels)
+            .map(|px| {
+                let (rgb, a) = match px {
+                    [l] => ([*l; 3], 1.0),
+                    [l, a] => ([*l; 3], *a),
+                    [r, g, b] => ([*r, *g, *b], 1.0),
+                    [r, g, b, a] => ([*r, *g, *b], *a),
+                    _ => unreachable!(),
+                };
+                let [r, g, b] = rgb.map(srgb_to_linear);
+                [r, g, b, a].map(to_unorm16)
+            })
+            .collect();
+
+        Ok(Self { width: info.width, height: info.height, data })
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> [u16; 4] {
+        self.data[(y * self.width + x) as usize]
+    }
+
+    /// Composite a linear straight alpha colour over one pixel, ignoring anything off the image
+    pub fn blend(&mut self, x: i32, y: i32, colour: [f32; 4]) {
+        if x < 0 || y < 0 || x as u32 >= self.width || y as u32 >= self.height || colour[3] <= 0.0 {
+            return;
+        }
+        let px = &mut self.data[(y as u32 * self.width + x as u32) as usize];
+        *px = over(px.map(from_unorm16), colour).map(to_unorm16);
+    }
+
+    /// Tightly packed 8 bit sRGB without alpha for formats that can't store it.
+    /// See through pixels are composited onto `background`, a linear colour.
+    pub fn to_srgb8(&self, background: [f32; 3]) -> Vec<u8> {
+        let lut = srgb8_lut();
+        let mut out = Vec::with_capacity(self.data.len() * 3);
+        for px in &self.data {
+            if px[3] == u16::MAX {
+                out.extend([px[0], px[1], px[2]].map(|c| lut[c as usize]));
+            } else {
+                let [r, g, b, a] = px.map(from_unorm16);
+                let [r, g, b, _] = over([background[0], background[1], background[2], 1.0], [r, g, b, a]);
+                out.extend([r, g, b].map(|c| lut[to_unorm16(c) as usize]));
+            }
+        }
+        out
+    }
+
+    /// Tightly packed 8 bit sRGB with straight alpha
+    pub fn to_srgba8(&self) -> Vec<u8> {
+        let lut = srgb8_lut();
+        self.data.iter()
+            .flat_map(|px| [lut[px[0] as usize], lut[px[1] as usize], lut[px[2] as usize], unorm16_to_8(px[3])])
+            .collect()
+    }
+
+    /// Whether `to_srgba8` loses nothing, so every sample was an 8 bit sRGB
+    /// value to begin with. The capture is half floats and the conversion
+    /// truncates, so samples can be a little off the exact value and still count.
+    pub fn fits_srgb8(&self) -> bool {
+        let lut = srgb8_lut();
+        let back: Vec<u16> = (0..=u8::MAX).map(|c| to_unorm16(srgb_to_linear(c as f32 / 255.0))).collect();
+        // a half float is good to about 1 part in 2048, then a step either way for the truncation
+        let close = |v: u16, exact: u16| v.abs_diff(exact) <= exact / 1024 + 2;
+        self.data.iter().all(|px| {
+            [px[0], px[1], px[2]].iter().all(|c| close(*c, back[lut[*c as usize] as usize]))
+                && close(px[3], unorm16_to_8(px[3]) as u16 * 257)
+        })
+    }
+
+    /// Big endian samples, which is what png wants for 16 bit images
+    pub fn to_be_bytes(&self) -> Vec<u8> {
+        self.data.iter()
+            .flat_map(|px| px.iter().flat_map(|c| c.to_be_bytes()))
+            .collect()
+    }
+}
+
+/// The capture as the duplication api hands it over: scRGB, so linear light with
+/// sRGB primaries and 1.0 at 80 nits. Pixels are kept as the original half floats
+/// so nothing is lost on the way through.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScrgbBuffer {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<[f16; 4]>,
+}
+
+impl ScrgbBuffer {
+    /// Most pixels a file may claim to hold, 2 GiB of halfs. Far past any screen
+    /// or scroll capture but small enough to allocate without thinking twice.
+    pub const MAX_PIXELS: u64 = 1 << 28;
+
+    /// Copy out of a mapped R16G16B16A16_FLOAT texture, dropping the row padding.
+    pub fn from_mapped(px_data: &[u8], row_pitch: usize, width: u32, height: u32) -> Self {
+        let mut data = Vec::with_capacity((width * height) as usize);
+        for row in px_data.chunks(row_pitch).take(height as usize) {
+            for px in row[..width as usize * 8].chunks_exact(8) {
+                data.push([
+                    f16::from_le_bytes([px[0], px[1]]),
+                    f16::from_le_bytes([px[2], px[3]]),
+                    f16::from_le_bytes([px[4], px[5]]),
+                    f16::from_le_bytes([px[6], px[7]]),
+                ]);
+            }
+        }
+        Self { width, height, data }
+    }
+
+    /// Copy of the pixels inside `rect`, which has to be within the image
+    pub fn crop(&self, rect: Rect) -> Result<Self, Box<dyn std::error::Error>> {
+        if rect.left < 0 || rect.top < 0 || rect.right <= rect.left || rect.bottom <= rect.top
+            || rect.right as u32 > self.width || rect.bottom as u32 > self.height {
+            return Err(format!("can't crop {:?} out of a {}x{} image", rect, self.width, self.height).into());
+        }
+        let width = (rect.right - rect.left) as u32;
+        let height = (rect.bottom - rect.top) as u32;
+        let mut data = Vec::with_capacity((width * height) as usize);
+        for y in rect.top..rect.bottom {
+            let start = (y as u32 * self.width + rect.left as u32) as usize;
+            data.extend_from_slice(&self.data[start..start + width as usize]);
+        }
+        Ok(Self { width, height, data })
+    }
+
+    /// Straight to SDR without tone mapping, for recordings where the shader pass is
+    /// too slow. `sdr_white` is the nits SDR white was shown at, brighter is clipped.
+    pub fn to_sdr(&self, sdr_white: f32) -> PixelBuffer {
+        let scale = SCRGB_WHITE / sdr_white;
+        let data = self.data.iter()
+            .map(|px| {
+                let [r, g, b] = [px[0], px[1], px[2]].map(|c| to_unorm16(c.to_f32() * scale));
+                // the desktop is opaque whatever the alpha says
+                [r, g, b, u16::MAX]
+            })
+            .collect();
+        PixelBuffer { width: self.width, height: self.height, data }
+    }
+
+    /// Carry the edits made to an SDR export over to the capture it came from.
+    /// `plain` is the SDR conversion of this capture before anything was drawn,
+    /// `edited` is it afterwards, the same width and maybe taller for a footer.
+    /// Pixels that changed are turned back into scRGB by undoing the conversion,
+    /// which divided by `scale`, everything else keeps the capture's own values.
+    pub fn with_edits(&self, plain: &PixelBuffer, edited: &PixelBuffer, scale: f32) -> Result<Self, Box<dyn std::error::Error>> {
+        if (plain.width, plain.height) != (self.width, self.height) || edited.width != self.width || edited.height < self.height {
+            return Err(format!(
+                "can't carry edits to a {}x{} export over to the {}x{} capture",
+                edited.width, edited.height, self.width, self.height
+            ).into());
+        }
+        let data = edited.data.iter()
+            .enumerate()
+            .map(|(i, px)| match (self.data.get(i), plain.data.get(i)) {
+                (Some(hdr), Some(before)) if before == px => *hdr,
+                _ => {
+                    let [r, g, b, a] = px.map(from_unorm16);
+                    [r * scale, g * scale, b * scale, a].map(f16::from_f32)
+                },
+            })
+            .collect();
+        Ok(Self { width: edited.width, height: edited.height, data })
+    }
+
+    /// Tightly packed little endian halfs, the layout the texture wants uploaded
+    pub fn to_le_bytes(&self) -> Vec<u8> {
+        self.data.iter()
+            .flat_map(|px| px.iter().flat_map(|c| c.to_le_bytes()))
+            .collect()
+    }
+}
+
+/// Nits that scRGB 1.0 stands for
+pub const SCRGB_WHITE: f32 = 80.0;
+
+pub fn to_unorm16(v: f32) -> u16 {
+    (v.clamp(0.0, 1.0) * 65535.0 + 0.5) as u16
+}
+
+pub fn unorm16_to_8(v: u16) -> u8 {
+    ((v as u32 * 255 + 32767) / 65535) as u8
+}
+
+pub fn from_unorm16(v: u16) -> f32 {
+    v as f32 / 65535.0
+}
+
+pub fn srgb_to_linear(v: f32) -> f32 {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub fn linear_to_srgb(v: f32) -> f32 {
+    if v <= 0.0031308 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Every 16 bit linear value mapped to 8 bit sRGB, the curve is too slow to run per pixel
+fn srgb8_lut() -> &'static [u8] {
+    static LUT: OnceLock<Vec<u8>> = OnceLock::new();
+    LUT.get_or_init(|| {
+        (0..=u16::MAX)
+            .map(|v| (linear_to_srgb(from_unorm16(v)) * 255.0 + 0.5) as u8)
+            .collect()
+    })
+}
+
+/// A rectangle in pixels, `right` and `bottom` are exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rect {
+    pub left: i32,
+    pub top: i32,
+    pub right: i32,
+    pub bottom: i32,
+}
+
+impl Rect {
+    /// Whether the two share any pixels
+    pub fn intersects(&self, other: &Rect) -> bool {
+        self.left < other.right && other.left < self.right && self.top < other.bottom && other.top < self.bottom
+    }
+
+    pub fn offset(self, dx: i32, dy: i32) -> Self {
+        Self {
+            left: self.left + dx,
+            top: self.top + dy,
+            right: self.right + dx,
+            bottom: self.bottom + dy,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Point {
+    pub x: i32,
+    pub y: i32,
+}
+
+/// An sRGB colour written as `#rrggbb` or `#rrggbbaa` in the config.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Colour {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+impl Colour {
+    pub const fn rgba(r: u8, g: u8, b: u8, a: u8) -> Self {
+        Self { r, g, b, a }
+    }
+
+    /// Channels as 0-1 floats, still sRGB encoded
+    pub fn to_srgb(self) -> [f32; 4] {
+        [self.r, self.g, self.b, self.a].map(|c| c as f32 / 255.0)
+    }
+
+    /// Channels as 0-1 floats in linear light, alpha is untouched
+    pub fn to_linear(self) -> [f32; 4] {
+        let [r, g, b, a] = self.to_srgb();
+        [srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b), a]
+    }
+}
+
+impl TryFrom<String> for Colour {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let hex = value.strip_prefix('#').unwrap_or(&value);
+        if !(hex.len() == 6 || hex.len() == 8) || !hex.is_ascii() {
+            return Err(format!("colour {value:?} should look like #rrggbb or #rrggbbaa"));
+        }
+        let channel = |i: usize| {
+            u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+                .map_err(|e| format!("colour {value:?}: {e}"))
+        };
+        Ok(Self {
+            r: channel(0)?,
+            g: channel(1)?,
+            b: channel(2)?,
+            a: if hex.len() == 8 { channel(3)? } else { 255 },
+        })
+    }
+}
+
+impl From<Colour> for String {
+    fn from(c: Colour) -> Self {
+        format!("#{:02x}{:02x}{:02x}{:02x}", c.r, c.g, c.b, c.a)
+    }
+}
+
+/// Porter-Duff `src` over `dst` for straight alpha colours
+pub fn over(dst: [f32; 4], src: [f32; 4]) -> [f32; 4] {
+    let a = src[3] + dst[3] * (1.0 - src[3]);
+    if a <= 0.0 {
+        return [0.0; 4];
+    }
+    let mut out = [0.0, 0.0, 0.0, a];
+    for c in 0..3 {
+        out[c] = (src[c] * src[3] + dst[c] * dst[3] * (1.0 - src[3])) / a;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What the conversion shader hands over for scRGB `v`: a half float,
+    /// divided by the brightest pixel then truncated
+    fn converted(v: f32, max_luminosity: f32) -> u16 {
+        (f16::from_f32(v).to_f32() / max_luminosity * 65535.0).clamp(0.0, 65535.0) as u16
+    }
+
+    fn grey(values: impl Iterator<Item = u16>) -> PixelBuffer {
+        let data: Vec<[u16; 4]> = values.map(|v| [v, v, v, u16::MAX]).collect();
+        PixelBuffer { width: data.len() as u32, height: 1, data }
+    }
+
+    #[test]
+    fn sdr_desktop_fits_8_bits() {
+        // every sRGB code as the desktop compositor would hand it over
+        let image = grey((0..=255).map(|c| converted(srgb_to_linear(c as f32 / 255.0), 1.0)));
+        assert!(image.fits_srgb8());
+
+        let exact = grey((0..=255).map(|c| to_unorm16(srgb_to_linear(c as f32 / 255.0))));
+        assert!(exact.fits_srgb8());
+    }
+
+    #[test]
+    fn finer_shades_dont_fit() {
+        let gradient = grey((0..4096).map(|i| i * 16));
+        assert!(!gradient.fits_srgb8());
+
+        // SDR content brought down by a brighter HDR highlight lands between codes
+        let tone_mapped = grey((0..=255).map(|c| converted(srgb_to_linear(c as f32 / 255.0), 1.37)));
+        assert!(!tone_mapped.fits_srgb8());
+
+        let mut see_through = grey((0..=255).map(|c| converted(srgb_to_linear(c as f32 / 255.0), 1.0)));
+        see_through.data[10][3] = 1000;
+        assert!(!see_through.fits_srgb8());
+    }
+}
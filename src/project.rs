@@ -0,0 +1,287 @@
+//! Editable project files saved next to exported captures.
+//!
+//! Layout, all integers little endian:
+//! ```text
+//! b"SSHOTPRJ"
+//! u32         header length
+//! [u8]        header, json
+//! [u8]        zlib compressed pixels, rows of RGBA half floats
+//! ```
+//! The header carries a schema `version`, older versions are migrated on read.
+
+use std::{
+    error::Error,
+    io::{Read, Write},
+    path::Path,
+};
+
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use half::f16;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    annotations::Annotations,
+    config::ExportSettings,
+    pixels::{Rect, ScrgbBuffer},
+};
+
+const MAGIC: &[u8; 8] = b"SSHOTPRJ";
+/// Longest header read, layers are small but there can be a lot of them
+const MAX_HEADER: usize = 16 << 20;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Project {
+    /// The whole screen as captured
+    pub frame: ScrgbBuffer,
+    /// Area of `frame` that was exported
+    pub selection: Option<Rect>,
+    pub annotations: Annotations,
+    pub export: ExportSettings,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    version: u32,
+    width: u32,
+    height: u32,
+    pixel_format: PixelFormat,
+    selection: Option<Rect>,
+    layers: Annotations,
+    #[serde(default)]
+    export: ExportSettings,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum PixelFormat {
+    /// scRGB as 4 little endian IEEE half floats per pixel
+    Rgba16Float,
+}
+
+impl Project {
+    pub const VERSION: u32 = 1;
+    pub const EXTENSION: &'static str = "sshot";
+
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        Self::read(std::io::BufReader::new(std::fs::File::open(path)?))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        self.write(&mut file)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    pub fn write(&self, mut w: impl Write) -> Result<(), Box<dyn Error>> {
+        let header = serde_json::to_vec(&Header {
+            version: Self::VERSION,
+            width: self.frame.width,
+            height: self.frame.height,
+            pixel_format: PixelFormat::Rgba16Float,
+            selection: self.selection,
+            layers: self.annotations.clone(),
+            export: self.export.clone(),
+        })?;
+
+        w.write_all(MAGIC)?;
+        w.write_all(&(header.len() as u32).to_le_bytes())?;
+        w.write_all(&header)?;
+
+        let mut pixels = ZlibEncoder::new(w, Compression::default());
+        pixels.write_all(&self.frame.to_le_bytes())?;
+        pixels.finish()?;
+        Ok(())
+    }
+
+    pub fn read(mut r: impl Read) -> Result<Self, Box<dyn Error>> {
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err("not a screenshotter project".into());
+        }
+
+        let mut len = [0; 4];
+        r.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len) as usize;
+        if len > MAX_HEADER {
+            return Err(format!("project header is {len} bytes, more than {MAX_HEADER}").into());
+        }
+        let mut header = vec![0; len];
+        r.read_exact(&mut header)?;
+        let header: Header = serde_json::from_value(migrate(serde_json::from_slice(&header)?)?)?;
+
+        let pixels = (header.width as u64).checked_mul(header.height as u64)
+            .filter(|&pixels| pixels <= ScrgbBuffer::MAX_PIXELS)
+            .ok_or_else(|| format!("project is {}x{}, too big to open", header.width, header.height))?;
+        let expected = pixels as usize * 8;
+        let mut bytes = Vec::with_capacity(expected);
+        // one byte over is enough to tell it's too long
+        ZlibDecoder::new(r).take(expected as u64 + 1).read_to_end(&mut bytes)?;
+        if bytes.len() != expected {
+            return Err(format!(
+                "project pixels are {} bytes, expected {} for {}x{}",
+                bytes.len(), expected, header.width, header.height
+            ).into());
+        }
+
+        let data = bytes.chunks_exact(8)
+            .map(|px| [
+                f16::from_le_bytes([px[0], px[1]]),
+                f16::from_le_bytes([px[2], px[3]]),
+                f16::from_le_bytes([px[4], px[5]]),
+                f16::from_le_bytes([px[6], px[7]]),
+            ])
+            .collect();
+
+        Ok(Self {
+            frame: ScrgbBuffer { width: header.width, height: header.height, data },
+            selection: header.selection,
+            annotations: header.layers,
+            export: header.export,
+        })
+    }
+}
+
+/// Bring a header from any released schema up to `Project::VERSION`.
+/// Each version bump adds a step here rewriting the json of the one before.
+fn migrate(header: serde_json::Value) -> Result<serde_json::Value, Box<dyn Error>> {
+    let version = header.get("version")
+        .and_then(|v| v.as_u64())
+        .ok_or("project header has no version")?;
+
+    match version {
+        1 => Ok(header),
+        0 => Err("project version 0 was never written, the file is corrupt".into()),
+        v => Err(format!("project version {v} is newer than this build supports ({})", Project::VERSION).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        annotations::Annotation,
+        effects::spotlight::SpotlightSettings,
+    };
+
+    fn sample() -> Project {
+        let (width, height) = (7, 5);
+        // hdr values above 1.0, negatives from wide gamut, and the odd subnormal
+        let data = (0..width * height)
+            .map(|i| [
+                f16::from_f32(i as f32 * 0.37),
+                f16::from_f32(-0.125 * i as f32),
+                f16::from_f32(12.5),
+                f16::from_bits(i as u16 + 1),
+            ])
+            .collect();
+
+        let mut annotations = Annotations::default();
+        annotations.add(Annotation::Highlight { rect: Rect { left: 1, top: 1, right: 4, bottom: 3 } });
+        annotations.add(Annotation::Highlight { rect: Rect { left: 5, top: 0, right: 7, bottom: 5 } });
+
+        Project {
+            frame: ScrgbBuffer { width, height, data },
+            selection: Some(Rect { left: 0, top: 0, right: 7, bottom: 5 }),
+            annotations,
+            export: ExportSettings {
+                spotlight: Some(SpotlightSettings { dim: 0.2, blur: 3.0 }),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn round_trip(project: &Project) -> Project {
+        let mut bytes = Vec::new();
+        project.write(&mut bytes).unwrap();
+        Project::read(bytes.as_slice()).unwrap()
+    }
+
+    #[test]
+    fn round_trip_is_lossless() {
+        let project = sample();
+        let read = round_trip(&project);
+
+        let bits = |p: &Project| -> Vec<u16> {
+            p.frame.data.iter().flat_map(|px| px.map(f16::to_bits)).collect()
+        };
+        assert_eq!(bits(&read), bits(&project));
+        assert_eq!(read, project);
+    }
+
+    #[test]
+    fn round_trip_survives_edits() {
+        let mut project = round_trip(&sample());
+        project.annotations.layers[0].visible = false;
+        project.selection = None;
+        project.export.spotlight = None;
+
+        assert_eq!(round_trip(&project), project);
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let patched = with_header(|h| h.replace("\"version\":1", "\"version\":2"));
+        let err = Project::read(patched.as_slice()).unwrap_err();
+        assert!(err.to_string().contains("newer"), "{err}");
+    }
+
+    fn with_header(edit: impl Fn(&str) -> String) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        sample().write(&mut bytes).unwrap();
+
+        let len = u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as usize;
+        let header = String::from_utf8(bytes[12..12 + len].to_vec()).unwrap();
+        let edited = edit(&header);
+        assert_ne!(header, edited);
+
+        let mut patched = bytes[..8].to_vec();
+        patched.extend((edited.len() as u32).to_le_bytes());
+        patched.extend(edited.as_bytes());
+        patched.extend(&bytes[12 + len..]);
+        patched
+    }
+
+    #[test]
+    fn rejects_version_zero() {
+        let patched = with_header(|h| h.replace("\"version\":1", "\"version\":0"));
+        let err = Project::read(patched.as_slice()).unwrap_err();
+        assert!(err.to_string().contains("corrupt"), "{err}");
+    }
+
+    #[test]
+    fn rejects_huge_sizes() {
+        let mut bytes = Vec::new();
+        sample().write(&mut bytes).unwrap();
+        bytes[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(Project::read(bytes.as_slice()).is_err());
+
+        for (width, height) in [(u32::MAX, u32::MAX), (1 << 16, 1 << 16)] {
+            let patched = with_header(|h| h.replace("\"width\":7", &format!("\"width\":{width}")).replace("\"height\":5", &format!("\"height\":{height}")));
+            let err = Project::read(patched.as_slice()).unwrap_err();
+            assert!(err.to_string().contains("too big"), "{err}");
+        }
+    }
+
+    #[test]
+    fn rejects_extra_pixels() {
+        // claims fewer pixels than the stream holds
+        let patched = with_header(|h| h.replace("\"height\":5", "\"height\":4"));
+        assert!(Project::read(patched.as_slice()).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_pixels() {
+        let mut bytes = Vec::new();
+        sample().write(&mut bytes).unwrap();
+        bytes.truncate(bytes.len() - 10);
+
+        assert!(Project::read(bytes.as_slice()).is_err());
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(Project::read(&b"\x89PNG\r\n\x1a\n\0\0\0\0"[..]).is_err());
+    }
+}
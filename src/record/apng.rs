@@ -0,0 +1,147 @@
+//! Animated png where every frame after the first only holds the rectangle
+//! that changed, drawn over what was already there.
+
+use std::{error::Error, path::PathBuf, time::Duration};
+
+use serde::Deserialize;
+
+use super::{changed_rect, crop_samples, Recorder};
+use crate::{
+    encode::{colour::Encoding, deflate, png::{filter_rows, Filter}},
+    pixels::{Rect, ScrgbBuffer},
+};
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ApngSettings {
+    /// Deflate level, 0 only stores and 9 is smallest but slowest
+    pub level: u32,
+    /// Times to play it through, 0 loops forever
+    pub plays: u32,
+}
+
+impl Default for ApngSettings {
+    fn default() -> Self {
+        Self { level: 6, plays: 0 }
+    }
+}
+
+pub struct ApngRecorder {
+    path: PathBuf,
+    width: u32,
+    height: u32,
+    settings: ApngSettings,
+    /// Nits the capture's SDR white was shown at
+    sdr_white: f32,
+    /// 8 bit RGB of the last frame, the next one is compared against it
+    previous: Option<Vec<u8>>,
+    /// Compressed as they come in, the newest one's delay isn't known until the next arrives
+    frames: Vec<Frame>,
+    /// When the newest frame was shown
+    last_time: Duration,
+}
+
+struct Frame {
+    rect: Rect,
+    delay: Duration,
+    /// zlib stream of the filtered rows
+    data: Vec<u8>,
+}
+
+impl ApngRecorder {
+    pub fn new(path: PathBuf, width: u32, height: u32, settings: ApngSettings, sdr_white: f32) -> Self {
+        Self { path, width, height, settings, sdr_white, previous: None, frames: Vec::new(), last_time: Duration::ZERO }
+    }
+
+    fn encode(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut info = png::Info::with_size(self.width, self.height);
+        info.color_type = png::ColorType::Rgb;
+        info.bit_depth = png::BitDepth::Eight;
+        info.animation_control = Some(png::AnimationControl { num_frames: self.frames.len() as u32, num_plays: self.settings.plays });
+        // only there because the encoder wants both, the frame controls are written below
+        info.frame_control = Some(png::FrameControl::default());
+        Encoding::SRGB.tag_png(&mut info);
+
+        let mut data = Vec::with_capacity(self.frames.iter().map(|f| f.data.len() + 64).sum());
+        {
+            let mut writer = png::Encoder::with_info(&mut data, info)?.write_header()?;
+            let mut sequence = 0u32;
+            for (i, frame) in self.frames.iter().enumerate() {
+                let (delay_num, delay_den) = delay_fraction(frame.delay);
+                let mut control = Vec::with_capacity(26);
+                control.extend(sequence.to_be_bytes());
+                for v in [frame.rect.right - frame.rect.left, frame.rect.bottom - frame.rect.top, frame.rect.left, frame.rect.top] {
+                    control.extend((v as u32).to_be_bytes());
+                }
+                control.extend(delay_num.to_be_bytes());
+                control.extend(delay_den.to_be_bytes());
+                // leave the frame in place and replace what's under it
+                control.extend([png::DisposeOp::None as u8, png::BlendOp::Source as u8]);
+                writer.write_chunk(png::chunk::fcTL, &control)?;
+                sequence += 1;
+
+                // the first frame doubles as the still image for viewers that don't animate
+                if i == 0 {
+                    writer.write_chunk(png::chunk::IDAT, &frame.data)?;
+                } else {
+                    let mut chunk = Vec::with_capacity(frame.data.len() + 4);
+                    chunk.extend(sequence.to_be_bytes());
+                    chunk.extend(&frame.data);
+                    writer.write_chunk(png::chunk::fdAT, &chunk)?;
+                    sequence += 1;
+                }
+            }
+        }
+        Ok(data)
+    }
+}
+
+impl Recorder for ApngRecorder {
+    fn push(&mut self, frame: &ScrgbBuffer, time: Duration) -> Result<(), Box<dyn Error>> {
+        if (frame.width, frame.height) != (self.width, self.height) {
+            return Err("frame size changed during the recording".into());
+        }
+        if self.settings.level > 9 {
+            return Err(format!("png compression level has to be 0 to 9, not {}", self.settings.level).into());
+        }
+        let rgb = frame.to_sdr(self.sdr_white).to_srgb8([0.0; 3]);
+        let width = self.width as usize;
+        let rect = match &self.previous {
+            None => Rect { left: 0, top: 0, right: self.width as i32, bottom: self.height as i32 },
+            Some(previous) => match changed_rect(previous, &rgb, width, 3) {
+                Some(rect) => rect,
+                // nothing new, the frame before just stays up longer
+                None => return Ok(()),
+            },
+        };
+
+        if let Some(last) = self.frames.last_mut() {
+            last.delay = time.saturating_sub(self.last_time);
+        }
+        let samples = crop_samples(&rgb, width, 3, rect);
+        let row_len = (rect.right - rect.left) as usize * 3;
+        let data = deflate::zlib(&filter_rows(&samples, row_len, 3, Filter::Adaptive), self.settings.level, 0)?;
+        self.frames.push(Frame { rect, delay: Duration::ZERO, data });
+        self.last_time = time;
+        self.previous = Some(rgb);
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>, end: Duration) -> Result<(), Box<dyn Error>> {
+        let last_time = self.last_time;
+        match self.frames.last_mut() {
+            Some(last) => last.delay = end.saturating_sub(last_time),
+            None => return Err("nothing was recorded".into()),
+        }
+        std::fs::write(&self.path, self.encode()?)?;
+        Ok(())
+    }
+}
+
+/// Milliseconds, or hundredths for delays too long to fit
+fn delay_fraction(delay: Duration) -> (u16, u16) {
+    match u16::try_from(delay.as_millis()) {
+        Ok(ms) => (ms, 1000),
+        Err(_) => (u16::try_from(delay.as_millis() / 10).unwrap_or(u16::MAX), 100),
+    }
+}
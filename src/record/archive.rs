@@ -0,0 +1,593 @@
+//! Lossless recordings, every frame exactly as it came off the screen.
+//!
+//! Layout, all integers little endian:
+//! ```text
+//! b"SSHOTREC"
+//! u32         header length
+//! [u8]        header, json
+//! frames, each
+//!   u8        1 for a keyframe, 0 for a delta on the frame before
+//!   u64       microseconds into the recording
+//!   u32       patch count
+//!   patches, each
+//!     i32 x4  left, top, right, bottom
+//!     u32     length
+//!     [u8]    zlib compressed rows of RGBA half floats
+//! index, written when the recording finishes
+//!   frames, each u64 offset, u64 microseconds, u8 keyframe
+//!   u64       microseconds the last frame lasts until
+//!   u64       frame count
+//!   u64       offset of the index
+//!   b"SSHOTIDX"
+//! ```
+//! A keyframe has one patch covering the whole frame. A delta has a patch for
+//! each run of tiles that changed. A recording that never finished has no
+//! index, the reader finds the frames by going through them instead.
+
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use half::f16;
+use serde::{Deserialize, Serialize};
+
+use super::{crop_samples, Recorder};
+use crate::{
+    encode::{exr, png},
+    pixels::{Rect, ScrgbBuffer},
+    replay::{Patch, Timeline},
+};
+
+const MAGIC: &[u8; 8] = b"SSHOTREC";
+const INDEX_MAGIC: &[u8; 8] = b"SSHOTIDX";
+/// End time, frame count and index offset, then the magic
+const TRAILER_LEN: usize = 8 * 3 + INDEX_MAGIC.len();
+/// Longest header read, it only holds the size and format
+const MAX_HEADER: usize = 64 * 1024;
+/// Bytes a patch takes before its data
+const PATCH_HEADER_LEN: u64 = 4 * 4 + 4;
+/// Deltas are worked out on a grid of squares this many pixels across
+const TILE: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ArchiveSettings {
+    /// Seconds between keyframes. Seeking starts from the one before, so
+    /// they're quicker to scrub through when closer together, but bigger.
+    pub keyframe_every: f32,
+    /// Deflate level, 0 only stores and 9 is smallest but slowest
+    pub level: u32,
+}
+
+impl Default for ArchiveSettings {
+    fn default() -> Self {
+        Self { keyframe_every: 2.0, level: 6 }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    version: u32,
+    width: u32,
+    height: u32,
+    pixel_format: PixelFormat,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum PixelFormat {
+    /// scRGB as 4 little endian IEEE half floats per pixel
+    Rgba16Float,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Entry {
+    offset: u64,
+    time: Duration,
+    key: bool,
+}
+
+/// Every frame and when the recording ended
+type Index = (Vec<Entry>, Duration);
+
+pub struct ArchiveWriter<W: Write> {
+    w: W,
+    /// Bytes written so far, where the next frame goes
+    offset: u64,
+    width: u32,
+    height: u32,
+    settings: ArchiveSettings,
+    /// The frame before as bytes, deltas are against it
+    previous: Option<Vec<u8>>,
+    last_key: Option<Duration>,
+    entries: Vec<Entry>,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub const VERSION: u32 = 1;
+
+    pub fn new(mut w: W, width: u32, height: u32, settings: ArchiveSettings) -> Result<Self, Box<dyn Error>> {
+        if width == 0 || height == 0 {
+            return Err("can't record an empty region".into());
+        }
+        let header = serde_json::to_vec(&Header { version: Self::VERSION, width, height, pixel_format: PixelFormat::Rgba16Float })?;
+        w.write_all(MAGIC)?;
+        w.write_all(&(header.len() as u32).to_le_bytes())?;
+        w.write_all(&header)?;
+        let offset = (MAGIC.len() + 4 + header.len()) as u64;
+        Ok(Self { w, offset, width, height, settings, previous: None, last_key: None, entries: Vec::new() })
+    }
+
+    /// Add a frame shown `time` into the recording, one that's the same as the
+    /// frame before isn't written and that one lasts longer instead
+    pub fn push(&mut self, frame: &ScrgbBuffer, time: Duration) -> Result<(), Box<dyn Error>> {
+        if (frame.width, frame.height) != (self.width, self.height) {
+            return Err("frame size changed during the recording".into());
+        }
+        if self.entries.last().is_some_and(|last| time < last.time) {
+            return Err("frames have to come in the order they were shown".into());
+        }
+        let bytes = frame.to_le_bytes();
+        if self.previous.as_ref() == Some(&bytes) {
+            return Ok(());
+        }
+        let width = self.width as usize;
+        let key = self.last_key.is_none_or(|last| (time - last).as_secs_f32() >= self.settings.keyframe_every);
+        let areas = match &self.previous {
+            Some(previous) if !key => changed_tiles(previous, &bytes, width, self.height as usize),
+            _ => vec![Rect { left: 0, top: 0, right: self.width as i32, bottom: self.height as i32 }],
+        };
+
+        let patches = areas.into_iter()
+            .map(|rect| Patch::compress(rect, &crop_samples(&bytes, width, 8, rect), self.settings.level))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut out = Vec::new();
+        out.push(key as u8);
+        out.extend((time.as_micros() as u64).to_le_bytes());
+        out.extend((patches.len() as u32).to_le_bytes());
+        for patch in &patches {
+            let Rect { left, top, right, bottom } = patch.rect;
+            for v in [left, top, right, bottom] {
+                out.extend(v.to_le_bytes());
+            }
+            out.extend((patch.data.len() as u32).to_le_bytes());
+            out.extend(patch.data.iter());
+        }
+        self.w.write_all(&out)?;
+
+        self.entries.push(Entry { offset: self.offset, time, key });
+        self.offset += out.len() as u64;
+        if key {
+            self.last_key = Some(time);
+        }
+        self.previous = Some(bytes);
+        Ok(())
+    }
+
+    /// Write the index, the last frame lasts until `end`
+    pub fn finish(mut self, end: Duration) -> Result<W, Box<dyn Error>> {
+        if self.entries.is_empty() {
+            return Err("nothing was recorded".into());
+        }
+        let mut out = Vec::with_capacity(self.entries.len() * 17 + TRAILER_LEN);
+        for entry in &self.entries {
+            out.extend(entry.offset.to_le_bytes());
+            out.extend((entry.time.as_micros() as u64).to_le_bytes());
+            out.push(entry.key as u8);
+        }
+        let end = end.max(self.entries.last().map_or(end, |e| e.time));
+        out.extend((end.as_micros() as u64).to_le_bytes());
+        out.extend((self.entries.len() as u64).to_le_bytes());
+        out.extend(self.offset.to_le_bytes());
+        out.extend(INDEX_MAGIC);
+        self.w.write_all(&out)?;
+        self.w.flush()?;
+        Ok(self.w)
+    }
+}
+
+/// Writes the recording as it goes, so most of it survives a crash
+pub struct ArchiveRecorder {
+    writer: ArchiveWriter<BufWriter<File>>,
+}
+
+impl ArchiveRecorder {
+    pub fn new(path: PathBuf, width: u32, height: u32, settings: ArchiveSettings) -> Result<Self, Box<dyn Error>> {
+        let file = BufWriter::new(File::create(&path)?);
+        Ok(Self { writer: ArchiveWriter::new(file, width, height, settings)? })
+    }
+}
+
+impl Recorder for ArchiveRecorder {
+    fn push(&mut self, frame: &ScrgbBuffer, time: Duration) -> Result<(), Box<dyn Error>> {
+        self.writer.push(frame, time)
+    }
+
+    fn finish(self: Box<Self>, end: Duration) -> Result<(), Box<dyn Error>> {
+        self.writer.finish(end)?;
+        Ok(())
+    }
+}
+
+/// Reads frames back from anywhere in a recording
+pub struct Archive<R: Read + Seek> {
+    r: R,
+    pub width: u32,
+    pub height: u32,
+    entries: Vec<Entry>,
+    /// When the last frame stops showing
+    end: Duration,
+    /// Length of the file, nothing in it can claim to be longer
+    size: u64,
+    /// The frame read last, stepping forward from it saves going back to the keyframe
+    cached: Option<(usize, ScrgbBuffer)>,
+}
+
+impl Archive<BufReader<File>> {
+    pub const EXTENSION: &'static str = "sshotrec";
+
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read + Seek> Archive<R> {
+    pub fn read(mut r: R) -> Result<Self, Box<dyn Error>> {
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err("not a recording archive".into());
+        }
+        let len = read_u32(&mut r)? as usize;
+        let first_frame = (MAGIC.len() + 4 + len) as u64;
+        let size = r.seek(SeekFrom::End(0))?;
+        if len > MAX_HEADER || first_frame > size {
+            return Err(format!("archive header claims to be {len} bytes, the file is {size}").into());
+        }
+        r.seek(SeekFrom::Start((MAGIC.len() + 4) as u64))?;
+        let mut header = vec![0; len];
+        r.read_exact(&mut header)?;
+        let header: Header = serde_json::from_slice(&header)?;
+        if header.version > ArchiveWriter::<Vec<u8>>::VERSION {
+            return Err(format!("archive version {} is newer than this build supports", header.version).into());
+        }
+        let pixels = header.width as u64 * header.height as u64;
+        if pixels == 0 || pixels > ScrgbBuffer::MAX_PIXELS {
+            return Err(format!("archive frames are {}x{}, that can't be right", header.width, header.height).into());
+        }
+
+        let (entries, end) = match read_index(&mut r)? {
+            Some(index) => index,
+            None => scan(&mut r, first_frame, size)?,
+        };
+        Ok(Self { r, width: header.width, height: header.height, entries, end, size, cached: None })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// How far into the recording frame `i` was shown
+    pub fn time(&self, i: usize) -> Duration {
+        self.entries[i].time
+    }
+
+    /// How long the whole recording lasts
+    pub fn duration(&self) -> Duration {
+        self.end
+    }
+
+    /// The frame showing `time` into the recording
+    pub fn index_at(&self, time: Duration) -> usize {
+        self.entries.partition_point(|e| e.time <= time).saturating_sub(1)
+    }
+
+    /// Frame `i`, built up from the keyframe before it
+    pub fn frame(&mut self, i: usize) -> Result<ScrgbBuffer, Box<dyn Error>> {
+        if i >= self.entries.len() {
+            return Err(format!("frame {} is past the end, there are {}", i, self.entries.len()).into());
+        }
+        let key = self.entries[..=i].iter().rposition(|e| e.key).ok_or("archive doesn't start with a keyframe")?;
+        let (start, mut frame) = match self.cached.take() {
+            Some((cached, frame)) if cached <= i && cached >= key => (cached + 1, frame),
+            _ => (key, ScrgbBuffer { width: self.width, height: self.height, data: vec![[f16::ZERO; 4]; self.width as usize * self.height as usize] }),
+        };
+        for entry in self.entries[start..=i].iter().copied() {
+            self.r.seek(SeekFrom::Start(entry.offset))?;
+            for patch in read_frame(&mut self.r, self.size)?.2 {
+                patch.apply(&mut frame)?;
+            }
+        }
+        self.cached = Some((i, frame.clone()));
+        Ok(frame)
+    }
+
+    /// Write frame `i` to `path` as png or exr, going by its extension. A png is
+    /// SDR, `sdr_white` is the nits white was shown at.
+    pub fn export(&mut self, i: usize, path: impl AsRef<Path>, sdr_white: f32) -> Result<(), Box<dyn Error>> {
+        let path = path.as_ref();
+        let frame = self.frame(i)?;
+        let bytes = match path.extension().and_then(|e| e.to_str()).map(str::to_lowercase).as_deref() {
+            Some("png") => png::encode(&frame.to_sdr(sdr_white), &png::PngSettings::default())?,
+            Some("exr") => exr::encode(&frame, &exr::ExrSettings::default())?,
+            _ => return Err(format!("can only export frames to png or exr, not {}", path.display()).into()),
+        };
+        std::fs::write(path, bytes)?;
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Timeline for Archive<R> {
+    fn len(&self) -> usize {
+        Archive::len(self)
+    }
+
+    fn time(&self, i: usize) -> Duration {
+        Archive::time(self, i)
+    }
+
+    fn frame(&mut self, i: usize) -> Result<ScrgbBuffer, Box<dyn Error>> {
+        Archive::frame(self, i)
+    }
+}
+
+/// Every tile that differs between two frames of packed half floats, tiles next
+/// to each other in a row of them joined up
+fn changed_tiles(previous: &[u8], current: &[u8], width: usize, height: usize) -> Vec<Rect> {
+    let row_len = width * 8;
+    let mut areas = Vec::new();
+    for top in (0..height).step_by(TILE) {
+        let bottom = (top + TILE).min(height);
+        let mut run: Option<Rect> = None;
+        for left in (0..width).step_by(TILE) {
+            let right = (left + TILE).min(width);
+            let differs = (top..bottom).any(|y| {
+                let span = y * row_len + left * 8..y * row_len + right * 8;
+                previous[span.clone()] != current[span]
+            });
+            match (&mut run, differs) {
+                (Some(rect), true) => rect.right = right as i32,
+                (None, true) => run = Some(Rect { left: left as i32, top: top as i32, right: right as i32, bottom: bottom as i32 }),
+                (Some(_), false) => areas.extend(run.take()),
+                (None, false) => {},
+            }
+        }
+        areas.extend(run);
+    }
+    areas
+}
+
+fn read_u32(r: &mut impl Read) -> Result<u32, Box<dyn Error>> {
+    let mut bytes = [0; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(r: &mut impl Read) -> Result<u64, Box<dyn Error>> {
+    let mut bytes = [0; 8];
+    r.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Whether it's a keyframe, when it was shown, and its patches. `size` is the
+/// length of the file, lengths that go past it are corrupt.
+fn read_frame<R: Read + Seek>(r: &mut R, size: u64) -> Result<(bool, Duration, Vec<Patch>), Box<dyn Error>> {
+    let mut key = [0];
+    r.read_exact(&mut key)?;
+    let time = Duration::from_micros(read_u64(r)?);
+    let count = read_u32(r)?;
+    let left_in_file = |r: &mut R| r.stream_position().map(|at| size.saturating_sub(at));
+    if count as u64 * PATCH_HEADER_LEN > left_in_file(r)? {
+        return Err(format!("frame claims {count} patches, more than the file has room for").into());
+    }
+    let mut patches = Vec::new();
+    for _ in 0..count {
+        let [left, top, right, bottom] = [(); 4].map(|_| read_u32(r).map(|v| v as i32));
+        let rect = Rect { left: left?, top: top?, right: right?, bottom: bottom? };
+        let len = read_u32(r)? as u64;
+        if len > left_in_file(r)? {
+            return Err(format!("patch claims {len} bytes, more than are left in the file").into());
+        }
+        let mut data = vec![0; len as usize];
+        r.read_exact(&mut data)?;
+        patches.push(Patch { rect, data: data.into() });
+    }
+    Ok((key[0] == 1, time, patches))
+}
+
+/// The index at the end, none if the recording never finished
+fn read_index<R: Read + Seek>(r: &mut R) -> Result<Option<Index>, Box<dyn Error>> {
+    let len = r.seek(SeekFrom::End(0))?;
+    if len < TRAILER_LEN as u64 {
+        return Ok(None);
+    }
+    r.seek(SeekFrom::End(-(TRAILER_LEN as i64)))?;
+    let end = Duration::from_micros(read_u64(r)?);
+    let count = read_u64(r)?;
+    let offset = read_u64(r)?;
+    let mut magic = [0; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != INDEX_MAGIC || offset.checked_add(count.saturating_mul(17)) != Some(len - TRAILER_LEN as u64) {
+        return Ok(None);
+    }
+
+    r.seek(SeekFrom::Start(offset))?;
+    let mut entries = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let offset = read_u64(r)?;
+        let time = Duration::from_micros(read_u64(r)?);
+        let mut key = [0];
+        r.read_exact(&mut key)?;
+        entries.push(Entry { offset, time, key: key[0] == 1 });
+    }
+    Ok(Some((entries, end)))
+}
+
+/// Find the frames by reading through them, a frame cut off part way is left out
+fn scan<R: Read + Seek>(r: &mut R, first_frame: u64, size: u64) -> Result<Index, Box<dyn Error>> {
+    let mut offset = r.seek(SeekFrom::Start(first_frame))?;
+    let mut entries = Vec::new();
+    while offset < size {
+        let Ok((key, time, _)) = read_frame(r, size) else {
+            break;
+        };
+        entries.push(Entry { offset, time, key });
+        offset = r.stream_position()?;
+    }
+    let end = entries.last().map_or(Duration::ZERO, |e| e.time);
+    Ok((entries, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// A frame that moves a little each time, with hdr values and negatives
+    fn frame(i: usize, width: u32, height: u32) -> ScrgbBuffer {
+        let data = (0..width * height)
+            .map(|p| {
+                let (x, y) = ((p % width) as usize, (p / width) as usize);
+                // a square that moves along, and one pixel that changes every frame
+                let lit = (x / 8 == i % 12 && y / 8 == i % 9) || (x, y) == (i % width as usize, height as usize - 1);
+                let v = if lit { 3.5 + i as f32 * 0.25 } else { (x + y) as f32 * 0.01 - 0.2 };
+                [f16::from_f32(v), f16::from_f32(-v), f16::from_f32(0.5), f16::from_f32(1.0)]
+            })
+            .collect();
+        ScrgbBuffer { width, height, data }
+    }
+
+    fn bits(frame: &ScrgbBuffer) -> Vec<u16> {
+        frame.data.iter().flat_map(|px| px.map(f16::to_bits)).collect()
+    }
+
+    /// 40 frames 50ms apart, with the odd repeat that shouldn't be written
+    fn record(width: u32, height: u32) -> (Vec<u8>, Vec<(ScrgbBuffer, Duration)>) {
+        let settings = ArchiveSettings { keyframe_every: 0.5, level: 1 };
+        let mut writer = ArchiveWriter::new(Vec::new(), width, height, settings).unwrap();
+        let mut shown = Vec::new();
+        for i in 0..40 {
+            let time = Duration::from_millis(i as u64 * 50);
+            let f = frame(if i % 7 == 6 { i - 1 } else { i }, width, height);
+            writer.push(&f, time).unwrap();
+            if i % 7 != 6 {
+                shown.push((f, time));
+            }
+        }
+        (writer.finish(Duration::from_secs(2)).unwrap(), shown)
+    }
+
+    #[test]
+    fn every_frame_reads_back_exactly() {
+        let (bytes, shown) = record(150, 70);
+        let mut archive = Archive::read(Cursor::new(bytes)).unwrap();
+        assert_eq!(archive.len(), shown.len());
+        assert_eq!(archive.duration(), Duration::from_secs(2));
+        for (i, (f, time)) in shown.iter().enumerate() {
+            assert_eq!(archive.time(i), *time);
+            assert_eq!(bits(&archive.frame(i).unwrap()), bits(f), "frame {i}");
+        }
+    }
+
+    #[test]
+    fn seeks_in_any_order() {
+        let (bytes, shown) = record(150, 70);
+        let mut archive = Archive::read(Cursor::new(bytes)).unwrap();
+        for i in [33, 2, 2, 17, 18, 0, 34, 9, 10, 25] {
+            assert_eq!(bits(&archive.frame(i).unwrap()), bits(&shown[i].0), "frame {i}");
+        }
+        assert_eq!(archive.index_at(Duration::from_millis(1025)), shown.iter().rposition(|(_, t)| t.as_millis() <= 1025).unwrap());
+        assert!(archive.frame(shown.len()).is_err());
+    }
+
+    #[test]
+    fn deltas_are_smaller_than_keyframes() {
+        let (bytes, _) = record(512, 256);
+        let archive = Archive::read(Cursor::new(bytes.clone())).unwrap();
+        let sizes: Vec<(bool, u64)> = archive.entries.windows(2).map(|w| (w[0].key, w[1].offset - w[0].offset)).collect();
+        let key = sizes.iter().filter(|(key, _)| *key).map(|(_, size)| *size).min().unwrap();
+        let delta = sizes.iter().filter(|(key, _)| !*key).map(|(_, size)| *size).max().unwrap();
+        assert!(sizes.iter().filter(|(key, _)| *key).count() >= 3);
+        assert!(delta * 3 < key, "delta {delta} against keyframe {key}");
+    }
+
+    #[test]
+    fn reads_a_recording_that_never_finished() {
+        let (bytes, shown) = record(150, 70);
+        let index = u64::from_le_bytes(bytes[bytes.len() - 16..bytes.len() - 8].try_into().unwrap()) as usize;
+        // the index never got written and the last frame was cut off part way
+        let cut = bytes[..index - 10].to_vec();
+        let mut archive = Archive::read(Cursor::new(cut)).unwrap();
+        assert_eq!(archive.len(), shown.len() - 1);
+        let last = archive.len() - 1;
+        assert_eq!(bits(&archive.frame(last).unwrap()), bits(&shown[last].0));
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(Archive::read(Cursor::new(b"SSHOTPRJ\0\0\0\0".to_vec())).is_err());
+        let (mut bytes, _) = record(20, 10);
+        let len = u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as usize;
+        let header = String::from_utf8(bytes[12..12 + len].to_vec()).unwrap().replace("\"version\":1", "\"version\":9");
+        bytes.splice(12..12 + len, header.into_bytes());
+        assert!(Archive::read(Cursor::new(bytes)).is_err());
+    }
+
+    #[test]
+    fn rejects_lengths_past_the_end() {
+        let (bytes, _) = record(20, 10);
+        let len = u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as usize;
+
+        let mut long_header = bytes.clone();
+        long_header[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(Archive::read(Cursor::new(long_header)).is_err());
+
+        let header = String::from_utf8(bytes[12..12 + len].to_vec()).unwrap();
+        for size in ["\"width\":4294967295", "\"width\":0"] {
+            let mut huge = bytes.clone();
+            huge.splice(12..12 + len, header.replace("\"width\":20", size).into_bytes());
+            assert!(Archive::read(Cursor::new(huge)).is_err(), "{size}");
+        }
+
+        // the first frame's patch count, then its first patch's length
+        let first_frame = 12 + len;
+        for at in [first_frame + 9, first_frame + 13 + 16] {
+            let mut corrupt = bytes.clone();
+            corrupt[at..at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+            let mut archive = Archive::read(Cursor::new(corrupt)).unwrap();
+            assert!(archive.frame(0).is_err());
+        }
+    }
+
+    #[test]
+    fn exports_frames() {
+        let (bytes, shown) = record(150, 70);
+        let mut archive = Archive::read(Cursor::new(bytes)).unwrap();
+        let dir = std::env::temp_dir();
+        let png_path = dir.join(format!("archive-test-{}.png", std::process::id()));
+        let exr_path = png_path.with_extension("exr");
+
+        archive.export(12, &png_path, 80.0).unwrap();
+        let decoder = ::png::Decoder::new(File::open(&png_path).unwrap());
+        let info = decoder.read_info().unwrap().info().clone();
+        assert_eq!((info.width, info.height), (150, 70));
+
+        archive.export(12, &exr_path, 80.0).unwrap();
+        let exr = std::fs::read(&exr_path).unwrap();
+        assert_eq!(exr[..4], [0x76, 0x2f, 0x31, 0x01]);
+        assert!(archive.export(12, dir.join("frame.bmp"), 80.0).is_err());
+
+        std::fs::remove_file(png_path).unwrap();
+        std::fs::remove_file(exr_path).unwrap();
+        assert_eq!(bits(&archive.frame(12).unwrap()), bits(&shown[12].0));
+    }
+}
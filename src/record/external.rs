@@ -0,0 +1,377 @@
+//! Video by streaming raw frames to another program, ffmpeg by default.
+//!
+//! Raw video has no timestamps, so frames go out at a constant rate with each
+//! one repeated until the next is due. When the encoder falls behind, frames
+//! that come in while the queue is full are dropped and the one before stays up
+//! longer, so the clip keeps the right length either way.
+
+use std::{
+    collections::VecDeque,
+    error::Error,
+    io::{self, BufRead, BufReader, Write},
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    sync::{atomic::{AtomicU32, Ordering}, mpsc::{self, SyncSender, TrySendError}},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use serde::Deserialize;
+use windows::{
+    core::HSTRING,
+    Win32::{
+        Foundation::{CloseHandle, GetLastError, ERROR_PIPE_CONNECTED, HANDLE, INVALID_HANDLE_VALUE},
+        Storage::FileSystem::{FlushFileBuffers, WriteFile, PIPE_ACCESS_OUTBOUND},
+        System::Pipes::{ConnectNamedPipe, CreateNamedPipeW, PIPE_TYPE_BYTE, PIPE_WAIT},
+    },
+};
+
+use super::Recorder;
+use crate::{
+    encode::avif::{bt709_to_bt2020, pq_oetf},
+    pixels::{ScrgbBuffer, SCRGB_WHITE},
+};
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ExternalSettings {
+    /// Looked up on the PATH if it isn't a full path
+    pub program: String,
+    /// Arguments to run it with. Besides `{path}` these can use `{input}`, where
+    /// to read the frames from, `{width}`, `{height}`, `{fps}`, `{pix_fmt}` the
+    /// ffmpeg name of the samples, and `{primaries}`, `{transfer}`, `{matrix}`
+    /// the ffmpeg names of their colour space. The defaults make an H.264 mp4,
+    /// for HDR10 swap to something like libx265 with yuv420p10le.
+    pub args: Vec<String>,
+    pub input: ExternalInput,
+    pub sample: VideoSample,
+    /// Frame rate of the stream
+    pub fps: f32,
+    /// Frames waiting for the encoder before new ones are dropped
+    pub queue: usize,
+}
+
+impl Default for ExternalSettings {
+    fn default() -> Self {
+        let args = [
+            "-hide_banner", "-loglevel", "error",
+            "-f", "rawvideo", "-pixel_format", "{pix_fmt}", "-video_size", "{width}x{height}", "-framerate", "{fps}",
+            "-i", "{input}",
+            // 4:2:0 needs even sizes
+            "-vf", "pad=ceil(iw/2)*2:ceil(ih/2)*2",
+            "-c:v", "libx264", "-pix_fmt", "yuv420p",
+            "-color_primaries", "{primaries}", "-color_trc", "{transfer}", "-colorspace", "{matrix}",
+            "-y", "{path}",
+        ];
+        Self {
+            program: "ffmpeg".to_string(),
+            args: args.map(String::from).to_vec(),
+            input: ExternalInput::Stdin,
+            sample: VideoSample::Sdr,
+            fps: 30.0,
+            queue: 8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExternalInput {
+    Stdin,
+    /// A named pipe, for programs that want stdin for something else
+    Pipe,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VideoSample {
+    /// 8 bit sRGB, `rgb24`
+    Sdr,
+    /// 10 bit PQ in BT.2020 as planes of 16 bit words, `gbrp10le`
+    Hdr10,
+}
+
+impl VideoSample {
+    /// `{pix_fmt}`, `{primaries}`, `{transfer}` and `{matrix}`
+    fn ffmpeg_names(self) -> [&'static str; 4] {
+        match self {
+            VideoSample::Sdr => ["rgb24", "bt709", "iec61966-2-1", "bt709"],
+            VideoSample::Hdr10 => ["gbrp10le", "bt2020", "smpte2084", "bt2020nc"],
+        }
+    }
+}
+
+/// A frame and how many times in a row to write it
+struct Chunk {
+    samples: Vec<u8>,
+    repeat: u64,
+}
+
+pub struct ExternalRecorder {
+    width: u32,
+    height: u32,
+    settings: ExternalSettings,
+    sdr_white: f32,
+    child: Child,
+    /// Set when the frames go through a named pipe, which is opened here if the
+    /// program quits before it does so the writer stops waiting
+    pipe_name: Option<String>,
+    sender: Option<SyncSender<Chunk>>,
+    writer: Option<JoinHandle<io::Result<()>>>,
+    /// The last few lines the program printed, for when it fails
+    stderr: Option<JoinHandle<String>>,
+    /// The newest frame and the slot it starts at, held back until the next
+    /// one says how long it lasts
+    current: Option<(Vec<u8>, u64)>,
+    dropped: u32,
+}
+
+impl ExternalRecorder {
+    pub fn new(path: PathBuf, width: u32, height: u32, settings: ExternalSettings, sdr_white: f32) -> Result<Self, Box<dyn Error>> {
+        if settings.fps.is_nan() || settings.fps <= 0.0 {
+            return Err(format!("recording fps has to be above 0, not {}", settings.fps).into());
+        }
+
+        let pipe = match settings.input {
+            ExternalInput::Stdin => None,
+            ExternalInput::Pipe => Some(Pipe::create()?),
+        };
+        let input = pipe.as_ref().map_or("-".to_string(), |pipe| pipe.name.clone());
+        let [pix_fmt, primaries, transfer, matrix] = settings.sample.ffmpeg_names();
+        let variables = [
+            ("{path}", path.display().to_string()),
+            ("{input}", input),
+            ("{width}", width.to_string()),
+            ("{height}", height.to_string()),
+            ("{fps}", settings.fps.to_string()),
+            ("{pix_fmt}", pix_fmt.to_string()),
+            ("{primaries}", primaries.to_string()),
+            ("{transfer}", transfer.to_string()),
+            ("{matrix}", matrix.to_string()),
+        ];
+        let args: Vec<String> = settings.args.iter()
+            .map(|arg| variables.iter().fold(arg.clone(), |arg, (name, value)| arg.replace(name, value)))
+            .collect();
+
+        let mut command = Command::new(&settings.program);
+        command
+            .args(&args)
+            .stdin(if pipe.is_some() { Stdio::null() } else { Stdio::piped() })
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped());
+        // we're a windows subsystem app, so a console program would get a console of its own
+        #[cfg(windows)]
+        std::os::windows::process::CommandExt::creation_flags(&mut command, windows::Win32::System::Threading::CREATE_NO_WINDOW.0);
+        let mut child = command.spawn().map_err(|e| format!("couldn't start {} : {e}", settings.program))?;
+        debug!("Started {} {:?}", settings.program, args);
+
+        let stderr = child.stderr.take().map(|stderr| std::thread::spawn(move || {
+            let mut lines = VecDeque::new();
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                if lines.len() == 10 {
+                    lines.pop_front();
+                }
+                lines.push_back(line);
+            }
+            Vec::from(lines).join("\n")
+        }));
+
+        let pipe_name = pipe.as_ref().map(|pipe| pipe.name.clone());
+        let sink: Box<dyn Write + Send> = match pipe {
+            Some(pipe) => Box::new(pipe),
+            None => Box::new(child.stdin.take().ok_or("no stdin to write to")?),
+        };
+        let (sender, receiver) = mpsc::sync_channel::<Chunk>(settings.queue.max(1));
+        let writer = std::thread::spawn(move || {
+            let mut sink = sink;
+            for chunk in receiver {
+                for _ in 0..chunk.repeat {
+                    sink.write_all(&chunk.samples)?;
+                }
+            }
+            sink.flush()
+            // dropping the sink closes it, which is how the encoder knows it's the end
+        });
+
+        Ok(Self {
+            width,
+            height,
+            settings,
+            sdr_white,
+            child,
+            pipe_name,
+            sender: Some(sender),
+            writer: Some(writer),
+            stderr,
+            current: None,
+            dropped: 0,
+        })
+    }
+
+    /// Which frame of the constant rate stream `time` falls on
+    fn slot(&self, time: Duration) -> u64 {
+        (time.as_secs_f64() * self.settings.fps as f64).round() as u64
+    }
+
+    fn samples(&self, frame: &ScrgbBuffer) -> Vec<u8> {
+        match self.settings.sample {
+            VideoSample::Sdr => frame.to_sdr(self.sdr_white).to_srgb8([0.0; 3]),
+            VideoSample::Hdr10 => {
+                let plane_len = frame.data.len() * 2;
+                let mut out = vec![0; plane_len * 3];
+                for (i, px) in frame.data.iter().enumerate() {
+                    let [r, g, b] = bt709_to_bt2020([px[0], px[1], px[2]].map(|c| c.to_f32() * SCRGB_WHITE))
+                        .map(|c| (pq_oetf(c.clamp(0.0, 10000.0)) * 1023.0).round() as u16);
+                    // planes go green, blue, red
+                    for (plane, v) in [g, b, r].into_iter().enumerate() {
+                        out[plane * plane_len + i * 2..][..2].copy_from_slice(&v.to_le_bytes());
+                    }
+                }
+                out
+            }
+        }
+    }
+
+    /// Queue the last frame. A writer still waiting for the pipe to be opened
+    /// never empties the queue, so this gives up once it or the program stops.
+    fn send_last(&mut self, sender: SyncSender<Chunk>, mut chunk: Chunk) {
+        loop {
+            match sender.try_send(chunk) {
+                Ok(()) | Err(TrySendError::Disconnected(_)) => return,
+                Err(TrySendError::Full(back)) => chunk = back,
+            }
+            let writer_stopped = self.writer.as_ref().is_none_or(JoinHandle::is_finished);
+            if writer_stopped || !matches!(self.child.try_wait(), Ok(None)) {
+                debug!("{} stopped before the last frame went out", self.settings.program);
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Wait for the writer, unsticking it if the program never opened the pipe
+    fn join_writer(&mut self) -> io::Result<()> {
+        let Some(writer) = self.writer.take() else {
+            return Ok(());
+        };
+        if let Some(name) = &self.pipe_name {
+            if !writer.is_finished() {
+                let _ = std::fs::OpenOptions::new().read(true).open(name);
+            }
+        }
+        writer.join().unwrap_or_else(|_| Err(io::Error::other("writer panicked")))
+    }
+}
+
+impl Recorder for ExternalRecorder {
+    fn push(&mut self, frame: &ScrgbBuffer, time: Duration) -> Result<(), Box<dyn Error>> {
+        if (frame.width, frame.height) != (self.width, self.height) {
+            return Err("frame size changed during the recording".into());
+        }
+        let samples = self.samples(frame);
+        let slot = self.slot(time);
+        let sender = self.sender.as_ref().ok_or("recording already finished")?;
+        match self.current.take() {
+            // lands on the same frame of the stream, the newer one wins
+            Some((_, start)) if slot <= start => self.current = Some((samples, start)),
+            Some((current, start)) => match sender.try_send(Chunk { samples: current, repeat: slot - start }) {
+                Ok(()) => self.current = Some((samples, slot)),
+                // the encoder is behind, the one waiting lasts until there's room
+                Err(TrySendError::Full(chunk)) => {
+                    self.dropped += 1;
+                    self.current = Some((chunk.samples, start));
+                }
+                Err(TrySendError::Disconnected(_)) => return Err(format!("{} stopped taking frames", self.settings.program).into()),
+            },
+            None => self.current = Some((samples, slot)),
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>, end: Duration) -> Result<(), Box<dyn Error>> {
+        let end = self.slot(end);
+        if let (Some(sender), Some((samples, start))) = (self.sender.take(), self.current.take()) {
+            // anything wrong shows up in the exit status
+            self.send_last(sender, Chunk { samples, repeat: end.saturating_sub(start).max(1) });
+        }
+
+        let status = self.child.wait()?;
+        let written = self.join_writer();
+        let output = self.stderr.take().and_then(|stderr| stderr.join().ok()).unwrap_or_default();
+        if !status.success() {
+            return Err(format!("{} failed with {status} : {output}", self.settings.program).into());
+        }
+        written.map_err(|e| format!("couldn't send frames to {} : {e}", self.settings.program))?;
+        if self.dropped > 0 {
+            debug!("{} couldn't keep up, dropped {} frames", self.settings.program, self.dropped);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ExternalRecorder {
+    /// Thrown away without finishing, don't leave the encoder running
+    fn drop(&mut self) {
+        if let Ok(None) = self.child.try_wait() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+        self.sender.take();
+        let _ = self.join_writer();
+    }
+}
+
+/// Server end of a named pipe the program reads the frames from
+struct Pipe {
+    name: String,
+    handle: HANDLE,
+    connected: bool,
+}
+
+impl Pipe {
+    fn create() -> Result<Self, Box<dyn Error>> {
+        static COUNT: AtomicU32 = AtomicU32::new(0);
+        let name = format!(r"\\.\pipe\screenshotter-{}-{}", std::process::id(), COUNT.fetch_add(1, Ordering::Relaxed));
+        let handle = unsafe {
+            CreateNamedPipeW(&HSTRING::from(name.as_str()), PIPE_ACCESS_OUTBOUND, PIPE_TYPE_BYTE | PIPE_WAIT, 1, 1 << 20, 0, 0, None)
+        };
+        if handle == INVALID_HANDLE_VALUE {
+            return Err(format!("couldn't create pipe {name} : {:?}", unsafe {GetLastError()}).into());
+        }
+        Ok(Self { name, handle, connected: false })
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        unsafe {
+            // blocks until the program opens it
+            if !self.connected {
+                if !ConnectNamedPipe(self.handle, None).as_bool() && GetLastError() != ERROR_PIPE_CONNECTED {
+                    return Err(io::Error::last_os_error());
+                }
+                self.connected = true;
+            }
+            let mut written = 0u32;
+            if !WriteFile(self.handle, Some(buf), Some(&mut written as *mut _), None).as_bool() {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(written as usize)
+        }
+    }
+
+    /// Waits for the program to read everything written, so the last frames
+    /// aren't lost when the handle closes
+    fn flush(&mut self) -> io::Result<()> {
+        if self.connected && !unsafe {FlushFileBuffers(self.handle)}.as_bool() {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        unsafe {CloseHandle(self.handle)};
+    }
+}
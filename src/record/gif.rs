@@ -0,0 +1,392 @@
+//! GIF, for places that only play those inline.
+//!
+//! Every frame after the first covers the rectangle that changed, with the
+//! pixels inside it that stayed the same left transparent so they compress
+//! to almost nothing. Frames are kept as 8 bit sRGB until the recording
+//! stops, since a palette for the whole clip needs all of them.
+
+use std::{collections::HashMap, error::Error, path::PathBuf, time::Duration};
+
+use color_quant::NeuQuant;
+use serde::Deserialize;
+use weezl::{encode::Encoder as LzwEncoder, BitOrder};
+
+use super::{changed_rect, crop_samples, Recorder};
+use crate::{
+    encode::png::{dither, exact_palette},
+    pixels::{Rect, ScrgbBuffer},
+};
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct GifSettings {
+    pub palette: GifPalette,
+    /// Spread the error from quantising so gradients don't band, costs some size
+    pub dither: bool,
+    /// Frames shown per second at most, anything quicker only keeps the newest.
+    /// Browsers slow down anything over 50.
+    pub max_fps: f32,
+    /// Times to play it through, 0 loops forever
+    pub plays: u32,
+}
+
+impl Default for GifSettings {
+    fn default() -> Self {
+        Self { palette: GifPalette::Local, dither: true, max_fps: 15.0, plays: 0 }
+    }
+}
+
+/// Each palette holds 255 colours, the last entry is kept for transparency
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GifPalette {
+    /// One for the whole clip, smallest when the colours don't change much
+    Global,
+    /// One per frame, truer colours when they do
+    Local,
+}
+
+pub struct GifRecorder {
+    path: PathBuf,
+    width: u32,
+    height: u32,
+    settings: GifSettings,
+    /// Nits the capture's SDR white was shown at
+    sdr_white: f32,
+    /// RGBA of the newest frame and when it was shown, it can still be
+    /// replaced by one that comes in too soon after
+    pending: Option<(Vec<u8>, Duration)>,
+    /// RGBA of the last frame kept, the next one is compared against it
+    previous: Option<Vec<u8>>,
+    frames: Vec<Frame>,
+}
+
+struct Frame {
+    rect: Rect,
+    start: Duration,
+    /// RGBA inside `rect`
+    rgba: Vec<u8>,
+    /// Pixels that are the same as the frame before, drawn transparent
+    kept: Vec<bool>,
+}
+
+impl Frame {
+    /// The pixels a palette has to cover
+    fn changed(&self) -> impl Iterator<Item = &[u8]> {
+        self.rgba.chunks_exact(4).zip(&self.kept).filter(|(_, kept)| !**kept).map(|(px, _)| px)
+    }
+}
+
+impl GifRecorder {
+    pub fn new(path: PathBuf, width: u32, height: u32, settings: GifSettings, sdr_white: f32) -> Self {
+        Self { path, width, height, settings, sdr_white, pending: None, previous: None, frames: Vec::new() }
+    }
+
+    fn keep(&mut self, rgba: Vec<u8>, start: Duration) {
+        let width = self.width as usize;
+        let (rect, kept) = match &self.previous {
+            None => (Rect { left: 0, top: 0, right: self.width as i32, bottom: self.height as i32 }, vec![false; rgba.len() / 4]),
+            Some(previous) => {
+                // nothing new, the frame before just stays up longer
+                let Some(rect) = changed_rect(previous, &rgba, width, 4) else {
+                    return;
+                };
+                let before = crop_samples(previous, width, 4, rect);
+                let after = crop_samples(&rgba, width, 4, rect);
+                let kept = before.chunks_exact(4).zip(after.chunks_exact(4)).map(|(a, b)| a == b).collect();
+                (rect, kept)
+            }
+        };
+        let rgba_rect = crop_samples(&rgba, width, 4, rect);
+        self.frames.push(Frame { rect, start, rgba: rgba_rect, kept });
+        self.previous = Some(rgba);
+    }
+
+    fn encode(&self, end: Duration) -> Result<Vec<u8>, Box<dyn Error>> {
+        let global = match self.settings.palette {
+            GifPalette::Global => {
+                let pixels: Vec<u8> = self.frames.iter().flat_map(Frame::changed).flatten().copied().collect();
+                Some(Palette::new(&pixels))
+            }
+            GifPalette::Local => None,
+        };
+
+        let mut out = Vec::new();
+        out.extend(b"GIF89a");
+        out.extend((self.width as u16).to_le_bytes());
+        out.extend((self.height as u16).to_le_bytes());
+        // 8 bits per primary, then the size of the global table if there is one
+        match &global {
+            Some(palette) => {
+                out.push(0x80 | 0x70 | (palette.bits() - 1));
+                out.extend([0, 0]); // background colour, pixel aspect
+                palette.write_table(&mut out);
+            }
+            None => out.extend([0x70, 0, 0]),
+        }
+        if self.settings.plays != 1 {
+            // the loop count is how many times to repeat after the first
+            out.extend([0x21, 0xff, 11]);
+            out.extend(b"NETSCAPE2.0");
+            out.extend([3, 1]);
+            out.extend((self.settings.plays.saturating_sub(1).min(u16::MAX as u32) as u16).to_le_bytes());
+            out.push(0);
+        }
+
+        for (i, frame) in self.frames.iter().enumerate() {
+            let local;
+            let palette = match &global {
+                Some(palette) => palette,
+                None => {
+                    let pixels: Vec<u8> = frame.changed().flatten().copied().collect();
+                    local = Palette::new(&pixels);
+                    &local
+                }
+            };
+            let transparent = palette.transparent();
+            let mut indices = palette.indices(&frame.rgba, &frame.kept, (frame.rect.right - frame.rect.left) as usize, self.settings.dither);
+            for (index, kept) in indices.iter_mut().zip(&frame.kept) {
+                if *kept {
+                    *index = transparent;
+                }
+            }
+
+            // delays are rounded from the start of the recording so the error doesn't add up
+            let next = self.frames.get(i + 1).map_or(end, |f| f.start);
+            let delay = (centiseconds(next) - centiseconds(frame.start)).clamp(2, u16::MAX as u64) as u16;
+            let any_kept = frame.kept.contains(&true);
+            // graphic control: leave the frame in place, with the transparent index if it's used
+            out.extend([0x21, 0xf9, 4, 1 << 2 | any_kept as u8]);
+            out.extend(delay.to_le_bytes());
+            out.extend([if any_kept { transparent } else { 0 }, 0]);
+
+            out.push(0x2c);
+            for v in [frame.rect.left, frame.rect.top, frame.rect.right - frame.rect.left, frame.rect.bottom - frame.rect.top] {
+                out.extend((v as u16).to_le_bytes());
+            }
+            if global.is_some() {
+                out.push(0);
+            } else {
+                out.push(0x80 | (palette.bits() - 1));
+                palette.write_table(&mut out);
+            }
+
+            let code_size = palette.bits().max(2);
+            out.push(code_size);
+            for block in lzw(&indices, code_size)?.chunks(255) {
+                out.push(block.len() as u8);
+                out.extend(block);
+            }
+            out.push(0);
+        }
+        out.push(0x3b);
+        Ok(out)
+    }
+}
+
+impl Recorder for GifRecorder {
+    fn push(&mut self, frame: &ScrgbBuffer, time: Duration) -> Result<(), Box<dyn Error>> {
+        if (frame.width, frame.height) != (self.width, self.height) {
+            return Err("frame size changed during the recording".into());
+        }
+        if self.width > u16::MAX as u32 || self.height > u16::MAX as u32 {
+            return Err(format!("a gif can be at most {0}x{0}", u16::MAX).into());
+        }
+        if self.settings.max_fps.is_nan() || self.settings.max_fps <= 0.0 {
+            return Err(format!("gif max_fps has to be above 0, not {}", self.settings.max_fps).into());
+        }
+        let interval = Duration::from_secs_f32(1.0 / self.settings.max_fps.min(50.0));
+        let rgba = frame.to_sdr(self.sdr_white).to_srgba8();
+        match self.pending.take() {
+            // too soon, it takes the place of the one waiting
+            Some((_, start)) if time.saturating_sub(start) < interval => self.pending = Some((rgba, start)),
+            Some((pending, start)) => {
+                self.keep(pending, start);
+                self.pending = Some((rgba, time));
+            }
+            None => self.pending = Some((rgba, time)),
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>, end: Duration) -> Result<(), Box<dyn Error>> {
+        let Some((pending, start)) = self.pending.take() else {
+            return Err("nothing was recorded".into());
+        };
+        self.keep(pending, start);
+        std::fs::write(&self.path, self.encode(end)?)?;
+        Ok(())
+    }
+}
+
+/// Every colour used if there are few enough, otherwise the best 255
+struct Palette {
+    colours: Vec<[u8; 4]>,
+    quantiser: Option<NeuQuant>,
+}
+
+impl Palette {
+    fn new(rgba: &[u8]) -> Self {
+        match exact_palette(rgba) {
+            Some(colours) if colours.len() < 256 => Self { colours, quantiser: None },
+            _ => {
+                let quantiser = NeuQuant::new(10, 255, rgba);
+                let colours = quantiser.color_map_rgba().chunks_exact(4).map(|c| [c[0], c[1], c[2], c[3]]).collect();
+                Self { colours, quantiser: Some(quantiser) }
+            }
+        }
+    }
+
+    /// The entry after the colours
+    fn transparent(&self) -> u8 {
+        self.colours.len() as u8
+    }
+
+    /// Tables are a power of two long, this is the power
+    fn bits(&self) -> u8 {
+        let entries = self.colours.len() + 1;
+        (usize::BITS - (entries - 1).leading_zeros()).max(1) as u8
+    }
+
+    fn write_table(&self, out: &mut Vec<u8>) {
+        for c in &self.colours {
+            out.extend(&c[..3]);
+        }
+        out.resize(out.len() + ((1 << self.bits()) - self.colours.len()) * 3, 0);
+    }
+
+    /// Index of each pixel, `kept` ones are left to be made transparent and
+    /// don't take part in dithering
+    fn indices(&self, rgba: &[u8], kept: &[bool], width: usize, dithered: bool) -> Vec<u8> {
+        match &self.quantiser {
+            Some(quantiser) if dithered => dither(rgba, width, quantiser, |i| kept[i]),
+            Some(quantiser) => rgba.chunks_exact(4).map(|px| quantiser.index_of(px) as u8).collect(),
+            None => {
+                let lookup: HashMap<[u8; 4], u8> = self.colours.iter().enumerate().map(|(i, c)| (*c, i as u8)).collect();
+                // unchanged pixels may have colours the palette doesn't, they're made transparent after
+                rgba.chunks_exact(4).map(|px| lookup.get(px).copied().unwrap_or(0)).collect()
+            }
+        }
+    }
+}
+
+fn centiseconds(time: Duration) -> u64 {
+    (time.as_millis() as u64 + 5) / 10
+}
+
+fn lzw(indices: &[u8], code_size: u8) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut out = Vec::with_capacity(indices.len() / 2);
+    LzwEncoder::new(BitOrder::Lsb, code_size)
+        .into_vec(&mut out)
+        .encode_all(indices)
+        .status
+        .map_err(|e| format!("lzw failed : {e}"))?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use half::f16;
+
+    use super::*;
+
+    fn frame(width: u32, height: u32, colour: impl Fn(u32, u32) -> [f32; 3]) -> ScrgbBuffer {
+        let data = (0..width * height)
+            .map(|i| {
+                let [r, g, b] = colour(i % width, i / width);
+                [f16::from_f32(r), f16::from_f32(g), f16::from_f32(b), f16::ONE]
+            })
+            .collect();
+        ScrgbBuffer { width, height, data }
+    }
+
+    fn record(frames: &[ScrgbBuffer], settings: GifSettings) -> Vec<u8> {
+        let (width, height) = (frames[0].width, frames[0].height);
+        let mut recorder = GifRecorder::new(PathBuf::new(), width, height, settings, 80.0);
+        for (i, frame) in frames.iter().enumerate() {
+            recorder.push(frame, Duration::from_millis(i as u64 * 100)).unwrap();
+        }
+        let (pending, start) = recorder.pending.take().unwrap();
+        recorder.keep(pending, start);
+        recorder.encode(Duration::from_millis(frames.len() as u64 * 100)).unwrap()
+    }
+
+    /// Each frame as shown, composited over the ones before, and the loop count
+    fn decode(bytes: &[u8]) -> (Vec<Vec<u8>>, gif::Repeat) {
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::RGBA);
+        let mut decoder = options.read_info(bytes).unwrap();
+        let (width, height) = (decoder.width() as usize, decoder.height() as usize);
+        let mut screen = vec![0; width * height * 4];
+        let mut shown = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            let (left, top, w) = (frame.left as usize, frame.top as usize, frame.width as usize);
+            for (i, px) in frame.buffer.chunks_exact(4).enumerate() {
+                if px[3] == 0 {
+                    continue;
+                }
+                let at = ((top + i / w) * width + left + i % w) * 4;
+                screen[at..at + 4].copy_from_slice(px);
+            }
+            shown.push(screen.clone());
+        }
+        (shown, decoder.repeat())
+    }
+
+    fn rgba(frame: &ScrgbBuffer) -> Vec<u8> {
+        frame.to_sdr(80.0).to_srgba8()
+    }
+
+    #[test]
+    fn decodes_to_what_was_recorded() {
+        let (width, height) = (40, 30);
+        // one colour, so a 1 bit table
+        let plain = frame(width, height, |_, _| [0.0, 0.0, 1.0]);
+        // a red square with a hole that stays blue, so the hole goes transparent
+        let square = frame(width, height, |x, y| {
+            let inside = (10..20).contains(&x) && (5..15).contains(&y) && !(14..16).contains(&x);
+            if inside { [1.0, 0.0, 0.0] } else { [0.0, 0.0, 1.0] }
+        });
+        let stripes = frame(width, height, |x, _| [(x % 3) as f32 / 2.0, 1.0, 0.0]);
+        let frames = [plain, square, stripes];
+
+        for palette in [GifPalette::Local, GifPalette::Global] {
+            let bytes = record(&frames, GifSettings { palette, plays: 3, ..Default::default() });
+            let (shown, repeat) = decode(&bytes);
+            assert_eq!(repeat, gif::Repeat::Finite(2));
+            assert_eq!(shown.len(), frames.len());
+            for (i, (shown, frame)) in shown.iter().zip(&frames).enumerate() {
+                assert!(*shown == rgba(frame), "{palette:?} frame {i}");
+            }
+        }
+    }
+
+    #[test]
+    fn plays_forever_without_a_count() {
+        let frames = [frame(4, 4, |_, _| [1.0; 3]), frame(4, 4, |x, _| [x as f32 / 3.0; 3])];
+        let (_, repeat) = decode(&record(&frames, GifSettings::default()));
+        assert_eq!(repeat, gif::Repeat::Infinite);
+    }
+
+    #[test]
+    fn quantised_frames_stay_close() {
+        let (width, height) = (64, 64);
+        let first = frame(width, height, |_, _| [0.2, 0.2, 0.2]);
+        // far more colours than fit, with a corner left as it was
+        let gradient = frame(width, height, |x, y| {
+            if x < 8 && y < 8 { [0.2, 0.2, 0.2] } else { [x as f32 / 63.0, y as f32 / 63.0, 0.5] }
+        });
+        let frames = [first, gradient];
+        let (shown, _) = decode(&record(&frames, GifSettings::default()));
+
+        let wanted = rgba(&frames[1]);
+        // dithering trades exact pixels for the right colour on average
+        let off = shown[1].iter().zip(&wanted).map(|(a, b)| a.abs_diff(*b) as f64).sum::<f64>() / wanted.len() as f64;
+        assert!(off < 6.0, "off by {off} on average");
+        // the corner came through the transparent index untouched
+        for y in 0..8 {
+            let row = y * width as usize * 4;
+            assert_eq!(shown[1][row..row + 32], wanted[row..row + 32]);
+        }
+    }
+}
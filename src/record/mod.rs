@@ -0,0 +1,108 @@
+//! Recording a region of the screen as a clip.
+//!
+//! Frames come off the duplication as they're presented and go to a
+//! `Recorder` with the time they were shown, which writes the file once
+//! the recording stops.
+
+pub mod apng;
+pub mod archive;
+pub mod external;
+pub mod gif;
+
+use std::{error::Error, path::PathBuf, time::Duration};
+
+use serde::Deserialize;
+
+use crate::pixels::{Rect, ScrgbBuffer};
+
+use self::{apng::ApngSettings, archive::ArchiveSettings, external::ExternalSettings, gif::GifSettings};
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RecordSettings {
+    /// See `template` for the `{variables}` it can use
+    pub path: String,
+    pub format: RecordFormat,
+    /// The screen is checked for a new frame this many times a second
+    pub max_fps: f32,
+    /// Nits SDR white is shown at. Only matters with HDR on, where it should
+    /// match the SDR content brightness in the windows display settings.
+    pub sdr_white: f32,
+}
+
+impl Default for RecordSettings {
+    fn default() -> Self {
+        Self {
+            path: "recording.png".to_string(),
+            format: RecordFormat::Apng(ApngSettings::default()),
+            max_fps: 30.0,
+            sdr_white: 80.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RecordFormat {
+    /// Animated png, lossless and plays in browsers
+    Apng(ApngSettings),
+    /// Plays everywhere, but only 256 colours a frame
+    Gif(GifSettings),
+    /// Streamed to another program as it's recorded, for video
+    External(ExternalSettings),
+    /// Every frame exactly as captured, see `archive`. Opening one steps through it.
+    Archive(ArchiveSettings),
+}
+
+impl RecordFormat {
+    /// `sdr_white` is the nits SDR white was shown at, for the formats that only take SDR
+    pub fn recorder(&self, path: PathBuf, width: u32, height: u32, sdr_white: f32) -> Result<Box<dyn Recorder>, Box<dyn Error>> {
+        Ok(match self {
+            RecordFormat::Apng(settings) => Box::new(apng::ApngRecorder::new(path, width, height, settings.clone(), sdr_white)),
+            RecordFormat::Gif(settings) => Box::new(gif::GifRecorder::new(path, width, height, settings.clone(), sdr_white)),
+            RecordFormat::External(settings) => Box::new(external::ExternalRecorder::new(path, width, height, settings.clone(), sdr_white)?),
+            RecordFormat::Archive(settings) => Box::new(archive::ArchiveRecorder::new(path, width, height, settings.clone())?),
+        })
+    }
+}
+
+pub trait Recorder {
+    /// `time` is how far into the recording the frame was shown
+    fn push(&mut self, frame: &ScrgbBuffer, time: Duration) -> Result<(), Box<dyn Error>>;
+
+    /// The last frame lasts until `end`
+    fn finish(self: Box<Self>, end: Duration) -> Result<(), Box<dyn Error>>;
+}
+
+/// Smallest rectangle holding every pixel that differs between two frames of
+/// packed samples, none when they're the same
+pub fn changed_rect(previous: &[u8], current: &[u8], width: usize, bytes_per_pixel: usize) -> Option<Rect> {
+    let row_len = width * bytes_per_pixel;
+    let (mut top, mut bottom) = (None, 0);
+    let (mut left, mut right) = (width, 0);
+    for (y, (before, after)) in previous.chunks(row_len).zip(current.chunks(row_len)).enumerate() {
+        if before == after {
+            continue;
+        }
+        top.get_or_insert(y);
+        bottom = y + 1;
+        let differs = |(a, b): (&u8, &u8)| a != b;
+        let first = before.iter().zip(after).position(differs).unwrap_or(0);
+        let last = before.iter().zip(after).rposition(differs).unwrap_or(0);
+        left = left.min(first / bytes_per_pixel);
+        right = right.max(last / bytes_per_pixel + 1);
+    }
+    top.map(|top| Rect { left: left as i32, top: top as i32, right: right as i32, bottom: bottom as i32 })
+}
+
+/// Copy of the samples inside `rect`
+pub fn crop_samples(samples: &[u8], width: usize, bytes_per_pixel: usize, rect: Rect) -> Vec<u8> {
+    let row_len = width * bytes_per_pixel;
+    let (left, right) = (rect.left as usize * bytes_per_pixel, rect.right as usize * bytes_per_pixel);
+    samples.chunks(row_len)
+        .skip(rect.top as usize)
+        .take((rect.bottom - rect.top) as usize)
+        .flat_map(|row| &row[left..right])
+        .copied()
+        .collect()
+}
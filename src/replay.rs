@@ -0,0 +1,511 @@
+//! Instant replay, the last stretch of the screen kept in memory so a glitch
+//! can still be saved after it's gone.
+//!
+//! The screen is sampled a few times a second. Every so often the whole output
+//! is kept as a keyframe, in between only the areas the duplication says
+//! changed. Deflating happens on a thread of its own so sampling doesn't wait
+//! on it. Once the buffer holds more than it should, the oldest keyframe goes
+//! along with the samples that build on it.
+
+use std::{
+    collections::VecDeque,
+    error::Error,
+    io::Read,
+    sync::{atomic::{AtomicBool, Ordering}, mpsc::{self, SyncSender, TrySendError}, Arc, Mutex},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use flate2::read::ZlibDecoder;
+use half::f16;
+use serde::Deserialize;
+
+use crate::{
+    encode::deflate::zlib,
+    pixels::{Rect, ScrgbBuffer},
+    record::{apng::ApngSettings, RecordFormat},
+};
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ReplaySettings {
+    /// Keep the buffer from startup, each sample reads back whatever changed
+    pub enabled: bool,
+    /// Seconds kept, up to a keyframe's worth more can be
+    pub seconds: f32,
+    /// Megabytes the compressed samples can take up
+    pub max_memory: u32,
+    /// Samples taken per second, at most
+    pub fps: f32,
+    /// Seconds between keyframes. They're the most work to compress, but the
+    /// buffer can only drop a keyframe at a time.
+    pub keyframe_every: f32,
+    /// See `template` for the `{variables}` it can use
+    pub path: String,
+    pub format: RecordFormat,
+}
+
+impl Default for ReplaySettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            seconds: 30.0,
+            max_memory: 512,
+            fps: 10.0,
+            keyframe_every: 5.0,
+            path: "replay.png".to_string(),
+            format: RecordFormat::Apng(ApngSettings::default()),
+        }
+    }
+}
+
+/// Part of the output, zlib compressed rows of little endian f16 RGBA
+#[derive(Debug, Clone)]
+pub struct Patch {
+    pub rect: Rect,
+    /// Shared between the buffer and the clips taken from it
+    pub data: Arc<Vec<u8>>,
+}
+
+impl Patch {
+    /// `pixels` are the rows inside `rect`, 8 bytes a pixel. `level` is 0 to 9.
+    pub fn compress(rect: Rect, pixels: &[u8], level: u32) -> Result<Self, Box<dyn Error>> {
+        Ok(Self { rect, data: Arc::new(zlib(pixels, level, 0)?) })
+    }
+
+    /// Draw it over `frame`
+    pub fn apply(&self, frame: &mut ScrgbBuffer) -> Result<(), Box<dyn Error>> {
+        let Rect { left, top, right, bottom } = self.rect;
+        if left < 0 || top < 0 || left >= right || top >= bottom || right as u32 > frame.width || bottom as u32 > frame.height {
+            return Err(format!("patch {:?} isn't inside the {}x{} frame", self.rect, frame.width, frame.height).into());
+        }
+        let width = (right - left) as usize;
+        let expected = width * (bottom - top) as usize * 8;
+        let mut bytes = Vec::with_capacity(expected);
+        // one byte over is enough to tell it's too long
+        ZlibDecoder::new(&self.data[..]).take(expected as u64 + 1).read_to_end(&mut bytes)?;
+        if bytes.len() != expected {
+            return Err(format!("patch {:?} has {} bytes, expected {}", self.rect, bytes.len(), expected).into());
+        }
+
+        for (y, row) in (top as usize..).zip(bytes.chunks_exact(width * 8)) {
+            let start = y * frame.width as usize + left as usize;
+            for (px, sample) in frame.data[start..start + width].iter_mut().zip(row.chunks_exact(8)) {
+                *px = std::array::from_fn(|c| f16::from_le_bytes([sample[c * 2], sample[c * 2 + 1]]));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One sample of the screen, a keyframe covers the whole output
+#[derive(Debug, Clone)]
+pub struct Sample {
+    /// Performance counter ticks when it was presented
+    pub present_time: i64,
+    pub key: bool,
+    pub patches: Vec<Patch>,
+}
+
+/// What's sampled, before it's compressed
+struct Raw {
+    present_time: i64,
+    key: bool,
+    /// Rows inside each area, 8 bytes a pixel
+    areas: Vec<(Rect, Vec<u8>)>,
+}
+
+struct Ring {
+    samples: VecDeque<Sample>,
+    bytes: usize,
+}
+
+pub struct Replay {
+    width: u32,
+    height: u32,
+    frequency: i64,
+    keyframe_ticks: i64,
+    sender: Option<SyncSender<Raw>>,
+    worker: Option<JoinHandle<()>>,
+    ring: Arc<Mutex<Ring>>,
+    /// Set when a sample couldn't be kept, or the samples since the last keyframe
+    /// take up too much, so the next has to be a keyframe
+    want_key: Arc<AtomicBool>,
+    last_key: Option<i64>,
+}
+
+/// Samples waiting to be compressed, past this they're dropped
+const QUEUE: usize = 4;
+
+impl Replay {
+    /// `frequency` is the performance counter ticks per second the samples are timed in
+    pub fn new(settings: &ReplaySettings, width: u32, height: u32, frequency: i64) -> Result<Self, Box<dyn Error>> {
+        for (name, value) in [("seconds", settings.seconds), ("fps", settings.fps), ("keyframe_every", settings.keyframe_every)] {
+            if value.is_nan() || value <= 0.0 {
+                return Err(format!("replay {name} has to be above 0, not {value}").into());
+            }
+        }
+        let keep_ticks = (settings.seconds as f64 * frequency as f64) as i64;
+        let max_bytes = settings.max_memory as usize * 1024 * 1024;
+        let ring = Arc::new(Mutex::new(Ring { samples: VecDeque::new(), bytes: 0 }));
+        let want_key = Arc::new(AtomicBool::new(false));
+
+        let (sender, receiver) = mpsc::sync_channel::<Raw>(QUEUE);
+        let worker = {
+            let (ring, want_key) = (ring.clone(), want_key.clone());
+            std::thread::spawn(move || {
+                // the group since the last keyframe can't be dropped, so it gets a share of the memory
+                let mut group_bytes = 0;
+                for raw in receiver {
+                    let patches: Result<Vec<Patch>, _> = raw.areas.iter().map(|(rect, pixels)| Patch::compress(*rect, pixels, 1)).collect();
+                    let patches = match patches {
+                        Ok(patches) => patches,
+                        Err(e) => {
+                            debug!("Couldn't compress a replay sample : {:?}", e);
+                            want_key.store(true, Ordering::Relaxed);
+                            continue;
+                        }
+                    };
+                    let bytes: usize = patches.iter().map(|p| p.data.len()).sum();
+                    group_bytes = if raw.key { bytes } else { group_bytes + bytes };
+                    if group_bytes > max_bytes / 4 {
+                        want_key.store(true, Ordering::Relaxed);
+                    }
+
+                    let mut ring = ring.lock().unwrap();
+                    if !raw.key && ring.samples.is_empty() {
+                        continue;
+                    }
+                    ring.samples.push_back(Sample { present_time: raw.present_time, key: raw.key, patches });
+                    ring.bytes += bytes;
+                    ring.evict(keep_ticks, max_bytes);
+                }
+            })
+        };
+
+        Ok(Self {
+            width,
+            height,
+            frequency,
+            keyframe_ticks: (settings.keyframe_every as f64 * frequency as f64) as i64,
+            sender: Some(sender),
+            worker: Some(worker),
+            ring,
+            want_key,
+            last_key: None,
+        })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Whether a sample presented at `present_time` has to cover the whole output
+    pub fn needs_keyframe(&self, present_time: i64) -> bool {
+        self.want_key.load(Ordering::Relaxed) || self.last_key.is_none_or(|last| present_time - last >= self.keyframe_ticks)
+    }
+
+    /// Keep a sample, `areas` are what changed since the one before with the rows
+    /// inside each, or the whole output for a keyframe
+    pub fn push(&mut self, present_time: i64, key: bool, areas: Vec<(Rect, Vec<u8>)>) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        match sender.try_send(Raw { present_time, key, areas }) {
+            Ok(()) => if key {
+                self.last_key = Some(present_time);
+                self.want_key.store(false, Ordering::Relaxed);
+            },
+            // everything after builds on this one, so start again from a keyframe
+            Err(TrySendError::Full(_)) => {
+                debug!("Replay compression fell behind, skipping a sample");
+                self.want_key.store(true, Ordering::Relaxed);
+            },
+            Err(TrySendError::Disconnected(_)) => debug!("Replay compression stopped"),
+        }
+    }
+
+    /// The samples held right now
+    pub fn clip(&self) -> Clip {
+        let ring = self.ring.lock().unwrap();
+        Clip {
+            width: self.width,
+            height: self.height,
+            frequency: self.frequency,
+            samples: ring.samples.iter().cloned().collect(),
+        }
+    }
+}
+
+impl Drop for Replay {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Ring {
+    /// Drop the oldest keyframe and what builds on it while what's left is enough
+    fn evict(&mut self, keep_ticks: i64, max_bytes: usize) {
+        while let Some(next_key) = self.samples.iter().skip(1).position(|s| s.key).map(|i| i + 1) {
+            let newest = self.samples.back().map_or(0, |s| s.present_time);
+            let enough_time = newest - self.samples[next_key].present_time >= keep_ticks;
+            if !enough_time && self.bytes <= max_bytes {
+                break;
+            }
+            for sample in self.samples.drain(..next_key) {
+                self.bytes -= sample.patches.iter().map(|p| p.data.len()).sum::<usize>();
+            }
+        }
+    }
+}
+
+/// Frames that can be stepped through, from a replay or an archive
+pub trait Timeline {
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// How far in frame `i` was shown
+    fn time(&self, i: usize) -> Duration;
+
+    fn frame(&mut self, i: usize) -> Result<ScrgbBuffer, Box<dyn Error>>;
+}
+
+/// Samples taken from a replay, they can outlive it
+pub struct Clip {
+    pub width: u32,
+    pub height: u32,
+    frequency: i64,
+    samples: Vec<Sample>,
+}
+
+impl Clip {
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// How far into the clip sample `i` was shown
+    pub fn time(&self, i: usize) -> Duration {
+        let ticks = self.samples[i].present_time - self.samples[0].present_time;
+        Duration::from_secs_f64(ticks.max(0) as f64 / self.frequency as f64)
+    }
+
+    /// Sample `i`, built up from the keyframe before it
+    pub fn frame(&self, i: usize) -> Result<ScrgbBuffer, Box<dyn Error>> {
+        let key = self.samples[..=i].iter().rposition(|s| s.key).ok_or("replay doesn't start with a keyframe")?;
+        let mut frame = self.blank();
+        for sample in &self.samples[key..=i] {
+            for patch in &sample.patches {
+                patch.apply(&mut frame)?;
+            }
+        }
+        Ok(frame)
+    }
+
+    /// Every sample in order with its time, each built on the one before
+    pub fn for_each(&self, mut f: impl FnMut(&ScrgbBuffer, Duration) -> Result<(), Box<dyn Error>>) -> Result<(), Box<dyn Error>> {
+        let mut frame = self.blank();
+        for (i, sample) in self.samples.iter().enumerate() {
+            for patch in &sample.patches {
+                patch.apply(&mut frame)?;
+            }
+            f(&frame, self.time(i))?;
+        }
+        Ok(())
+    }
+
+    fn blank(&self) -> ScrgbBuffer {
+        ScrgbBuffer { width: self.width, height: self.height, data: vec![[f16::ZERO; 4]; self.width as usize * self.height as usize] }
+    }
+}
+
+impl Timeline for Clip {
+    fn len(&self) -> usize {
+        Clip::len(self)
+    }
+
+    fn time(&self, i: usize) -> Duration {
+        Clip::time(self, i)
+    }
+
+    fn frame(&mut self, i: usize) -> Result<ScrgbBuffer, Box<dyn Error>> {
+        Clip::frame(self, i)
+    }
+}
+
+/// The areas to read back for a sample, clipped to the output. Lots of little
+/// ones are read as the one area around them all.
+pub fn sample_areas<'a>(changed: impl Iterator<Item = &'a Rect>, width: u32, height: u32) -> Vec<Rect> {
+    let mut areas: Vec<Rect> = changed
+        .map(|r| Rect { left: r.left.max(0), top: r.top.max(0), right: r.right.min(width as i32), bottom: r.bottom.min(height as i32) })
+        .filter(|r| r.left < r.right && r.top < r.bottom)
+        .collect();
+    // a move and a dirty rect can cover the same area without being next to each other
+    areas.sort_by_key(|r| (r.top, r.left, r.bottom, r.right));
+    areas.dedup();
+    if areas.len() > 32 {
+        let around = areas.iter().fold(areas[0], |a, r| Rect {
+            left: a.left.min(r.left),
+            top: a.top.min(r.top),
+            right: a.right.max(r.right),
+            bottom: a.bottom.max(r.bottom),
+        });
+        areas = vec![around];
+    }
+    areas
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(left: i32, top: i32, right: i32, bottom: i32) -> Rect {
+        Rect { left, top, right, bottom }
+    }
+
+    /// A sample at `present_time` whose patches take up `bytes`, for the ring's bookkeeping
+    fn sized(present_time: i64, key: bool, bytes: usize) -> Sample {
+        let patch = Patch { rect: rect(0, 0, 1, 1), data: Arc::new(vec![0; bytes]) };
+        Sample { present_time, key, patches: vec![patch] }
+    }
+
+    fn ring(samples: Vec<Sample>) -> Ring {
+        let bytes = samples.iter().flat_map(|s| &s.patches).map(|p| p.data.len()).sum();
+        Ring { samples: samples.into(), bytes }
+    }
+
+    fn times(ring: &Ring) -> Vec<i64> {
+        ring.samples.iter().map(|s| s.present_time).collect()
+    }
+
+    #[test]
+    fn evicts_whole_groups_by_time() {
+        // keyframes at 0, 30 and 60, a sample every 10
+        let samples = (0..8).map(|i| sized(i * 10, i % 3 == 0, 10)).collect();
+        let mut ring = ring(samples);
+
+        // 40 ticks wanted, the newest is 70 so the group from 30 still has to stay
+        ring.evict(40, usize::MAX);
+        assert_eq!(times(&ring), [30, 40, 50, 60, 70]);
+        assert_eq!(ring.bytes, 50);
+
+        // the newest group is never dropped, however little is wanted
+        ring.evict(0, usize::MAX);
+        assert_eq!(times(&ring), [60, 70]);
+        ring.evict(0, 0);
+        assert_eq!(times(&ring), [60, 70]);
+    }
+
+    #[test]
+    fn evicts_whole_groups_by_bytes() {
+        let samples = vec![
+            sized(0, true, 100), sized(1, false, 10),
+            sized(2, true, 100), sized(3, false, 10),
+            sized(4, true, 100), sized(5, false, 10),
+        ];
+        let mut ring = ring(samples);
+
+        // plenty of time wanted, but only room for two groups
+        ring.evict(1000, 250);
+        assert_eq!(times(&ring), [2, 3, 4, 5]);
+        assert_eq!(ring.bytes, 220);
+        ring.evict(1000, 220);
+        assert_eq!(times(&ring), [2, 3, 4, 5]);
+    }
+
+    fn gradient(width: u32, height: u32, seed: f32) -> ScrgbBuffer {
+        let data = (0..width * height)
+            .map(|i| [f16::from_f32(i as f32 * 0.01 + seed), f16::from_f32(seed), f16::from_f32(-0.5), f16::ONE])
+            .collect();
+        ScrgbBuffer { width, height, data }
+    }
+
+    /// The rows of `frame` inside `rect`, packed the way patches hold them
+    fn pixels(frame: &ScrgbBuffer, rect: Rect) -> Vec<u8> {
+        frame.crop(rect).unwrap().to_le_bytes()
+    }
+
+    #[test]
+    fn rebuilds_frames_from_keyframe_and_deltas() {
+        let (width, height) = (24, 16);
+        let whole = rect(0, 0, width as i32, height as i32);
+        let mut frames = vec![gradient(width, height, 0.0)];
+        let mut samples = vec![Sample { present_time: 0, key: true, patches: vec![Patch::compress(whole, &pixels(&frames[0], whole), 1).unwrap()] }];
+
+        // each step paints a couple of areas from another image over the last frame
+        let areas = [
+            vec![rect(0, 0, 4, 4)],
+            vec![rect(10, 2, 24, 5), rect(3, 10, 7, 16)],
+            vec![rect(0, 0, 24, 1)],
+        ];
+        for (i, changed) in areas.iter().enumerate() {
+            let source = gradient(width, height, i as f32 + 1.0);
+            let mut frame = frames.last().unwrap().clone();
+            let mut patches = Vec::new();
+            for area in changed {
+                let patch = Patch::compress(*area, &pixels(&source, *area), 1).unwrap();
+                patch.apply(&mut frame).unwrap();
+                patches.push(patch);
+            }
+            // applied on its own, the patch puts exactly the source's pixels there
+            for area in changed {
+                assert_eq!(frame.crop(*area).unwrap(), source.crop(*area).unwrap());
+            }
+            frames.push(frame);
+            samples.push(Sample { present_time: (i as i64 + 1) * 500, key: false, patches });
+        }
+        // a second keyframe partway, later frames only build on it
+        let key = gradient(width, height, 9.0);
+        samples.push(Sample { present_time: 2500, key: true, patches: vec![Patch::compress(whole, &pixels(&key, whole), 1).unwrap()] });
+        frames.push(key);
+
+        let clip = Clip { width, height, frequency: 1000, samples };
+        for (i, frame) in frames.iter().enumerate() {
+            assert!(clip.frame(i).unwrap() == *frame, "frame {i}");
+        }
+        assert_eq!(clip.time(2), Duration::from_secs(1));
+
+        let mut walked = Vec::new();
+        clip.for_each(|frame, _| {
+            walked.push(frame.clone());
+            Ok(())
+        }).unwrap();
+        assert!(walked == frames);
+    }
+
+    #[test]
+    fn rejects_bad_patches() {
+        let mut frame = gradient(8, 8, 0.0);
+        let area = rect(0, 0, 4, 4);
+        // more pixels than the rect holds
+        let long = Patch::compress(area, &pixels(&frame, rect(0, 0, 8, 8)), 1).unwrap();
+        assert!(long.apply(&mut frame).is_err());
+        let short = Patch::compress(area, &pixels(&frame, rect(0, 0, 4, 2)), 1).unwrap();
+        assert!(short.apply(&mut frame).is_err());
+        let outside = Patch::compress(rect(6, 6, 10, 10), &[0; 128], 1).unwrap();
+        assert!(outside.apply(&mut frame).is_err());
+    }
+
+    #[test]
+    fn sample_areas_clip_and_merge() {
+        let changed = [rect(-5, -5, 10, 10), rect(95, 40, 120, 60), rect(200, 0, 210, 10), rect(-5, -5, 10, 10)];
+        let areas = sample_areas(changed.iter(), 100, 50);
+        assert_eq!(areas, [rect(0, 0, 10, 10), rect(95, 40, 100, 50)]);
+
+        let many: Vec<Rect> = (0..40).map(|i| rect(i * 2, i, i * 2 + 1, i + 1)).collect();
+        assert_eq!(sample_areas(many.iter(), 100, 50), [rect(0, 0, 79, 40)]);
+    }
+}
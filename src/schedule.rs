@@ -0,0 +1,265 @@
+//! Captures taken by themselves, every so often or on a cron schedule.
+
+use std::{error::Error, time::Duration};
+
+use chrono::{DateTime, Datelike, Local, NaiveDateTime, Timelike};
+use serde::Deserialize;
+
+use crate::record::{apng::ApngSettings, RecordFormat};
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ScheduleSettings {
+    /// Seconds between captures, the first is taken straight away
+    pub every: f32,
+    /// Capture on a cron schedule instead, `minute hour day month weekday` in local
+    /// time. Each field is `*`, a number, a range `a-b`, either with a step like `*/5`,
+    /// or a comma separated list of those. Weekdays go from 0 for Sunday. The first
+    /// capture waits until the schedule says.
+    pub cron: Option<String>,
+    pub area: ScheduleArea,
+    /// Stop after this many captures, 0 keeps going until stopped
+    pub count: u64,
+    /// Start as soon as the program does
+    pub autostart: bool,
+    /// Also put every capture into one clip, written once the schedule stops
+    pub timelapse: Option<Timelapse>,
+}
+
+impl Default for ScheduleSettings {
+    fn default() -> Self {
+        Self { every: 60.0, cron: None, area: ScheduleArea::Screen, count: 0, autostart: false, timelapse: None }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ScheduleArea {
+    /// The whole monitor being captured
+    Screen,
+    /// Whatever was selected last, the whole monitor if nothing was
+    Selection,
+    /// In pixels from the top left of the monitor
+    Region { left: i32, top: i32, width: u32, height: u32 },
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Timelapse {
+    /// See `template` for the `{variables}` it can use
+    pub path: String,
+    pub format: RecordFormat,
+    /// Captures shown per second
+    pub fps: f32,
+}
+
+impl Default for Timelapse {
+    fn default() -> Self {
+        Self { path: "timelapse.png".to_string(), format: RecordFormat::Apng(ApngSettings::default()), fps: 10.0 }
+    }
+}
+
+pub enum When {
+    Every(Duration),
+    Cron(Cron),
+}
+
+impl When {
+    pub fn from_settings(settings: &ScheduleSettings) -> Result<Self, Box<dyn Error>> {
+        match &settings.cron {
+            Some(cron) => Ok(When::Cron(Cron::parse(cron)?)),
+            None if settings.every.is_finite() && settings.every > 0.0 => Ok(When::Every(Duration::from_secs_f32(settings.every))),
+            None => Err(format!("schedule every has to be above 0, not {}", settings.every).into()),
+        }
+    }
+
+    /// When the first capture is due, straight away unless it's on a cron schedule
+    pub fn first(&self, now: DateTime<Local>) -> Result<DateTime<Local>, Box<dyn Error>> {
+        match self {
+            When::Every(_) => Ok(now),
+            When::Cron(cron) => cron.next_after(now).ok_or_else(|| "cron schedule never comes round".into()),
+        }
+    }
+
+    /// The capture after one due at `last`, never before `now`
+    pub fn next(&self, last: DateTime<Local>, now: DateTime<Local>) -> Result<DateTime<Local>, Box<dyn Error>> {
+        match self {
+            // counted from when it was due so it doesn't drift, unless it fell behind
+            When::Every(every) => Ok((last + chrono::Duration::from_std(*every)?).max(now)),
+            When::Cron(_) => self.first(now),
+        }
+    }
+}
+
+/// A parsed cron line, each field a mask of the values it matches
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// When both days and weekdays are given cron matches either, not both
+    any_day: bool,
+}
+
+impl Cron {
+    pub fn parse(line: &str) -> Result<Self, Box<dyn Error>> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!("cron needs 5 fields, minute hour day month weekday, not {line:?}").into());
+        };
+        let mut weekday_mask = field(weekdays, 0, 7)?;
+        // 7 is Sunday as well
+        if weekday_mask & 1 << 7 != 0 {
+            weekday_mask |= 1;
+        }
+        Ok(Self {
+            minutes: field(minutes, 0, 59)?,
+            hours: field(hours, 0, 23)?,
+            days: field(days, 1, 31)?,
+            months: field(months, 1, 12)?,
+            weekdays: weekday_mask,
+            any_day: !days.starts_with('*') && !weekdays.starts_with('*'),
+        })
+    }
+
+    fn matches(&self, time: &NaiveDateTime) -> bool {
+        let has = |mask: u64, v: u32| mask & 1 << v != 0;
+        let day = has(self.days, time.day());
+        let weekday = has(self.weekdays, time.weekday().num_days_from_sunday());
+        has(self.minutes, time.minute())
+            && has(self.hours, time.hour())
+            && has(self.months, time.month())
+            && if self.any_day { day || weekday } else { day && weekday }
+    }
+
+    /// First whole minute after `time` that matches, looking up to a few years ahead.
+    /// Times skipped by a clock change are passed over.
+    pub fn next_after(&self, time: DateTime<Local>) -> Option<DateTime<Local>> {
+        let mut next = time.naive_local().with_second(0)?.with_nanosecond(0)? + chrono::Duration::minutes(1);
+        // enough for the 29th of February on a given weekday
+        for _ in 0..60 * 24 * 366 * 8 {
+            if self.matches(&next) {
+                if let Some(local) = next.and_local_timezone(Local).earliest() {
+                    return Some(local);
+                }
+            }
+            next += chrono::Duration::minutes(1);
+        }
+        None
+    }
+}
+
+/// One cron field as a mask of the values from `min` to `max` it covers
+fn field(text: &str, min: u32, max: u32) -> Result<u64, Box<dyn Error>> {
+    let number = |s: &str| -> Result<u32, Box<dyn Error>> {
+        let v: u32 = s.parse().map_err(|_| format!("{s:?} isn't a number in cron field {text:?}"))?;
+        if !(min..=max).contains(&v) {
+            return Err(format!("{v} is outside {min}-{max} in cron field {text:?}").into());
+        }
+        Ok(v)
+    };
+
+    let mut mask = 0;
+    for part in text.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|s| *s > 0).ok_or_else(|| format!("bad step in cron field {text:?}"))?),
+            None => (part, 1),
+        };
+        let (from, to) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((from, to)) => (number(from)?, number(to)?),
+                // a single value with a step runs to the end, like `5/15`
+                None if step > 1 => (number(range)?, max),
+                None => (number(range)?, number(range)?),
+            },
+        };
+        if from > to {
+            return Err(format!("range goes backwards in cron field {text:?}").into());
+        }
+        for v in (from..=to).step_by(step as usize) {
+            mask |= 1 << v;
+        }
+    }
+    Ok(mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn at(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(year, month, day, hour, minute, 0).unwrap()
+    }
+
+    fn next(line: &str, after: DateTime<Local>) -> DateTime<Local> {
+        Cron::parse(line).unwrap().next_after(after).unwrap()
+    }
+
+    #[test]
+    fn steps_and_ranges() {
+        assert_eq!(next("*/15 * * * *", at(2024, 1, 3, 10, 7)), at(2024, 1, 3, 10, 15));
+        // always after, even on a matching minute
+        assert_eq!(next("*/15 * * * *", at(2024, 1, 3, 10, 15)), at(2024, 1, 3, 10, 30));
+        // a single value with a step runs to the end of the field
+        assert_eq!(next("5/20 * * * *", at(2024, 1, 3, 10, 26)), at(2024, 1, 3, 10, 45));
+        assert_eq!(next("0 9-17/4 * * *", at(2024, 1, 3, 13, 30)), at(2024, 1, 3, 17, 0));
+        assert_eq!(next("0 9-17/4 * * *", at(2024, 1, 3, 17, 30)), at(2024, 1, 4, 9, 0));
+        assert_eq!(next("30 1,3,22 * * *", at(2024, 1, 3, 2, 0)), at(2024, 1, 3, 3, 30));
+        assert_eq!(next("0 0 1 */6 *", at(2024, 2, 10, 0, 0)), at(2024, 7, 1, 0, 0));
+    }
+
+    #[test]
+    fn seven_is_sunday() {
+        // the 3rd of January 2024 was a Wednesday
+        assert_eq!(next("0 12 * * 7", at(2024, 1, 3, 0, 0)), at(2024, 1, 7, 12, 0));
+        assert_eq!(next("0 12 * * 0", at(2024, 1, 3, 0, 0)), at(2024, 1, 7, 12, 0));
+        assert_eq!(next("0 12 * * 6-7", at(2024, 1, 6, 13, 0)), at(2024, 1, 7, 12, 0));
+    }
+
+    #[test]
+    fn day_or_weekday() {
+        // both given, either will do: Friday the 5th comes before the 13th
+        assert_eq!(next("0 0 13 * 5", at(2024, 1, 1, 0, 0)), at(2024, 1, 5, 0, 0));
+        assert_eq!(next("0 0 13 * 5", at(2024, 1, 12, 0, 0)), at(2024, 1, 13, 0, 0));
+        // only one given, that one has to match
+        assert_eq!(next("0 0 13 * *", at(2024, 1, 1, 0, 0)), at(2024, 1, 13, 0, 0));
+        assert_eq!(next("0 0 * * 5", at(2024, 1, 6, 0, 0)), at(2024, 1, 12, 0, 0));
+        // weekdays only, from a Sunday afternoon
+        assert_eq!(next("0 9 * * 1-5", at(2024, 1, 7, 15, 0)), at(2024, 1, 8, 9, 0));
+    }
+
+    #[test]
+    fn leap_days_and_impossible_dates() {
+        assert_eq!(next("0 0 29 2 *", at(2025, 1, 1, 0, 0)), at(2028, 2, 29, 0, 0));
+        assert!(Cron::parse("0 0 30 2 *").unwrap().next_after(at(2024, 1, 1, 0, 0)).is_none());
+    }
+
+    #[test]
+    fn rejects_bad_fields() {
+        for line in [
+            "", "* * * *", "* * * * * *", "60 * * * *", "* 24 * * *", "* * 0 * *", "* * 32 * *",
+            "* * * 0 *", "* * * 13 *", "* * * * 8", "*/0 * * * *", "5-1 * * * *", "a * * * *",
+            "1- * * * *", "*/x * * * *", ",5 * * * *",
+        ] {
+            assert!(Cron::parse(line).is_err(), "{line:?}");
+        }
+    }
+
+    #[test]
+    fn first_capture() {
+        let now = at(2024, 1, 7, 15, 0);
+        let every = When::from_settings(&ScheduleSettings::default()).unwrap();
+        assert_eq!(every.first(now).unwrap(), now);
+
+        let settings = ScheduleSettings { cron: Some("0 9 * * 1-5".to_string()), ..Default::default() };
+        let cron = When::from_settings(&settings).unwrap();
+        assert_eq!(cron.first(now).unwrap(), at(2024, 1, 8, 9, 0));
+
+        let settings = ScheduleSettings { cron: Some("0 0 30 2 *".to_string()), ..Default::default() };
+        assert!(When::from_settings(&settings).unwrap().first(now).is_err());
+    }
+}
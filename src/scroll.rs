@@ -0,0 +1,231 @@
+//! Stitching a region captured while it scrolls into one tall image.
+//!
+//! Each frame is lined up with the one before by matching rows. Rows that stay
+//! put at the top and bottom, like sticky headers and footers, aren't part of
+//! what scrolls, so they're left out of the matching and only kept once.
+
+use std::{collections::hash_map::DefaultHasher, error::Error, hash::{Hash, Hasher}};
+
+use half::f16;
+use serde::Deserialize;
+
+use crate::pixels::ScrgbBuffer;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ScrollSettings {
+    /// Wheel notches to scroll the region by each step, 0 leaves the scrolling to you.
+    /// Scrolling by itself stops once the end is reached.
+    pub auto_scroll: u32,
+    /// Milliseconds between looking for new frames, and between steps when scrolling by itself
+    pub interval: u32,
+    /// Tallest the stitched image gets, direct3d can't go past 16384
+    pub max_height: u32,
+}
+
+impl Default for ScrollSettings {
+    fn default() -> Self {
+        Self { auto_scroll: 0, interval: 150, max_height: 16384 }
+    }
+}
+
+/// Share of the rows with detail in the overlap that have to match, a few
+/// can differ for things like a blinking caret
+const MATCH_THRESHOLD: f32 = 0.9;
+/// Rows with detail the overlap needs before a match is believed
+const MIN_DETAIL_ROWS: usize = 8;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Row {
+    hash: u64,
+    /// One colour all the way across, these match anywhere so they don't count
+    blank: bool,
+}
+
+pub struct Stitcher {
+    width: u32,
+    max_height: u32,
+    /// Everything so far, it always ends with the newest frame
+    image: Vec<[f16; 4]>,
+    previous: Vec<Row>,
+    full: bool,
+}
+
+impl Stitcher {
+    pub fn new(first: &ScrgbBuffer, max_height: u32) -> Self {
+        let mut image = first.data.clone();
+        image.truncate(max_height as usize * first.width as usize);
+        Self { width: first.width, max_height, image, previous: rows(first), full: first.height >= max_height }
+    }
+
+    /// Add what scrolled into view since the last frame, returns how many rows that was
+    pub fn push(&mut self, frame: &ScrgbBuffer) -> Result<u32, Box<dyn Error>> {
+        if frame.width != self.width || frame.height as usize != self.previous.len() {
+            return Err("frame size changed while scrolling".into());
+        }
+        if self.full {
+            return Ok(0);
+        }
+        let rows = rows(frame);
+        let height = rows.len();
+        let top = rows.iter().zip(&self.previous).take_while(|(a, b)| a == b).count();
+        if top == height {
+            return Ok(0);
+        }
+        let bottom = rows.iter().rev().zip(self.previous.iter().rev()).take_while(|(a, b)| a == b).count();
+
+        let Some(shift) = find_shift(&self.previous[top..height - bottom], &rows[top..height - bottom]) else {
+            // scrolled back up or too far in one go, carry on from the last frame that fit
+            debug!("Couldn't line up a frame with the one before, skipping it");
+            return Ok(0);
+        };
+
+        // the old footer comes off the end, the new rows and the footer again go on
+        let width = self.width as usize;
+        self.image.truncate(self.image.len() - bottom * width);
+        self.image.extend_from_slice(&frame.data[(height - bottom - shift) * width..]);
+        self.previous = rows;
+
+        let limit = self.max_height as usize * width;
+        if self.image.len() >= limit {
+            self.image.truncate(limit);
+            self.full = true;
+            debug!("Stitched image reached {} rows, stopping there", self.max_height);
+        }
+        Ok(shift as u32)
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.full
+    }
+
+    pub fn finish(self) -> ScrgbBuffer {
+        ScrgbBuffer { width: self.width, height: (self.image.len() / self.width as usize) as u32, data: self.image }
+    }
+}
+
+fn rows(frame: &ScrgbBuffer) -> Vec<Row> {
+    frame.data.chunks_exact(frame.width as usize)
+        .map(|row| {
+            let mut hasher = DefaultHasher::new();
+            for px in row {
+                px.map(f16::to_bits).hash(&mut hasher);
+            }
+            Row { hash: hasher.finish(), blank: row.iter().all(|px| px.map(f16::to_bits) == row[0].map(f16::to_bits)) }
+        })
+        .collect()
+}
+
+/// How many rows `after` moved up from `before`. Picks the best match, with
+/// the biggest overlap when several are as good.
+fn find_shift(before: &[Row], after: &[Row]) -> Option<usize> {
+    let mut best: Option<(f32, usize)> = None;
+    for shift in 1..before.len() {
+        let (mut detail, mut matched) = (0, 0);
+        for (a, b) in before[shift..].iter().zip(after) {
+            if a.blank && b.blank && a == b {
+                continue;
+            }
+            detail += 1;
+            matched += (a == b) as usize;
+        }
+        if detail < MIN_DETAIL_ROWS {
+            continue;
+        }
+        let score = matched as f32 / detail as f32;
+        if score >= MATCH_THRESHOLD && best.is_none_or(|(best, _)| score > best) {
+            best = Some((score, shift));
+        }
+    }
+    best.map(|(_, shift)| shift)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: u32 = 16;
+    const HEADER: usize = 3;
+    const FOOTER: usize = 2;
+    const VIEW: usize = 30;
+
+    /// A row no other row looks like
+    fn row(seed: usize) -> Vec<[f16; 4]> {
+        (0..WIDTH as usize)
+            .map(|x| [f16::from_f32(((seed * 31 + x * 7) % 997) as f32), f16::from_f32(seed as f32), f16::ZERO, f16::ONE])
+            .collect()
+    }
+
+    /// The page scrolled down `offset` rows, between a header and a footer that stay put
+    fn view(offset: usize) -> ScrgbBuffer {
+        let data = (0..HEADER).map(|i| row(10_000 + i))
+            .chain((offset..offset + VIEW - HEADER - FOOTER).map(row))
+            .chain((0..FOOTER).map(|i| row(20_000 + i)))
+            .flatten()
+            .collect();
+        ScrgbBuffer { width: WIDTH, height: VIEW as u32, data }
+    }
+
+    /// What a stitched image of the page down to `offset` should be
+    fn page(offset: usize) -> ScrgbBuffer {
+        let data: Vec<_> = (0..HEADER).map(|i| row(10_000 + i))
+            .chain((0..offset + VIEW - HEADER - FOOTER).map(row))
+            .chain((0..FOOTER).map(|i| row(20_000 + i)))
+            .flatten()
+            .collect();
+        ScrgbBuffer { width: WIDTH, height: (data.len() / WIDTH as usize) as u32, data }
+    }
+
+    #[test]
+    fn keeps_header_and_footer_once() {
+        let mut stitcher = Stitcher::new(&view(0), 16384);
+        assert_eq!(stitcher.push(&view(10)).unwrap(), 10);
+        assert_eq!(stitcher.push(&view(12)).unwrap(), 2);
+        assert_eq!(stitcher.push(&view(20)).unwrap(), 8);
+        assert!(stitcher.finish() == page(20));
+    }
+
+    #[test]
+    fn nothing_scrolled_adds_nothing() {
+        let mut stitcher = Stitcher::new(&view(0), 16384);
+        assert_eq!(stitcher.push(&view(0)).unwrap(), 0);
+        assert_eq!(stitcher.push(&view(5)).unwrap(), 5);
+        assert_eq!(stitcher.push(&view(5)).unwrap(), 0);
+        assert!(stitcher.finish() == page(5));
+    }
+
+    #[test]
+    fn frames_that_dont_line_up_are_skipped() {
+        let mut stitcher = Stitcher::new(&view(0), 16384);
+        // further than a whole view in one go, nothing overlaps
+        assert_eq!(stitcher.push(&view(100)).unwrap(), 0);
+        assert_eq!(stitcher.push(&view(6)).unwrap(), 6);
+        assert!(stitcher.finish() == page(6));
+    }
+
+    #[test]
+    fn stops_at_max_height() {
+        let max_height = 55;
+        let mut stitcher = Stitcher::new(&view(0), max_height);
+        for offset in (10..100).step_by(10) {
+            stitcher.push(&view(offset)).unwrap();
+        }
+        assert!(stitcher.is_full());
+        assert_eq!(stitcher.push(&view(110)).unwrap(), 0);
+
+        let image = stitcher.finish();
+        assert_eq!(image.height, max_height);
+        // the top is the page as far as it got
+        let expected = page(100);
+        assert!(image.data[..] == expected.data[..image.data.len()]);
+    }
+
+    #[test]
+    fn rejects_a_different_size() {
+        let mut stitcher = Stitcher::new(&view(0), 16384);
+        let mut smaller = view(1);
+        smaller.height -= 1;
+        smaller.data.truncate(smaller.data.len() - WIDTH as usize);
+        assert!(stitcher.push(&smaller).is_err());
+    }
+}
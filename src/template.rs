@@ -0,0 +1,173 @@
+//! `{variable}` substitution for output file names and text stamps.
+//!
+//! | variable       | expands to                                   |
+//! |----------------|----------------------------------------------|
+//! | `{date}`       | `2024-01-31`                                 |
+//! | `{time}`       | `13-45-07`, safe to use in a file name       |
+//! | `{now:FORMAT}` | the time with a strftime `FORMAT`            |
+//! | `{machine}`    | computer name                                |
+//! | `{user}`       | logged in user                               |
+//! | `{build}`      | the `BUILD_NUMBER` environment variable      |
+//! | `{env:NAME}`   | any environment variable                     |
+//! | `{width}`      | width of the capture                         |
+//! | `{height}`     | height of the capture                        |
+//! | `{seq}`        | number from a schedule or watch, from 1      |
+//! | `{seq:N}`      | the same padded with zeros to `N` digits     |
+//!
+//! `{{` and `}}` give literal braces. Numbered captures saved to a file name
+//! without `{seq}` get `-{seq}` added before the extension.
+
+use std::{borrow::Cow, error::Error};
+
+use chrono::{format::{Item, StrftimeItems}, DateTime, Local};
+
+pub struct TemplateContext {
+    pub time: DateTime<Local>,
+    pub width: u32,
+    pub height: u32,
+    /// Only set for scheduled and watched captures
+    pub sequence: Option<u64>,
+}
+
+impl TemplateContext {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self { time: Local::now(), width, height, sequence: None }
+    }
+
+    pub fn expand(&self, template: &str) -> Result<String, Box<dyn Error>> {
+        let mut out = String::with_capacity(template.len());
+        let mut rest = template;
+
+        while let Some(i) = rest.find(['{', '}']) {
+            out.push_str(&rest[..i]);
+            let brace = rest.as_bytes()[i];
+            rest = &rest[i + 1..];
+
+            // doubled braces are literal
+            if rest.as_bytes().first() == Some(&brace) {
+                out.push(brace as char);
+                rest = &rest[1..];
+                continue;
+            }
+            if brace == b'}' {
+                return Err(format!("unmatched '}}' in template {template:?}").into());
+            }
+
+            let end = rest.find('}').ok_or_else(|| format!("unclosed '{{' in template {template:?}"))?;
+            out.push_str(&self.variable(&rest[..end])?);
+            rest = &rest[end + 1..];
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+
+    fn variable(&self, name: &str) -> Result<String, Box<dyn Error>> {
+        let (name, arg) = match name.split_once(':') {
+            Some((name, arg)) => (name, Some(arg)),
+            None => (name, None),
+        };
+
+        Ok(match (name, arg) {
+            ("date", None) => self.time.format("%Y-%m-%d").to_string(),
+            ("time", None) => self.time.format("%H-%M-%S").to_string(),
+            ("now", Some(format)) => {
+                let items: Vec<Item> = StrftimeItems::new(format).collect();
+                if items.contains(&Item::Error) {
+                    return Err(format!("bad time format {format:?}").into());
+                }
+                self.time.format_with_items(items.into_iter()).to_string()
+            },
+            ("machine", None) => env_or(&["COMPUTERNAME", "HOSTNAME"], "unknown"),
+            ("user", None) => env_or(&["USERNAME", "USER"], "unknown"),
+            ("build", None) => env_or(&["BUILD_NUMBER"], ""),
+            ("env", Some(var)) => env_or(&[var], ""),
+            ("width", None) => self.width.to_string(),
+            ("height", None) => self.height.to_string(),
+            ("seq", digits) => {
+                let sequence = self.sequence.ok_or("{seq} is only set for scheduled and watched captures")?;
+                let digits: usize = match digits {
+                    Some(digits) => digits.parse().map_err(|_| format!("{{seq:{digits}}} needs a number of digits"))?,
+                    None => 0,
+                };
+                format!("{sequence:0digits$}")
+            },
+            _ => return Err(format!("unknown template variable {{{name}}}").into()),
+        })
+    }
+}
+
+/// `path` with `-{seq}` put before the extension unless it already has a
+/// `{seq}`, so numbered captures don't keep writing over the same file
+pub fn numbered(path: &str) -> Cow<'_, str> {
+    if path.contains("{seq}") || path.contains("{seq:") {
+        return Cow::Borrowed(path);
+    }
+    let name_start = path.rfind(['/', '\\']).map_or(0, |i| i + 1);
+    match path[name_start..].rfind('.').filter(|i| *i > 0) {
+        Some(dot) => {
+            let (stem, extension) = path.split_at(name_start + dot);
+            Cow::Owned(format!("{stem}-{{seq}}{extension}"))
+        },
+        None => Cow::Owned(format!("{path}-{{seq}}")),
+    }
+}
+
+fn env_or(vars: &[&str], fallback: &str) -> String {
+    vars.iter()
+        .find_map(|var| std::env::var(var).ok())
+        .unwrap_or_else(|| fallback.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn context() -> TemplateContext {
+        TemplateContext { time: Local.with_ymd_and_hms(2024, 1, 31, 13, 45, 7).unwrap(), width: 640, height: 480, sequence: None }
+    }
+
+    #[test]
+    fn expands_variables() {
+        let context = context();
+        assert_eq!(context.expand("shot {date} {time}.png").unwrap(), "shot 2024-01-31 13-45-07.png");
+        assert_eq!(context.expand("{now:%d.%m.%y}").unwrap(), "31.01.24");
+        assert_eq!(context.expand("{width}x{height}").unwrap(), "640x480");
+        assert_eq!(context.expand("{{width}} }}{{").unwrap(), "{width} }{");
+        assert_eq!(context.expand("no variables").unwrap(), "no variables");
+        assert_eq!(context.expand("[{env:SCREENSHOTTER_SURELY_UNSET}]").unwrap(), "[]");
+    }
+
+    #[test]
+    fn numbers_captures() {
+        let mut context = context();
+        let err = context.expand("{seq}").unwrap_err();
+        assert!(err.to_string().contains("only set"), "{err}");
+
+        context.sequence = Some(7);
+        assert_eq!(context.expand("shot-{seq}").unwrap(), "shot-7");
+        assert_eq!(context.expand("shot-{seq:3}").unwrap(), "shot-007");
+        assert!(context.expand("{seq:x}").is_err());
+    }
+
+    #[test]
+    fn rejects_bad_templates() {
+        let context = context();
+        for template in ["{nope}", "{width:3}", "{now}", "{now:%Q}", "{date", "date}", "{}"] {
+            assert!(context.expand(template).is_err(), "{template:?}");
+        }
+        let err = context.expand("{nope}").unwrap_err();
+        assert!(err.to_string().contains("unknown template variable {nope}"), "{err}");
+    }
+
+    #[test]
+    fn numbers_file_names() {
+        assert_eq!(numbered("img.png"), "img-{seq}.png");
+        assert_eq!(numbered(r"C:\shots.v2\img"), r"C:\shots.v2\img-{seq}");
+        assert_eq!(numbered("out/archive.tar.gz"), "out/archive.tar-{seq}.gz");
+        assert_eq!(numbered(".hidden"), ".hidden-{seq}");
+        assert_eq!(numbered("shot-{seq:4}.png"), "shot-{seq:4}.png");
+        assert_eq!(numbered("{seq}/img.png"), "{seq}/img.png");
+    }
+}
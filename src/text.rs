@@ -0,0 +1,88 @@
+use std::{error::Error, path::Path};
+
+use ab_glyph::{Font as _, FontVec, PxScale, ScaleFont};
+use serde::{Deserialize, Serialize};
+
+/// A TrueType/OpenType font loaded from disk
+pub struct Font(FontVec);
+
+/// Anti aliased coverage of some rendered text, 0 to 1 per pixel
+pub struct TextMask {
+    pub width: u32,
+    pub height: u32,
+    pub coverage: Vec<f32>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Align {
+    #[default]
+    Left,
+    Centre,
+    Right,
+}
+
+impl Font {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path)
+            .map_err(|e| format!("couldn't read font {} : {e}", path.display()))?;
+        Ok(Self(FontVec::try_from_vec(bytes)?))
+    }
+
+    /// Lay out `text` at `size` pixels high, one line per `\n`
+    pub fn render(&self, text: &str, size: f32, align: Align) -> TextMask {
+        let font = self.0.as_scaled(PxScale::from(size));
+        let line_height = font.height() + font.line_gap();
+
+        let lines: Vec<(Vec<ab_glyph::Glyph>, f32)> = text.lines()
+            .enumerate()
+            .map(|(row, line)| {
+                let baseline = font.ascent() + row as f32 * line_height;
+                let mut caret = 0.0;
+                let mut previous = None;
+                let mut glyphs = Vec::new();
+                for c in line.chars() {
+                    let id = font.glyph_id(c);
+                    if let Some(previous) = previous {
+                        caret += font.kern(previous, id);
+                    }
+                    glyphs.push(id.with_scale_and_position(size, ab_glyph::point(caret, baseline)));
+                    caret += font.h_advance(id);
+                    previous = Some(id);
+                }
+                (glyphs, caret)
+            })
+            .collect();
+
+        let width = lines.iter().map(|(_, w)| *w).fold(0.0, f32::max).ceil() as u32;
+        let height = if lines.is_empty() {
+            0
+        } else {
+            (font.height() + (lines.len() - 1) as f32 * line_height).ceil() as u32
+        };
+
+        let mut coverage = vec![0.0; (width * height) as usize];
+        for (glyphs, line_width) in lines {
+            let shift = match align {
+                Align::Left => 0.0,
+                Align::Centre => ((width as f32 - line_width) / 2.0).floor(),
+                Align::Right => width as f32 - line_width,
+            };
+            for glyph in glyphs {
+                let Some(outline) = self.0.outline_glyph(glyph) else { continue };
+                let bounds = outline.px_bounds();
+                outline.draw(|x, y, c| {
+                    let x = bounds.min.x as i32 + x as i32 + shift as i32;
+                    let y = bounds.min.y as i32 + y as i32;
+                    if x >= 0 && y >= 0 && (x as u32) < width && (y as u32) < height {
+                        let px = &mut coverage[(y as u32 * width + x as u32) as usize];
+                        *px = (*px + c).min(1.0);
+                    }
+                });
+            }
+        }
+
+        TextMask { width, height, coverage }
+    }
+}
@@ -0,0 +1,85 @@
+//! Watching a region and capturing it whenever enough of it changes.
+//!
+//! The duplication says which areas changed, so most of the time nothing
+//! inside the region did and there's nothing to read back. When something did,
+//! or the duplication couldn't say, the region is compared pixel by pixel with
+//! how it looked at the last capture. Comparing with the last capture rather
+//! than the frame before means a change that fades in still adds up.
+
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::pixels::ScrgbBuffer;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct WatchSettings {
+    /// Share of the region's pixels that have to change for a capture, 0.005 is half a percent
+    pub threshold: f32,
+    /// How far any channel has to move for a pixel to count as changed, where 1.0
+    /// is SDR white. Keeps video noise and dithering from counting.
+    pub tolerance: f32,
+    /// Seconds after a capture before another can be taken
+    pub cooldown: f32,
+    /// Milliseconds between looking for changes
+    pub interval: u32,
+}
+
+impl Default for WatchSettings {
+    fn default() -> Self {
+        Self { threshold: 0.005, tolerance: 0.02, cooldown: 5.0, interval: 200 }
+    }
+}
+
+pub struct Watcher {
+    threshold: f32,
+    tolerance: f32,
+    cooldown: Duration,
+    /// The region at the last capture, or when the watch started
+    baseline: ScrgbBuffer,
+    last_capture: Option<Instant>,
+}
+
+impl Watcher {
+    pub fn new(baseline: ScrgbBuffer, settings: &WatchSettings) -> Self {
+        Self {
+            threshold: settings.threshold,
+            tolerance: settings.tolerance,
+            cooldown: Duration::from_secs_f32(settings.cooldown.max(0.0)),
+            baseline,
+            last_capture: None,
+        }
+    }
+
+    /// Too soon after the last capture for another
+    pub fn cooling(&self, now: Instant) -> bool {
+        self.last_capture.is_some_and(|last| now.saturating_duration_since(last) < self.cooldown)
+    }
+
+    /// Whether `region` changed enough to capture, it becomes the baseline if so
+    pub fn check(&mut self, region: ScrgbBuffer, now: Instant) -> bool {
+        if self.cooling(now) {
+            return false;
+        }
+        let share = changed_share(&self.baseline, &region, self.tolerance);
+        if share < self.threshold {
+            return false;
+        }
+        debug!("{:.2}% of the watched region changed", share * 100.0);
+        self.baseline = region;
+        self.last_capture = Some(now);
+        true
+    }
+}
+
+/// Share of the pixels that differ by more than `tolerance` in any channel
+fn changed_share(before: &ScrgbBuffer, after: &ScrgbBuffer, tolerance: f32) -> f32 {
+    if before.data.len() != after.data.len() || after.data.is_empty() {
+        return 1.0;
+    }
+    let changed = before.data.iter().zip(&after.data)
+        .filter(|(a, b)| a.iter().zip(b.iter()).any(|(a, b)| (a.to_f32() - b.to_f32()).abs() > tolerance))
+        .count();
+    changed as f32 / after.data.len() as f32
+}